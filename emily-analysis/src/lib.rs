@@ -0,0 +1,172 @@
+//! Versioned analysis file format. The analysis stores the complete game review knowledge -
+//! positions as FENs with their evaluations, and variations with moves in UCI notation.
+//!
+//! Files can be either JSON (for debugging) or MessagePack (compact binary), the format is
+//! detected when reading.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// Current version of the analysis file format
+pub const VERSION: u32 = 1;
+
+/// Analysis file errors
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Not an analysis file")]
+    NotAnalysis(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("Unsupported analysis file version {0}, expected {VERSION}")]
+    Version(u32),
+    #[error("Invalid analysis file")]
+    Invalid(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("Cannot serialize analysis")]
+    Serialize(#[source] Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// Analysis file format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    /// MessagePack
+    Binary,
+}
+
+impl Format {
+    /// Format matching the file extension - `.json` files are JSON, anything else is binary
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => Self::Json,
+            _ => Self::Binary,
+        }
+    }
+
+    /// Detects the format of the stored analysis. The JSON is always an object, while the
+    /// binary always starts with a MessagePack map marker.
+    fn detect(data: &[u8]) -> Self {
+        match data.iter().find(|byte| !byte.is_ascii_whitespace()) {
+            Some(b'{') => Self::Json,
+            _ => Self::Binary,
+        }
+    }
+
+    fn parse<'a, T: Deserialize<'a>>(
+        self,
+        data: &'a [u8],
+    ) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+        let parsed = match self {
+            Self::Json => serde_json::from_slice(data)?,
+            Self::Binary => rmp_serde::from_slice(data)?,
+        };
+        Ok(parsed)
+    }
+}
+
+/// Engine evaluation, from the white PoV
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Score {
+    /// Centipawns
+    Cp(i16),
+    /// Mate in #moves (negative if white gets mated)
+    Mate(i8),
+}
+
+/// Details of the move played from the position
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MoveInfo;
+
+/// Analysed position
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Position {
+    pub fen: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eval: Option<Score>,
+    /// Best move found by the engine, in UCI notation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub best: Option<String>,
+    /// Depth of the engine analysis
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub depth: Option<u8>,
+    /// Principal variation found by the engine, in UCI notation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pv: Option<Vec<String>>,
+    /// If the engine analysis is not final
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub provisional: bool,
+    /// If the engine analysis is a deeper re-search
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub researched: bool,
+    /// Opponent's threat line in UCI notation, played from the position with the side to move
+    /// swapped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threat: Option<Vec<String>>,
+    /// Moves considered in the position, in UCI notation
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub moves: BTreeMap<String, MoveInfo>,
+}
+
+/// Line of moves through the positions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Variation {
+    /// Moves in UCI notation
+    pub moves: Vec<String>,
+    /// Indices of the positions on the line, one more than the moves
+    pub positions: Vec<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<String>,
+}
+
+/// Complete game analysis
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Analysis {
+    pub positions: Vec<Position>,
+    pub variations: Vec<Variation>,
+    /// Main line index in `variations`
+    pub main: usize,
+    /// PGN tags of the game
+    #[serde(default)]
+    pub tags: Vec<(String, String)>,
+}
+
+/// Stored analysis file
+#[derive(Serialize, Deserialize)]
+struct AnalysisFile<A> {
+    version: u32,
+    knowledge: A,
+}
+
+/// Version of the analysis file, read before the whole file is parsed
+#[derive(Deserialize)]
+struct AnalysisVersion {
+    version: u32,
+}
+
+impl Analysis {
+    /// Serializes the analysis file in the given format
+    pub fn to_bytes(&self, format: Format) -> Result<Vec<u8>, Error> {
+        let file = AnalysisFile {
+            version: VERSION,
+            knowledge: self,
+        };
+
+        let data = match format {
+            Format::Json => serde_json::to_vec_pretty(&file).map_err(|err| err.into()),
+            // Named fields, as the format relies on optional fields
+            Format::Binary => rmp_serde::to_vec_named(&file).map_err(|err| err.into()),
+        };
+        data.map_err(Error::Serialize)
+    }
+
+    /// Reads the analysis file serialized with `to_bytes`. The format is detected automatically.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        let format = Format::detect(data);
+        let AnalysisVersion { version } = format.parse(data).map_err(Error::NotAnalysis)?;
+        if version != VERSION {
+            return Err(Error::Version(version));
+        }
+
+        let file: AnalysisFile<Self> = format.parse(data).map_err(Error::Invalid)?;
+        Ok(file.knowledge)
+    }
+}
@@ -1,2 +1,3 @@
 //! Adapters making types between crates work
+pub mod board;
 pub mod debug;
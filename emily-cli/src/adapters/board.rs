@@ -0,0 +1,182 @@
+//! Terminal rendering of the chess board. The board is printed from the white side with
+//! coordinates, optionally highlighting the last move and showing the evaluation.
+
+use std::fmt::{Debug, Display, Formatter, Result};
+use std::io::IsTerminal;
+
+use shakmaty::uci::UciMove;
+use shakmaty::{Chess, Color, File, Move, Piece, Position, Rank, Role, Square};
+
+use crate::uci::Score;
+
+const RESET: &str = "\x1b[0m";
+const LIGHT: &str = "\x1b[48;5;180m";
+const DARK: &str = "\x1b[48;5;137m";
+const LIGHT_LAST: &str = "\x1b[48;5;186m";
+const DARK_LAST: &str = "\x1b[48;5;143m";
+const WHITE_PIECE: &str = "\x1b[1;38;5;231m";
+const BLACK_PIECE: &str = "\x1b[1;38;5;16m";
+
+/// Checks if the board printed to the `stream` should use the ANSI colours - only in a terminal
+/// and unless disabled with `NO_COLOR`
+pub fn use_colors(stream: &impl IsTerminal) -> bool {
+    stream.is_terminal() && std::env::var_os("NO_COLOR").is_none_or(|no| no.is_empty())
+}
+
+/// Board rendering of the position
+pub struct Board<'a> {
+    position: &'a Chess,
+    /// Last move played, highlighted
+    last: Option<(Option<Square>, Square)>,
+    /// Evaluation from the white PoV
+    eval: Option<Score>,
+    /// Unicode chess symbols instead of the piece letters
+    unicode: bool,
+    /// ANSI colours for the squares and pieces
+    colors: bool,
+}
+
+impl<'a> Board<'a> {
+    pub fn new(position: &'a Chess) -> Self {
+        Self {
+            position,
+            last: None,
+            eval: None,
+            unicode: true,
+            colors: true,
+        }
+    }
+
+    /// Highlights the move played to reach the position
+    pub fn last_move(mut self, last: Option<&Move>) -> Self {
+        // Castling is highlighted as the king move
+        self.last = last.and_then(|mov| match UciMove::from_standard(mov) {
+            UciMove::Normal { from, to, .. } => Some((Some(from), to)),
+            UciMove::Put { to, .. } => Some((None, to)),
+            UciMove::Null => None,
+        });
+        self
+    }
+
+    /// Shows the evaluation (from the white PoV)
+    pub fn eval(mut self, eval: Option<Score>) -> Self {
+        self.eval = eval;
+        self
+    }
+
+    /// Uses the piece letters instead of the Unicode symbols
+    pub fn ascii(mut self, ascii: bool) -> Self {
+        self.unicode = !ascii;
+        self
+    }
+
+    /// Enables the ANSI colours
+    pub fn colors(mut self, colors: bool) -> Self {
+        self.colors = colors;
+        self
+    }
+
+    fn is_last(&self, square: Square) -> bool {
+        self.last
+            .is_some_and(|(from, to)| from == Some(square) || to == square)
+    }
+
+    fn symbol(&self, piece: Piece) -> char {
+        if !self.unicode {
+            return piece.char();
+        }
+
+        // With colours, pieces are distinguished by the foreground and the solid symbols are
+        // better visible
+        let color = match self.colors {
+            true => Color::Black,
+            false => piece.color,
+        };
+
+        match (color, piece.role) {
+            (Color::White, Role::King) => '♔',
+            (Color::White, Role::Queen) => '♕',
+            (Color::White, Role::Rook) => '♖',
+            (Color::White, Role::Bishop) => '♗',
+            (Color::White, Role::Knight) => '♘',
+            (Color::White, Role::Pawn) => '♙',
+            (Color::Black, Role::King) => '♚',
+            (Color::Black, Role::Queen) => '♛',
+            (Color::Black, Role::Rook) => '♜',
+            (Color::Black, Role::Bishop) => '♝',
+            (Color::Black, Role::Knight) => '♞',
+            (Color::Black, Role::Pawn) => '♟',
+        }
+    }
+
+    fn write_square(&self, f: &mut Formatter<'_>, square: Square) -> Result {
+        let piece = self.position.board().piece_at(square);
+        let symbol = piece.map_or('·', |piece| self.symbol(piece));
+        let last = self.is_last(square);
+
+        if !self.colors {
+            return match last {
+                true => write!(f, "({symbol})"),
+                false => write!(f, " {symbol} "),
+            };
+        }
+
+        let background = match (square.is_light(), last) {
+            (true, false) => LIGHT,
+            (false, false) => DARK,
+            (true, true) => LIGHT_LAST,
+            (false, true) => DARK_LAST,
+        };
+        let foreground = match piece.map(|piece| piece.color) {
+            Some(Color::White) => WHITE_PIECE,
+            _ => BLACK_PIECE,
+        };
+        write!(f, "{background}{foreground} {symbol} {RESET}")
+    }
+}
+
+impl Display for Board<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        for rank in Rank::ALL.into_iter().rev() {
+            write!(f, "{} ", rank.char())?;
+            for file in File::ALL {
+                self.write_square(f, Square::from_coords(file, rank))?;
+            }
+            writeln!(f)?;
+        }
+
+        write!(f, " ")?;
+        for file in File::ALL {
+            write!(f, " {} ", file.char())?;
+        }
+        writeln!(f)?;
+
+        match self.position.turn() {
+            Color::White => write!(f, "White to move")?,
+            Color::Black => write!(f, "Black to move")?,
+        }
+        if let Some(eval) = self.eval {
+            write!(f, ", eval {eval}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Board starts on its own line in the logs
+impl Debug for Board<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        writeln!(f)?;
+        Display::fmt(self, f)
+    }
+}
+
+/// Rendering the position as a board
+pub trait BoardExt {
+    fn d_board(&self) -> Board<'_>;
+}
+
+impl BoardExt for Chess {
+    fn d_board(&self) -> Board<'_> {
+        Board::new(self)
+    }
+}
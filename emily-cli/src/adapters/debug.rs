@@ -14,7 +14,7 @@ pub struct FlatOpt<'a, T: ?Sized>(&'a T);
 pub trait FlatOptExt {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result;
 
-    fn d_opt(&self) -> FlatOpt<Self> {
+    fn d_opt(&self) -> FlatOpt<'_, Self> {
         FlatOpt(self)
     }
 }
@@ -46,7 +46,7 @@ pub struct DFen<'a, T: ?Sized>(&'a T);
 pub trait DFenExt {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result;
 
-    fn d_fen(&self) -> DFen<Self> {
+    fn d_fen(&self) -> DFen<'_, Self> {
         DFen(self)
     }
 }
@@ -90,7 +90,7 @@ pub struct Line<'a, T: ?Sized>(&'a T);
 pub trait LineExt {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result;
 
-    fn d_line(&self) -> Line<Self> {
+    fn d_line(&self) -> Line<'_, Self> {
         Line(self)
     }
 }
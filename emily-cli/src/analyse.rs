@@ -0,0 +1,499 @@
+//! Interactive infinite analysis of a single position, stepping through the game moves
+
+use std::fmt::Write as _;
+use std::io::{IsTerminal, Write as _};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use color_eyre::eyre::{ensure, OptionExt};
+use color_eyre::Result;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::terminal;
+use shakmaty::san::SanPlus;
+use shakmaty::uci::UciMove;
+use shakmaty::{Chess, Color, EnPassantMode, Move, Position};
+use structopt::StructOpt;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{debug, error, info, instrument, trace};
+
+use crate::adapters::board::{use_colors, BoardExt};
+use crate::adapters::debug::DFenExt;
+use crate::knowledge::PgnReader;
+use crate::rev::parse_chess;
+use crate::shutdown::Shutdown;
+use crate::uci::{self, Info};
+use crate::Config;
+
+/// How often the analysis is redrawn
+const REFRESH: Duration = Duration::from_millis(250);
+/// Maximum number of lines shown
+const MAX_LINES: u8 = 8;
+/// How often the terminal input thread checks if the commands are still awaited
+const POLL: Duration = Duration::from_millis(100);
+
+/// Command given by the user
+#[derive(Debug, Clone, Copy)]
+enum Key {
+    /// Stop or resume the analysis
+    Toggle,
+    /// Step forward through the moves
+    Next,
+    /// Step back through the moves
+    Prev,
+    /// Show one more line
+    More,
+    /// Show one less line
+    Fewer,
+    /// Finish the analysis
+    Quit,
+}
+
+impl Key {
+    /// Parses the command line typed when the input is not a terminal
+    fn parse(line: &str) -> Option<Self> {
+        use Key::*;
+
+        match line.trim() {
+            "s" | " " => Some(Toggle),
+            "n" | "f" => Some(Next),
+            "p" | "b" => Some(Prev),
+            "+" => Some(More),
+            "-" => Some(Fewer),
+            "q" => Some(Quit),
+            _ => None,
+        }
+    }
+
+    /// Maps the key pressed in the terminal. Ctrl-C quits, as the raw mode doesn't raise SIGINT.
+    fn from_event(event: KeyEvent) -> Option<Self> {
+        use Key::*;
+
+        match event.code {
+            KeyCode::Char('c') if event.modifiers.contains(KeyModifiers::CONTROL) => Some(Quit),
+            KeyCode::Char(' ') => Some(Toggle),
+            KeyCode::Char(c) => Self::parse(c.encode_utf8(&mut [0; 4])),
+            KeyCode::Right | KeyCode::Down => Some(Next),
+            KeyCode::Left | KeyCode::Up => Some(Prev),
+            KeyCode::Esc => Some(Quit),
+            _ => None,
+        }
+    }
+}
+
+/// Commands read from stdin. In a terminal single keys are read in the raw mode, which is
+/// restored on drop. Otherwise every command is a line.
+struct Keys {
+    keys: mpsc::UnboundedReceiver<Key>,
+    /// If the terminal is in the raw mode
+    raw: bool,
+}
+
+impl Keys {
+    /// Starts reading the commands
+    fn listen() -> Result<Self> {
+        let (tx, keys) = mpsc::unbounded_channel();
+        let raw = std::io::stdin().is_terminal();
+
+        match raw {
+            true => {
+                terminal::enable_raw_mode()?;
+                // Terminal events are read with blocking calls, the thread finishes when the
+                // commands are not awaited anymore
+                std::thread::spawn(move || Self::read_terminal(tx));
+            }
+            false => {
+                tokio::spawn(Self::read_lines(tx));
+            }
+        }
+
+        Ok(Self { keys, raw })
+    }
+
+    fn read_terminal(tx: mpsc::UnboundedSender<Key>) {
+        while !tx.is_closed() {
+            let event = match event::poll(POLL) {
+                Ok(false) => continue,
+                Ok(true) => event::read(),
+                Err(err) => Err(err),
+            };
+
+            match event {
+                Ok(Event::Key(event)) if event.kind == KeyEventKind::Press => {
+                    match Key::from_event(event) {
+                        Some(key) => {
+                            if tx.send(key).is_err() {
+                                break;
+                            }
+                        }
+                        None => debug!(?event, "Unknown key"),
+                    }
+                }
+                Ok(_) => (),
+                Err(err) => {
+                    error!(%err, "While reading keys");
+                    break;
+                }
+            }
+        }
+        trace!("Keys input closed");
+    }
+
+    async fn read_lines(tx: mpsc::UnboundedSender<Key>) {
+        let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+        loop {
+            match stdin.next_line().await {
+                Ok(Some(line)) => match Key::parse(&line) {
+                    Some(key) => {
+                        if tx.send(key).is_err() {
+                            break;
+                        }
+                    }
+                    None => debug!(line, "Unknown command"),
+                },
+                Ok(None) => break,
+                Err(err) => {
+                    error!(%err, "While reading commands");
+                    break;
+                }
+            }
+        }
+        trace!("Commands input closed");
+    }
+
+    /// Waits for the next command. When the input is closed there are no more commands, and the
+    /// analysis can be only finished with the shutdown.
+    async fn next(&mut self) -> Key {
+        match self.keys.recv().await {
+            Some(key) => key,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+impl Drop for Keys {
+    fn drop(&mut self) {
+        if self.raw {
+            if let Err(err) = terminal::disable_raw_mode() {
+                error!(%err, "While restoring the terminal");
+            }
+        }
+    }
+}
+
+/// Formats the line in SAN with move numbers. The line is followed as long as its moves are legal.
+fn san_line(position: &Chess, line: &[UciMove]) -> String {
+    let mut position = position.clone();
+    let mut san = String::new();
+
+    for (idx, mov) in line.iter().enumerate() {
+        let Ok(mov) = mov.to_move(&position) else {
+            break;
+        };
+
+        let no = position.fullmoves();
+        match position.turn() {
+            Color::White => san.push_str(&format!("{no}. ")),
+            Color::Black if idx == 0 => san.push_str(&format!("{no}... ")),
+            Color::Black => (),
+        }
+
+        san.push_str(&SanPlus::from_move_and_play_unchecked(&mut position, &mov).to_string());
+        san.push(' ');
+    }
+
+    san.trim_end().to_owned()
+}
+
+/// Analysed game - position with moves which can be stepped through
+struct Analysis {
+    root: Chess,
+    moves: Vec<Move>,
+    /// Halfmoves played from the root
+    hm: usize,
+    /// Number of lines (`MultiPV`)
+    lines: u8,
+    /// If the engine should be analysing
+    running: bool,
+    /// Board drawn with the piece letters
+    ascii: bool,
+    /// Terminal in the raw mode, lines are ended with CRLF and commands don't need Enter
+    raw: bool,
+    /// Latest engine info per line
+    infos: Vec<Info>,
+}
+
+impl Analysis {
+    /// Position currently analysed
+    fn position(&self) -> Chess {
+        let mut position = self.root.clone();
+        for mov in &self.moves[..self.hm] {
+            position.play_unchecked(mov);
+        }
+        position
+    }
+
+    /// Updates the line the info is about
+    fn update(&mut self, info: Info) {
+        if info.multipv == 0 || info.multipv > self.lines {
+            return;
+        }
+
+        match self
+            .infos
+            .iter_mut()
+            .find(|line| line.multipv == info.multipv)
+        {
+            Some(line) => *line = info,
+            None => self.infos.push(info),
+        }
+        self.infos.sort_by_key(|line| line.multipv);
+    }
+
+    /// Applies the command. Returns `false` when the analysis should finish. Lines stay shown
+    /// when the analysis is stopped, until it is resumed.
+    fn apply(&mut self, key: Key) -> bool {
+        use Key::*;
+
+        match key {
+            Toggle if self.running => {
+                self.running = false;
+                return true;
+            }
+            Toggle => self.running = true,
+            Next if self.hm < self.moves.len() => self.hm += 1,
+            Prev if self.hm > 0 => self.hm -= 1,
+            More if self.lines < MAX_LINES => self.lines += 1,
+            Fewer if self.lines > 1 => self.lines -= 1,
+            Quit => return false,
+            Next | Prev | More | Fewer => return true,
+        }
+
+        self.infos.clear();
+        true
+    }
+
+    /// Redraws the analysis in place
+    fn render(&self, status: &str) -> Result<()> {
+        let position = self.position();
+        let fen = shakmaty::fen::Fen::from_position(position.clone(), EnPassantMode::Legal);
+
+        let board = position
+            .d_board()
+            .last_move(self.hm.checked_sub(1).map(|last| &self.moves[last]))
+            .eval(
+                self.infos
+                    .first()
+                    .map(|info| info.score.pov(position.turn())),
+            )
+            .ascii(self.ascii)
+            .colors(use_colors(&std::io::stdout()));
+
+        let mut out = String::new();
+        // Cursor home, clear the screen
+        write!(out, "\x1b[H\x1b[2J")?;
+        writeln!(out, "{board}")?;
+        writeln!(out)?;
+        writeln!(out, "{fen}")?;
+
+        match self.hm.checked_sub(1) {
+            Some(last) => {
+                let mut before = self.root.clone();
+                for mov in &self.moves[..last] {
+                    before.play_unchecked(mov);
+                }
+                let last = UciMove::from_standard(&self.moves[last]);
+                writeln!(
+                    out,
+                    "Move {}/{}: {}",
+                    self.hm,
+                    self.moves.len(),
+                    san_line(&before, &[last])
+                )?;
+            }
+            None => writeln!(out, "Move 0/{}", self.moves.len())?,
+        }
+
+        let (depth, nodes) = self
+            .infos
+            .first()
+            .map(|info| (info.depth, info.nodes))
+            .unwrap_or_default();
+        writeln!(out, "Depth {depth}, nodes {nodes} ({status})")?;
+        writeln!(out)?;
+
+        for info in &self.infos {
+            let eval = info.score.pov(position.turn()).to_string();
+            let line = san_line(&position, &info.line);
+            writeln!(out, "{:>2}. {eval:>7}  {line}", info.multipv)?;
+        }
+
+        writeln!(out)?;
+        write!(
+            out,
+            "s: stop/resume, n/p: next/previous move, +/-: more/fewer lines, q: quit"
+        )?;
+        match self.raw {
+            true => writeln!(out)?,
+            false => writeln!(out, " (then Enter)")?,
+        }
+
+        if self.raw {
+            out = out.replace('\n', "\r\n");
+        }
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(out.as_bytes())?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+/// Interactive infinite analysis. Lines are shown in SAN, evaluations from the white PoV.
+/// Commands are single keys in a terminal, or lines read from stdin otherwise.
+#[derive(Debug, StructOpt)]
+pub struct Analyse {
+    /// Position to analyse
+    #[structopt(short, long, parse(try_from_str = parse_chess))]
+    fen: Option<Chess>,
+    /// Input PGN file, main line moves of the game can be stepped through
+    #[structopt(short, long, conflicts_with = "fen")]
+    input: Option<PathBuf>,
+    /// Game number in the input PGN (starting with 1)
+    #[structopt(short, long, default_value = "1")]
+    game: usize,
+    /// Initial number of lines shown
+    #[structopt(short, long, default_value = "1")]
+    lines: u8,
+    /// Draws the board with the piece letters instead of the chess symbols
+    #[structopt(long)]
+    ascii: bool,
+}
+
+impl Analyse {
+    /// Loads the analysed position and the moves following it
+    async fn load(&self) -> Result<(Chess, Vec<Move>)> {
+        let Some(input) = &self.input else {
+            return Ok((self.fen.clone().unwrap_or_default(), vec![]));
+        };
+
+        ensure!(self.game > 0, "Games are numbered from 1");
+        let mut games = PgnReader::new(BufReader::new(File::open(input).await?));
+        for _ in 1..self.game {
+            ensure!(games.skip_game().await?, "Game not found in the input");
+        }
+
+        let knowledge = games
+            .next_game()
+            .await?
+            .ok_or_eyre("Game not found in the input")??;
+        let (_, mainline) = knowledge.mainline();
+        Ok((
+            knowledge.root().position().clone(),
+            mainline.moves().to_vec(),
+        ))
+    }
+
+    /// Analyses the current position until the next command
+    #[instrument(skip_all, fields(hm = analysis.hm), err)]
+    async fn analyse(
+        engine: &mut uci::Engine,
+        analysis: &mut Analysis,
+        keys: &mut Keys,
+        shutdown: &mut Shutdown,
+    ) -> Result<Key> {
+        let mut stream = engine
+            .go(
+                analysis.root.clone(),
+                &analysis.moves[..analysis.hm],
+                None,
+                None,
+            )
+            .await?;
+        let mut redraw = interval(REFRESH);
+        redraw.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut finished = false;
+
+        let key = loop {
+            tokio::select! {
+                info = stream.info(), if !finished => match info? {
+                    Some(info) => analysis.update(info),
+                    None => {
+                        finished = true;
+                        analysis.render("finished")?;
+                    }
+                },
+                _ = redraw.tick(), if !finished => analysis.render("running")?,
+                key = keys.next() => break key,
+                _ = shutdown.wait() => break Key::Quit,
+            }
+        };
+
+        let best = stream.stop_wait().await?;
+        trace!(%best, "Analysis stopped");
+        Ok(key)
+    }
+
+    #[instrument(skip(self, config), err)]
+    pub async fn run(self, config: Config) -> Result<()> {
+        info!(?self, "Position analysis");
+        ensure!(
+            (1..=MAX_LINES).contains(&self.lines),
+            "Number of lines has to be between 1 and {MAX_LINES}"
+        );
+
+        let mut shutdown = Shutdown::listen()?;
+        let (root, moves) = self.load().await?;
+        trace!(pos = ?root.d_fen(), moves = moves.len(), "Position loaded");
+
+        let mut engine =
+            uci::Engine::run(config.engine.ok_or_eyre("No engine configuration")?).await?;
+        engine.new_game().await?;
+
+        let mut keys = Keys::listen()?;
+        let mut analysis = Analysis {
+            root,
+            moves,
+            hm: 0,
+            lines: self.lines,
+            running: true,
+            ascii: self.ascii,
+            raw: keys.raw,
+            infos: vec![],
+        };
+        let mut lines = 0;
+
+        loop {
+            if lines != analysis.lines {
+                engine
+                    .set_option("MultiPV", &analysis.lines.to_string())
+                    .await?;
+                lines = analysis.lines;
+            }
+
+            let position = analysis.position();
+            let key = if analysis.running && !position.legal_moves().is_empty() {
+                Self::analyse(&mut engine, &mut analysis, &mut keys, &mut shutdown).await?
+            } else {
+                let status = match position.outcome() {
+                    Some(outcome) => format!("game over, {outcome}"),
+                    None => "stopped".to_owned(),
+                };
+                analysis.render(&status)?;
+
+                tokio::select! {
+                    key = keys.next() => key,
+                    _ = shutdown.wait() => Key::Quit,
+                }
+            };
+
+            debug!(?key, "Command");
+            if !analysis.apply(key) {
+                break;
+            }
+        }
+
+        engine.quit().await
+    }
+}
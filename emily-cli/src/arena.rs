@@ -0,0 +1,188 @@
+//! Matches between the engines
+
+use std::path::PathBuf;
+
+use color_eyre::eyre::{ensure, eyre};
+use color_eyre::Result;
+use shakmaty::Color;
+use structopt::StructOpt;
+use tokio::fs::File;
+use tracing::{info, instrument, warn};
+
+use self::game::{Adjudicator, Player, Rules};
+use self::opening::Opening;
+use self::stats::{Record, Sprt};
+use crate::clock::{parse_time_control, TimeControl};
+use crate::knowledge::PgnWriter;
+use crate::shutdown::Shutdown;
+use crate::Config;
+
+mod game;
+mod opening;
+mod stats;
+mod tournament;
+
+pub use self::tournament::Tournament;
+
+/// Match between the engines from the config. The first engine plays against every other one,
+/// alternating colours over the openings. Games are adjudicated according to the `match` config.
+#[derive(Debug, StructOpt)]
+pub struct Match {
+    /// Engines names, the first one plays against all the others
+    #[structopt(required = true, min_values = 2)]
+    engines: Vec<String>,
+    /// Output PGN file with all the games
+    #[structopt(short, long)]
+    output: PathBuf,
+    /// Opening suite - EPD positions or PGN games main lines
+    #[structopt(long)]
+    openings: Option<PathBuf>,
+    /// Games against every opponent, by default every opening is played with both colours
+    #[structopt(short, long)]
+    games: Option<usize>,
+    /// Time control as minutes and increment seconds
+    #[structopt(short, long, parse(try_from_str = parse_time_control))]
+    time_control: Option<TimeControl>,
+    /// Nodes limit per move
+    #[structopt(long)]
+    nodes: Option<u64>,
+}
+
+impl Match {
+    /// Prints the engine record with the Elo difference
+    fn report(name: &str, opponent: &str, record: &Record) {
+        let elo = match record.elo() {
+            Some((elo, margin)) if elo.is_finite() => format!("Elo {elo:+.1} ± {margin:.1}"),
+            _ => "Elo unknown".to_owned(),
+        };
+        println!(
+            "{name} vs {opponent}: {record} ({}/{}), {elo}",
+            record.points(),
+            record.games()
+        );
+    }
+
+    #[instrument(skip(self, config), err)]
+    pub async fn run(self, config: Config) -> Result<()> {
+        info!(?self, "Engine match");
+        ensure!(
+            self.time_control.is_some() || self.nodes.is_some(),
+            "Time control or nodes limit is required"
+        );
+        let mut shutdown = Shutdown::listen()?;
+
+        let engines = self
+            .engines
+            .iter()
+            .map(|name| {
+                config
+                    .engine_named(name)
+                    .cloned()
+                    .ok_or_else(|| eyre!("Unknown engine {name}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let openings = match &self.openings {
+            Some(path) => opening::load(path).await?,
+            None => vec![Opening::default()],
+        };
+        let games = self.games.unwrap_or(2 * openings.len());
+
+        let rules = Rules {
+            time_control: self.time_control,
+            nodes: self.nodes,
+            config: &config.matches,
+        };
+        let mut adjudicator = Adjudicator::start(&config).await?;
+        let mut output = PgnWriter::new(File::create(&self.output).await?);
+
+        let (first, opponents) = engines.split_first().expect("At least two engines");
+        let mut player = Player::start(first.clone()).await?;
+        let mut records = vec![];
+        let mut total = Record::default();
+        let mut sprt = Sprt::Continue;
+
+        'opponents: for opponent in opponents {
+            let mut opponent = Player::start(opponent.clone()).await?;
+            let mut record = Record::default();
+
+            for round in 0..games {
+                if shutdown.is_requested() || sprt != Sprt::Continue {
+                    break;
+                }
+
+                // Every opening is played twice in a row, with swapped colours
+                let opening = &openings[round / 2 % openings.len()];
+                let color = match round % 2 {
+                    0 => Color::White,
+                    _ => Color::Black,
+                };
+                let (white, black) = match color {
+                    Color::White => (&mut player, &mut opponent),
+                    Color::Black => (&mut opponent, &mut player),
+                };
+
+                let mut game = game::play(
+                    white,
+                    black,
+                    opening,
+                    &rules,
+                    adjudicator.as_mut(),
+                    &mut shutdown,
+                )
+                .await?;
+                game.knowledge
+                    .set_tag("Round", (output.games() + 1).to_string());
+                output.write(&game.knowledge).await?;
+
+                let Some(outcome) = game.outcome else {
+                    warn!(round, "Game abandoned");
+                    break;
+                };
+
+                record.add(outcome, color);
+                total.add(outcome, color);
+                info!(round, %outcome, %record, opponent = opponent.name, "Game finished");
+
+                if let Some(config) = &config.matches.sprt {
+                    sprt = Sprt::test(&total, config);
+                }
+            }
+
+            records.push((opponent.name.clone(), record));
+            opponent.quit().await?;
+            if shutdown.is_requested() || sprt != Sprt::Continue {
+                break 'opponents;
+            }
+        }
+
+        let name = player.name.clone();
+        player.quit().await?;
+        if let Some(adjudicator) = adjudicator {
+            adjudicator.quit().await?;
+        }
+        info!(file = ?self.output, games = output.games(), "Games stored");
+
+        for (opponent, record) in &records {
+            Self::report(&name, opponent, record);
+        }
+        if records.len() > 1 {
+            Self::report(&name, "all", &total);
+        }
+
+        if let Some(config) = &config.matches.sprt {
+            let (lower, upper) = Sprt::bounds(config);
+            let result = match sprt {
+                Sprt::Continue => "undecided".to_owned(),
+                Sprt::H0 => format!("H0 (Elo {}) accepted", config.elo0),
+                Sprt::H1 => format!("H1 (Elo {}) accepted", config.elo1),
+            };
+            println!(
+                "SPRT: LLR {:.2} ({lower:.2}, {upper:.2}), {result}",
+                total.llr(config)
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,339 @@
+//! Single game between two engines
+
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::OptionExt;
+use color_eyre::Result;
+use shakmaty::{Chess, Color, Outcome, Position};
+use tokio::time::sleep;
+use tracing::{debug, instrument, trace, warn};
+
+use super::opening::Opening;
+use crate::adapters::board::{use_colors, BoardExt};
+use crate::adapters::debug::{DFenExt, MovExt};
+use crate::clock::{Clocks, TimeControl};
+use crate::knowledge::Knowledge;
+use crate::shutdown::Shutdown;
+use crate::uci::{self, Clock, Score};
+use crate::{config, Config};
+
+/// Engine taking part in the match
+#[derive(Debug)]
+pub struct Player {
+    pub name: String,
+    engine: uci::Engine,
+}
+
+impl Player {
+    pub async fn start(config: config::Engine) -> Result<Self> {
+        Ok(Self {
+            name: config.name.clone(),
+            engine: uci::Engine::run(config).await?,
+        })
+    }
+
+    pub async fn quit(self) -> Result<()> {
+        self.engine.quit().await
+    }
+
+    /// Lets the engine search its move. The search is stopped once the `limit` is exceeded.
+    #[instrument(skip_all, fields(player = self.name), err)]
+    async fn search(
+        &mut self,
+        knowledge: &Knowledge,
+        clock: Option<Clock>,
+        nodes: Option<u64>,
+        limit: Option<Duration>,
+        shutdown: &mut Shutdown,
+    ) -> Result<Search> {
+        let (_, variation) = knowledge.mainline();
+        let mut stream = self
+            .engine
+            .play(
+                knowledge.root().position().clone(),
+                variation.moves(),
+                clock,
+                nodes,
+            )
+            .await?;
+
+        let timeout = async {
+            match limit {
+                Some(limit) => sleep(limit).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(timeout);
+
+        let mut last = None;
+        loop {
+            tokio::select! {
+                info = stream.info() => match info? {
+                    Some(info) if info.multipv <= 1 => last = Some((info.score, info.depth)),
+                    Some(_) => (),
+                    None => break,
+                },
+                _ = &mut timeout => {
+                    stream.stop_wait().await?;
+                    return Ok(Search::Flag);
+                },
+                _ = shutdown.wait() => {
+                    stream.stop_wait().await?;
+                    return Ok(Search::Abandon);
+                },
+            }
+        }
+
+        let best = stream.best().await?;
+        Ok(Search::Move {
+            best,
+            score: last.map(|(score, _)| score),
+            depth: last.map(|(_, depth)| depth),
+        })
+    }
+}
+
+/// Result of the engine search
+#[derive(Debug)]
+enum Search {
+    /// Move found, the score is from the engine PoV
+    Move {
+        best: shakmaty::uci::UciMove,
+        score: Option<Score>,
+        depth: Option<u8>,
+    },
+    /// Clock run out while searching
+    Flag,
+    /// Game interrupted
+    Abandon,
+}
+
+/// Engine adjudicating the endgames, see [`config::Tablebase`]
+#[derive(Debug)]
+pub struct Adjudicator {
+    engine: uci::Engine,
+    config: config::Tablebase,
+}
+
+impl Adjudicator {
+    /// Starts the adjudicator if the tablebase adjudication is configured
+    pub async fn start(config: &Config) -> Result<Option<Self>> {
+        let Some(tablebase) = config.matches.tablebase.clone() else {
+            return Ok(None);
+        };
+
+        let engine = config
+            .engine_named(&tablebase.engine)
+            .ok_or_eyre("Unknown tablebase engine")?;
+        Ok(Some(Self {
+            engine: uci::Engine::run(engine.clone()).await?,
+            config: tablebase,
+        }))
+    }
+
+    pub async fn quit(self) -> Result<()> {
+        self.engine.quit().await
+    }
+
+    /// Adjudicates the position if it is small enough and the evaluation is a mate or a tablebase
+    /// win
+    #[instrument(skip_all, fields(position = ?position.d_fen()), err)]
+    async fn adjudicate(&mut self, position: &Chess) -> Result<Option<Outcome>> {
+        if position.board().occupied().count() > self.config.pieces as usize {
+            return Ok(None);
+        }
+
+        let mut stream = self
+            .engine
+            .go(position.clone(), &[], self.config.depth.or(Some(1)), None)
+            .await?;
+        let mut score = None;
+        while let Some(info) = stream.info().await? {
+            if info.multipv <= 1 {
+                score = Some(info.score.pov(position.turn()));
+            }
+        }
+        stream.best().await?;
+
+        let win = self.config.win_score as i16;
+        let outcome = match score {
+            Some(Score::Mate(mate)) if mate > 0 => Some(Color::White),
+            Some(Score::Mate(_)) => Some(Color::Black),
+            Some(Score::Cp(cp)) if cp >= win => Some(Color::White),
+            Some(Score::Cp(cp)) if cp <= -win => Some(Color::Black),
+            _ => None,
+        };
+
+        debug!(?score, ?outcome, "Position adjudicated");
+        Ok(outcome.map(|winner| Outcome::Decisive { winner }))
+    }
+}
+
+/// Adjudicates the game by the engines' evaluations (white PoV) of the last moves
+fn adjudicate_evals(
+    evals: &[Option<Score>],
+    fullmoves: u32,
+    config: &config::Match,
+) -> Option<Outcome> {
+    let last = |moves: u8| {
+        let plies = 2 * moves as usize;
+        (plies > 0 && evals.len() >= plies).then(|| &evals[evals.len() - plies..])
+    };
+
+    if let Some(resign) = &config.resign {
+        if let Some(evals) = last(resign.moves) {
+            let score = resign.score as i16;
+            if evals
+                .iter()
+                .all(|eval| eval.is_some_and(|eval| eval >= Score::Cp(score)))
+            {
+                return Some(Outcome::Decisive {
+                    winner: Color::White,
+                });
+            }
+            if evals
+                .iter()
+                .all(|eval| eval.is_some_and(|eval| eval <= Score::Cp(-score)))
+            {
+                return Some(Outcome::Decisive {
+                    winner: Color::Black,
+                });
+            }
+        }
+    }
+
+    if let Some(draw) = config.draw.as_ref().filter(|draw| fullmoves >= draw.after) {
+        if let Some(evals) = last(draw.moves) {
+            let drawn = evals
+                .iter()
+                .all(|eval| matches!(eval, Some(Score::Cp(cp)) if cp.unsigned_abs() <= draw.score));
+            if drawn {
+                return Some(Outcome::Draw);
+            }
+        }
+    }
+
+    None
+}
+
+/// Limits and rules of the games
+#[derive(Debug)]
+pub struct Rules<'a> {
+    pub time_control: Option<TimeControl>,
+    pub nodes: Option<u64>,
+    pub config: &'a config::Match,
+}
+
+/// Finished (or abandoned) game
+#[derive(Debug)]
+pub struct Game {
+    pub knowledge: Knowledge,
+    /// Game outcome, `None` if the game was abandoned
+    pub outcome: Option<Outcome>,
+}
+
+/// Plays the game from the `opening`. Engines' evaluations are stored with the moves.
+#[instrument(skip_all, fields(white = white.name, black = black.name, ?opening), err)]
+pub async fn play(
+    white: &mut Player,
+    black: &mut Player,
+    opening: &Opening,
+    rules: &Rules<'_>,
+    mut adjudicator: Option<&mut Adjudicator>,
+    shutdown: &mut Shutdown,
+) -> Result<Game> {
+    let mut knowledge = Knowledge::new(opening.root.clone());
+    for (hm, mov) in opening.moves.iter().enumerate() {
+        knowledge.add_move(0, hm, mov.clone())?;
+    }
+
+    knowledge
+        .set_tag("Event", "Emily match")
+        .set_tag("White", white.name.clone())
+        .set_tag("Black", black.name.clone());
+    if let Some(tc) = rules.time_control {
+        knowledge.set_tag("TimeControl", tc.tag());
+    }
+
+    white.engine.new_game().await?;
+    black.engine.new_game().await?;
+
+    let mut clocks = rules.time_control.map(Clocks::new);
+    // Engines' evaluations of the moves played in the game (from the white PoV)
+    let mut evals = vec![];
+
+    let (termination, outcome) = loop {
+        let (main, variation) = knowledge.mainline();
+        let hm = variation.moves().len();
+        if let Some(outcome) = variation.outcome() {
+            break ("normal", Some(*outcome));
+        }
+
+        let (_, position) = knowledge.variation_hm(main, hm);
+        let position = position.position().clone();
+
+        if let Some(adjudicator) = adjudicator.as_deref_mut() {
+            if let Some(outcome) = adjudicator.adjudicate(&position).await? {
+                break ("adjudication", Some(outcome));
+            }
+        }
+
+        if let Some(outcome) = adjudicate_evals(&evals, position.fullmoves().get(), rules.config) {
+            break ("adjudication", Some(outcome));
+        }
+
+        let turn = position.turn();
+        let player = match turn {
+            Color::White => &mut *white,
+            Color::Black => &mut *black,
+        };
+
+        let lost = Some(Outcome::Decisive { winner: !turn });
+        let clock = clocks.as_ref().map(Clocks::uci);
+        let limit = clocks.as_ref().map(|clocks| clocks.remaining(turn));
+        let started = Instant::now();
+        let search = player
+            .search(&knowledge, clock, rules.nodes, limit, shutdown)
+            .await?;
+
+        let (best, score, depth) = match search {
+            Search::Move { best, score, depth } => (best, score, depth),
+            Search::Flag => break ("time forfeit", lost),
+            Search::Abandon => break ("abandoned", None),
+        };
+
+        // Move played after the clock run out is too late
+        if let Some(clocks) = &mut clocks {
+            if !clocks.punch(turn, started.elapsed()) {
+                break ("time forfeit", lost);
+            }
+        }
+
+        let Ok(mov) = best.to_move(&position) else {
+            let board = position.d_board().colors(use_colors(&std::io::stderr()));
+            warn!(player = player.name, %best, ?board, "Illegal move played");
+            break ("illegal move", lost);
+        };
+
+        trace!(mov = ?mov.d_mov(), ?score, ?depth, "Move played");
+        let eval = score.map(|score| score.pov(turn));
+        evals.push(eval);
+
+        // The evaluation is stored with the move it was found for
+        let (_, _, info) = knowledge.add_move(main, hm, mov)?;
+        if let Some(eval) = eval {
+            info.update_eval(eval);
+        }
+        if let Some(depth) = depth {
+            info.update_depth(depth);
+        }
+    };
+
+    knowledge.set_tag("Termination", termination);
+    if let Some(outcome) = outcome {
+        knowledge.set_tag("Result", outcome.to_string());
+    }
+
+    debug!(termination, ?outcome, "Game finished");
+    Ok(Game { knowledge, outcome })
+}
@@ -0,0 +1,85 @@
+//! Opening suites the games are started from
+
+use std::path::Path;
+
+use color_eyre::eyre::{ensure, Context};
+use color_eyre::Result;
+use derivative::Derivative;
+use shakmaty::fen::Epd;
+use shakmaty::uci::UciMove;
+use shakmaty::{CastlingMode, Chess, EnPassantMode, Move};
+use tokio::fs::File;
+use tokio::io::BufReader;
+use tracing::{debug, instrument, warn};
+
+use crate::adapters::debug::{DFenExt, LineExt};
+use crate::knowledge::PgnReader;
+
+/// Opening - starting position and the moves played from it
+#[derive(Derivative, Default, Clone)]
+#[derivative(Debug)]
+pub struct Opening {
+    #[derivative(Debug(format_with = "DFenExt::fmt"))]
+    pub root: Chess,
+    #[derivative(Debug(format_with = "LineExt::fmt"))]
+    pub moves: Vec<Move>,
+}
+
+impl Opening {
+    /// Identifies the opening as the EPD of the root followed by the UCI moves
+    pub fn key(&self) -> String {
+        let epd = Epd::from_position(self.root.clone(), EnPassantMode::Legal);
+        self.moves
+            .iter()
+            .map(|mov| UciMove::from_standard(mov).to_string())
+            .fold(epd.to_string(), |key, mov| key + " " + &mov)
+    }
+}
+
+/// Parses the EPD suite, only positions are used (operations are ignored)
+fn parse_epd(suite: &str) -> Result<Vec<Opening>> {
+    suite
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let epd: Vec<_> = line.split_whitespace().take(4).collect();
+            let epd: Epd = epd.join(" ").parse().wrap_err("Invalid EPD")?;
+            let root = epd.into_position(CastlingMode::Standard)?;
+            Ok(Opening {
+                root,
+                moves: vec![],
+            })
+        })
+        .collect()
+}
+
+/// Loads the opening suite. EPD files (`.epd`) contain the starting positions, otherwise the file
+/// is read as PGN and the main line of every game is the opening.
+#[instrument(err)]
+pub async fn load(path: &Path) -> Result<Vec<Opening>> {
+    let openings = match path.extension().is_some_and(|ext| ext == "epd") {
+        true => parse_epd(&tokio::fs::read_to_string(path).await?)?,
+        false => {
+            let mut games = PgnReader::new(BufReader::new(File::open(path).await?));
+            let mut openings = vec![];
+            while let Some(game) = games.next_game().await? {
+                match game {
+                    Ok(knowledge) => {
+                        let (_, mainline) = knowledge.mainline();
+                        openings.push(Opening {
+                            root: knowledge.root().position().clone(),
+                            moves: mainline.moves().to_vec(),
+                        });
+                    }
+                    Err(err) => warn!(%err, game = games.games(), "Invalid opening, skipping"),
+                }
+            }
+            openings
+        }
+    };
+
+    ensure!(!openings.is_empty(), "No openings in the suite");
+    debug!(openings = openings.len(), "Opening suite loaded");
+    Ok(openings)
+}
@@ -0,0 +1,227 @@
+//! Match statistics - results, Elo estimation and the SPRT
+
+use std::fmt::{Display, Formatter};
+
+use shakmaty::{Color, Outcome};
+
+use crate::config;
+
+/// Normal distribution quantile for the 95% confidence
+const CONFIDENCE: f64 = 1.96;
+
+/// Elo difference for the expected score
+fn elo(score: f64) -> f64 {
+    400. * (score / (1. - score)).log10()
+}
+
+/// Expected score for the Elo difference
+fn expected(elo: f64) -> f64 {
+    1. / (1. + 10f64.powf(-elo / 400.))
+}
+
+/// Games results of a single engine
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Record {
+    pub wins: u32,
+    pub draws: u32,
+    pub losses: u32,
+}
+
+impl Record {
+    /// Adds the game result of the engine playing `color`
+    pub fn add(&mut self, outcome: Outcome, color: Color) {
+        match outcome {
+            Outcome::Draw => self.draws += 1,
+            Outcome::Decisive { winner } if winner == color => self.wins += 1,
+            Outcome::Decisive { .. } => self.losses += 1,
+        }
+    }
+
+    pub fn games(&self) -> u32 {
+        self.wins + self.draws + self.losses
+    }
+
+    /// Points scored
+    pub fn points(&self) -> f64 {
+        self.wins as f64 + self.draws as f64 / 2.
+    }
+
+    /// Average score per game and its variance
+    fn score(&self) -> Option<(f64, f64)> {
+        let games = self.games() as f64;
+        if games == 0. {
+            return None;
+        }
+
+        let score = self.points() / games;
+        let variance = (self.wins as f64 * (1. - score).powi(2)
+            + self.draws as f64 * (0.5 - score).powi(2)
+            + self.losses as f64 * score.powi(2))
+            / games;
+        Some((score, variance))
+    }
+
+    /// Elo difference against the opponents with the 95% confidence margin. Unknown without
+    /// games, infinite if all the games were won (or lost).
+    pub fn elo(&self) -> Option<(f64, f64)> {
+        let (score, variance) = self.score()?;
+        let deviation = (variance / self.games() as f64).sqrt() * CONFIDENCE;
+
+        let low = elo((score - deviation).max(0.));
+        let high = elo((score + deviation).min(1.));
+        Some((elo(score), (high - low) / 2.))
+    }
+
+    /// SPRT log-likelihood ratio of the `elo1` hypothesis against the `elo0` one (normal
+    /// approximation of the game results)
+    pub fn llr(&self, sprt: &config::Sprt) -> f64 {
+        let Some((score, variance)) = self.score() else {
+            return 0.;
+        };
+
+        if variance == 0. {
+            return 0.;
+        }
+
+        let (s0, s1) = (expected(sprt.elo0), expected(sprt.elo1));
+        self.games() as f64 * (s1 - s0) * (2. * score - s0 - s1) / (2. * variance)
+    }
+}
+
+impl std::ops::AddAssign for Record {
+    fn add_assign(&mut self, rhs: Self) {
+        self.wins += rhs.wins;
+        self.draws += rhs.draws;
+        self.losses += rhs.losses;
+    }
+}
+
+/// Formatted as `+W =D -L`
+impl Display for Record {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "+{} ={} -{}", self.wins, self.draws, self.losses)
+    }
+}
+
+/// SPRT state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sprt {
+    /// More games are needed
+    Continue,
+    /// `elo0` hypothesis accepted
+    H0,
+    /// `elo1` hypothesis accepted
+    H1,
+}
+
+impl Sprt {
+    /// LLR bounds for the test
+    pub fn bounds(config: &config::Sprt) -> (f64, f64) {
+        (
+            (config.beta / (1. - config.alpha)).ln(),
+            ((1. - config.beta) / config.alpha).ln(),
+        )
+    }
+
+    /// Test state for the record
+    pub fn test(record: &Record, config: &config::Sprt) -> Self {
+        let llr = record.llr(config);
+        let (lower, upper) = Self::bounds(config);
+
+        if llr >= upper {
+            Self::H1
+        } else if llr <= lower {
+            Self::H0
+        } else {
+            Self::Continue
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPRT: config::Sprt = config::Sprt {
+        elo0: 0.,
+        elo1: 10.,
+        alpha: 0.05,
+        beta: 0.05,
+    };
+
+    fn record(wins: u32, draws: u32, losses: u32) -> Record {
+        Record {
+            wins,
+            draws,
+            losses,
+        }
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-6, "{a} != {b}");
+    }
+
+    #[test]
+    fn elo_with_margin() {
+        let (elo, margin) = record(60, 20, 20).elo().unwrap();
+        assert_close(elo, 147.190714);
+        assert_close(margin, 66.014639);
+
+        let (elo, _) = record(20, 20, 60).elo().unwrap();
+        assert_close(elo, -147.190714);
+        assert_eq!(record(10, 10, 10).elo().unwrap().0, 0.);
+    }
+
+    #[test]
+    fn elo_edge_cases() {
+        assert!(Record::default().elo().is_none());
+        assert_eq!(record(3, 0, 0).elo().unwrap().0, f64::INFINITY);
+        assert_eq!(record(0, 0, 3).elo().unwrap().0, f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn llr() {
+        assert_close(record(60, 20, 20).llr(&SPRT), 1.733713);
+        assert_close(record(10, 10, 10).llr(&SPRT), -0.018629);
+        // No information without games or without variance
+        assert_eq!(Record::default().llr(&SPRT), 0.);
+        assert_eq!(record(0, 5, 0).llr(&SPRT), 0.);
+    }
+
+    #[test]
+    fn sprt() {
+        let (lower, upper) = Sprt::bounds(&SPRT);
+        assert_close(lower, -2.944439);
+        assert_close(upper, 2.944439);
+
+        assert_eq!(Sprt::test(&record(60, 20, 20), &SPRT), Sprt::Continue);
+        assert_eq!(Sprt::test(&record(240, 80, 80), &SPRT), Sprt::H1);
+        assert_eq!(Sprt::test(&record(80, 80, 240), &SPRT), Sprt::H0);
+    }
+
+    #[test]
+    fn results_added() {
+        let mut record = Record::default();
+        record.add(
+            Outcome::Decisive {
+                winner: Color::White,
+            },
+            Color::White,
+        );
+        record.add(
+            Outcome::Decisive {
+                winner: Color::White,
+            },
+            Color::Black,
+        );
+        record.add(Outcome::Draw, Color::Black);
+        record += Record {
+            wins: 1,
+            draws: 0,
+            losses: 0,
+        };
+
+        assert_eq!(record.to_string(), "+2 =1 -1");
+        assert_eq!(record.points(), 2.5);
+    }
+}
@@ -0,0 +1,619 @@
+//! Tournaments between multiple engines
+
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use color_eyre::eyre::{bail, ensure, eyre, Context};
+use color_eyre::Result;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use shakmaty::{Color, Outcome};
+use structopt::StructOpt;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::BufReader;
+use tracing::{debug, error, info, instrument, warn};
+
+use super::game::{self, Adjudicator, Player, Rules};
+use super::opening::{self, Opening};
+use super::stats::Record;
+use crate::clock::{parse_time_control, TimeControl};
+use crate::knowledge::{PgnReader, PgnWriter};
+use crate::rev::review_game;
+use crate::shutdown::Shutdown;
+use crate::{config, Config};
+
+/// Tournament schedule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Schedule {
+    /// Every engine plays against every other one
+    RoundRobin,
+    /// The first engine plays against all the others
+    Gauntlet,
+}
+
+impl FromStr for Schedule {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "round-robin" => Ok(Self::RoundRobin),
+            "gauntlet" => Ok(Self::Gauntlet),
+            _ => bail!("Unknown schedule {s}, expected `round-robin` or `gauntlet`"),
+        }
+    }
+}
+
+/// Scheduled game
+#[derive(Debug, Clone, Copy)]
+struct Pairing {
+    /// Game number, starting with 1
+    no: usize,
+    white: usize,
+    black: usize,
+    opening: usize,
+}
+
+/// Game finished in the tournament, engines are the indices in the tournament engines
+#[derive(Debug, Serialize, Deserialize)]
+struct Finished {
+    white: usize,
+    black: usize,
+    result: String,
+}
+
+/// Tournament setup determining the games by their numbers, a resumed tournament has to match it
+#[derive(Debug, Serialize, Deserialize)]
+struct Setup {
+    engines: Vec<String>,
+    schedule: Schedule,
+    /// Games of every pairing
+    games: usize,
+    /// Openings by their `Opening::key`
+    openings: Vec<String>,
+}
+
+impl Setup {
+    /// Checks if the tournament being resumed was set up the same way
+    fn ensure_resumable(&self, resumed: &Setup) -> Result<()> {
+        ensure!(
+            self.engines == resumed.engines,
+            "Resumed tournament was played by different engines"
+        );
+        ensure!(
+            self.schedule == resumed.schedule,
+            "Resumed tournament was played with a different schedule"
+        );
+        ensure!(
+            self.games == resumed.games,
+            "Resumed tournament was played with a different number of games"
+        );
+        ensure!(
+            self.openings == resumed.openings,
+            "Resumed tournament was played with different openings"
+        );
+        Ok(())
+    }
+}
+
+/// Tournament progress, stored after every game to allow resuming
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    #[serde(flatten)]
+    setup: Setup,
+    /// Finished games by their number
+    finished: BTreeMap<usize, Finished>,
+    /// Errors of the games which failed, by their number. Failed games are played again when the
+    /// tournament is resumed.
+    #[serde(default)]
+    failed: BTreeMap<usize, String>,
+}
+
+impl State {
+    fn new(setup: Setup) -> Self {
+        Self {
+            setup,
+            finished: BTreeMap::new(),
+            failed: BTreeMap::new(),
+        }
+    }
+
+    #[instrument(err)]
+    async fn load(path: &Path) -> Result<Self> {
+        let data = tokio::fs::read(path)
+            .await
+            .wrap_err("While reading tournament state")?;
+        serde_json::from_slice(&data).wrap_err("While parsing tournament state")
+    }
+
+    /// Stores the state, the file is replaced atomically
+    async fn store(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(self)?)
+            .await
+            .wrap_err("While writing tournament state")?;
+        tokio::fs::rename(&tmp, path)
+            .await
+            .wrap_err("While writing tournament state")
+    }
+
+    /// Games results as the `Record` of every engine against every other one
+    fn crosstable(&self) -> Result<Vec<Vec<Record>>> {
+        let engines = self.setup.engines.len();
+        let mut table = vec![vec![Record::default(); engines]; engines];
+        for game in self.finished.values() {
+            let outcome: Outcome = game.result.parse()?;
+            table[game.white][game.black].add(outcome, Color::White);
+            table[game.black][game.white].add(outcome, Color::Black);
+        }
+        Ok(table)
+    }
+}
+
+/// Tournament between the engines from the config, as round-robin or gauntlet (the first engine
+/// against all the others). Games are played concurrently, every game by its own engine instances.
+#[derive(Debug, StructOpt)]
+pub struct Tournament {
+    /// Engines names
+    #[structopt(required = true, min_values = 2)]
+    engines: Vec<String>,
+    /// Tournament schedule, `round-robin` or `gauntlet`
+    #[structopt(short, long, default_value = "round-robin")]
+    schedule: Schedule,
+    /// Output PGN file with all the games
+    #[structopt(short, long)]
+    output: PathBuf,
+    /// Opening suite - EPD positions or PGN games main lines
+    #[structopt(long)]
+    openings: Option<PathBuf>,
+    /// Games of every pairing, by default every opening is played with both colours
+    #[structopt(short, long)]
+    games: Option<usize>,
+    /// Time control as minutes and increment seconds
+    #[structopt(short, long, parse(try_from_str = parse_time_control))]
+    time_control: Option<TimeControl>,
+    /// Nodes limit per move
+    #[structopt(long)]
+    nodes: Option<u64>,
+    /// Games played concurrently. By default as many as the CPUs allow with the engines' `Threads`
+    /// option.
+    #[structopt(short, long)]
+    jobs: Option<NonZeroUsize>,
+    /// File the tournament progress is stored to
+    #[structopt(long)]
+    state: Option<PathBuf>,
+    /// Continues the tournament stored in the state file
+    #[structopt(long, requires = "state")]
+    resume: bool,
+    /// Reviews the games after the tournament, the reviewed games are stored in this PGN file
+    #[structopt(long)]
+    review: Option<PathBuf>,
+}
+
+impl Tournament {
+    /// Games of every pairing
+    fn games(&self, openings: usize) -> usize {
+        self.games.unwrap_or(2 * openings)
+    }
+
+    /// All the tournament games in the order they are played
+    fn schedule(&self, engines: usize, openings: usize) -> Vec<Pairing> {
+        let pairs: Vec<_> = match self.schedule {
+            Schedule::RoundRobin => (0..engines)
+                .flat_map(|a| (a + 1..engines).map(move |b| (a, b)))
+                .collect(),
+            Schedule::Gauntlet => (1..engines).map(|b| (0, b)).collect(),
+        };
+
+        // Games are scheduled round after round, so all the pairings progress evenly
+        (0..self.games(openings))
+            .flat_map(|round| pairs.iter().map(move |&pair| (round, pair)))
+            .enumerate()
+            .map(|(idx, (round, (a, b)))| {
+                // Every opening is played twice in a row, with swapped colours
+                let (white, black) = match round % 2 {
+                    0 => (a, b),
+                    _ => (b, a),
+                };
+                Pairing {
+                    no: idx + 1,
+                    white,
+                    black,
+                    opening: round / 2 % openings,
+                }
+            })
+            .collect()
+    }
+
+    /// Concurrent games the CPUs can handle. Only one engine of the game searches at a time, so
+    /// a game needs the threads of a single engine.
+    fn jobs(&self, engines: &[config::Engine]) -> usize {
+        self.jobs
+            .map_or_else(|| config::Engine::concurrency(engines), NonZeroUsize::get)
+    }
+
+    /// Plays a single game with the fresh engine instances
+    #[instrument(skip_all, fields(game = pairing.no), err)]
+    async fn play(
+        engines: &[config::Engine],
+        config: &Config,
+        pairing: Pairing,
+        opening: &Opening,
+        rules: &Rules<'_>,
+        mut shutdown: Shutdown,
+    ) -> Result<game::Game> {
+        let mut white = Player::start(engines[pairing.white].clone()).await?;
+        let mut black = Player::start(engines[pairing.black].clone()).await?;
+        let mut adjudicator = Adjudicator::start(config).await?;
+
+        let game = game::play(
+            &mut white,
+            &mut black,
+            opening,
+            rules,
+            adjudicator.as_mut(),
+            &mut shutdown,
+        )
+        .await?;
+
+        white.quit().await?;
+        black.quit().await?;
+        if let Some(adjudicator) = adjudicator {
+            adjudicator.quit().await?;
+        }
+        Ok(game)
+    }
+
+    /// Prints the crosstable and the rating list. Ratings are the performances against the
+    /// field.
+    fn report(state: &State) -> Result<()> {
+        let table = state.crosstable()?;
+        let totals: Vec<_> = table
+            .iter()
+            .map(|row| {
+                row.iter().fold(Record::default(), |mut total, record| {
+                    total += *record;
+                    total
+                })
+            })
+            .collect();
+
+        let engines = &state.setup.engines;
+        let mut ranking: Vec<_> = (0..engines.len()).collect();
+        ranking.sort_by(|a, b| totals[*b].points().total_cmp(&totals[*a].points()));
+        let width = engines.iter().map(String::len).max().unwrap_or(0).max(6);
+
+        println!();
+        print!("{:>3} {:width$} ", "", "Engine");
+        for rank in 1..=ranking.len() {
+            print!("{rank:>5}");
+        }
+        println!("  Score  Games");
+
+        for (rank, &engine) in ranking.iter().enumerate() {
+            print!("{:>3} {:width$} ", rank + 1, engines[engine]);
+            for &opponent in &ranking {
+                let record = &table[engine][opponent];
+                match record.games() {
+                    0 => print!("{:>5}", "-"),
+                    _ => print!("{:>5}", record.points()),
+                }
+            }
+            println!(
+                "  {:>5}  {:>5}",
+                totals[engine].points(),
+                totals[engine].games()
+            );
+        }
+
+        println!();
+        println!("{:>3} {:width$}    Elo      ±  Record", "", "Engine");
+        for (rank, &engine) in ranking.iter().enumerate() {
+            let total = &totals[engine];
+            let (elo, margin) = match total.elo() {
+                Some((elo, margin)) if elo.is_finite() => {
+                    (format!("{elo:+.0}"), format!("{margin:.0}"))
+                }
+                _ => ("?".to_owned(), "?".to_owned()),
+            };
+            println!(
+                "{:>3} {:width$} {elo:>6} {margin:>6}  {total}",
+                rank + 1,
+                engines[engine]
+            );
+        }
+
+        if !state.failed.is_empty() {
+            println!();
+            for (no, err) in &state.failed {
+                println!("Game {no} failed: {err}");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reviews all the tournament games
+    #[instrument(skip(self, config, shutdown), err)]
+    async fn review(&self, path: &Path, config: &Config, shutdown: &Shutdown) -> Result<()> {
+        let mut games = PgnReader::new(BufReader::new(File::open(&self.output).await?));
+        let mut output = PgnWriter::new(File::create(path).await?);
+
+        while let Some(game) = games.next_game().await? {
+            if shutdown.is_requested() {
+                warn!("Review interrupted");
+                break;
+            }
+
+            match game {
+                Ok(knowledge) => {
+                    info!(game = games.games(), "Reviewing game");
+                    let knowledge = review_game(knowledge, config, shutdown).await?;
+                    output.write(&knowledge).await?;
+                }
+                Err(err) => warn!(%err, game = games.games(), "Invalid game, skipping"),
+            }
+        }
+
+        info!(file = ?path, games = output.games(), "Reviewed games stored");
+        Ok(())
+    }
+
+    #[instrument(skip(self, config), err)]
+    pub async fn run(self, config: Config) -> Result<()> {
+        info!(?self, "Tournament");
+        ensure!(
+            self.time_control.is_some() || self.nodes.is_some(),
+            "Time control or nodes limit is required"
+        );
+        let shutdown = Shutdown::listen()?;
+
+        let engines = self
+            .engines
+            .iter()
+            .map(|name| {
+                config
+                    .engine_named(name)
+                    .cloned()
+                    .ok_or_else(|| eyre!("Unknown engine {name}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let openings = match &self.openings {
+            Some(path) => opening::load(path).await?,
+            None => vec![Opening::default()],
+        };
+
+        let setup = Setup {
+            engines: self.engines.clone(),
+            schedule: self.schedule,
+            games: self.games(openings.len()),
+            openings: openings.iter().map(Opening::key).collect(),
+        };
+        let mut state = match (&self.state, self.resume) {
+            (Some(path), true) => {
+                let state = State::load(path).await?;
+                setup.ensure_resumable(&state.setup)?;
+                state
+            }
+            _ => State::new(setup),
+        };
+
+        let schedule = self.schedule(engines.len(), openings.len());
+        let total = schedule.len();
+        let mut pending = schedule
+            .into_iter()
+            .filter(|pairing| !state.finished.contains_key(&pairing.no))
+            .rev()
+            .collect::<Vec<_>>();
+        let jobs = self.jobs(&engines);
+        info!(
+            games = total,
+            remaining = pending.len(),
+            jobs,
+            "Tournament scheduled"
+        );
+
+        let output = match self.resume {
+            true => {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.output)
+                    .await?
+            }
+            false => File::create(&self.output).await?,
+        };
+        let mut output = PgnWriter::new(output);
+
+        let rules = Rules {
+            time_control: self.time_control,
+            nodes: self.nodes,
+            config: &config.matches,
+        };
+        let mut games = FuturesUnordered::new();
+
+        loop {
+            while !shutdown.is_requested() && games.len() < jobs {
+                let Some(pairing) = pending.pop() else {
+                    break;
+                };
+
+                debug!(?pairing, "Starting game");
+                let opening = &openings[pairing.opening];
+                let game = Self::play(
+                    &engines,
+                    &config,
+                    pairing,
+                    opening,
+                    &rules,
+                    shutdown.clone(),
+                );
+                games.push(async move { (pairing, game.await) });
+            }
+
+            let Some((pairing, game)) = games.next().await else {
+                break;
+            };
+
+            // Failed game doesn't stop the others, it is recorded and played again on resume
+            let mut game = match game {
+                Ok(game) => game,
+                Err(err) => {
+                    error!(game = pairing.no, %err, "Game failed");
+                    state.failed.insert(pairing.no, err.to_string());
+                    if let Some(path) = &self.state {
+                        state.store(path).await?;
+                    }
+                    continue;
+                }
+            };
+            let Some(outcome) = game.outcome else {
+                warn!(game = pairing.no, "Game abandoned");
+                continue;
+            };
+
+            game.knowledge.set_tag("Round", pairing.no.to_string());
+            output.write(&game.knowledge).await?;
+
+            state.failed.remove(&pairing.no);
+            state.finished.insert(
+                pairing.no,
+                Finished {
+                    white: pairing.white,
+                    black: pairing.black,
+                    result: outcome.to_string(),
+                },
+            );
+            if let Some(path) = &self.state {
+                state.store(path).await?;
+            }
+
+            info!(
+                game = pairing.no,
+                white = self.engines[pairing.white],
+                black = self.engines[pairing.black],
+                %outcome,
+                "Game finished ({}/{total})",
+                state.finished.len()
+            );
+        }
+
+        Self::report(&state)?;
+
+        if shutdown.is_requested() {
+            match self.state {
+                Some(_) => warn!("Tournament interrupted, it can be continued with --resume"),
+                None => warn!("Tournament interrupted"),
+            }
+            return Ok(());
+        }
+
+        if let Some(path) = &self.review {
+            self.review(path, &config, &shutdown).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tournament(args: &[&str]) -> Tournament {
+        let args = ["tournament", "-o", "games.pgn"].iter().chain(args);
+        Tournament::from_iter_safe(args).unwrap()
+    }
+
+    fn pairings(schedule: &[Pairing]) -> Vec<(usize, usize, usize)> {
+        schedule
+            .iter()
+            .map(|pairing| (pairing.white, pairing.black, pairing.opening))
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_scheduled() {
+        let schedule = tournament(&["a", "b", "c"]).schedule(3, 1);
+        let nos: Vec<_> = schedule.iter().map(|pairing| pairing.no).collect();
+        assert_eq!(nos, (1..=6).collect::<Vec<_>>());
+        assert_eq!(
+            pairings(&schedule),
+            [
+                (0, 1, 0),
+                (0, 2, 0),
+                (1, 2, 0),
+                (1, 0, 0),
+                (2, 0, 0),
+                (2, 1, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn gauntlet_scheduled() {
+        let schedule = tournament(&["a", "b", "c", "-s", "gauntlet"]).schedule(3, 2);
+        assert_eq!(
+            pairings(&schedule),
+            [
+                (0, 1, 0),
+                (0, 2, 0),
+                (1, 0, 0),
+                (2, 0, 0),
+                (0, 1, 1),
+                (0, 2, 1),
+                (1, 0, 1),
+                (2, 0, 1),
+            ]
+        );
+
+        let schedule = tournament(&["a", "b", "-g", "3"]).schedule(2, 2);
+        assert_eq!(pairings(&schedule), [(0, 1, 0), (1, 0, 0), (0, 1, 1)]);
+    }
+
+    #[test]
+    fn crosstable_counted() {
+        let setup = Setup {
+            engines: vec!["a".into(), "b".into()],
+            schedule: Schedule::RoundRobin,
+            games: 3,
+            openings: vec![],
+        };
+        let mut state = State::new(setup);
+        for (no, (white, black, result)) in [(0, 1, "1-0"), (1, 0, "1-0"), (0, 1, "1/2-1/2")]
+            .into_iter()
+            .enumerate()
+        {
+            let result = result.into();
+            state.finished.insert(
+                no + 1,
+                Finished {
+                    white,
+                    black,
+                    result,
+                },
+            );
+        }
+
+        let table = state.crosstable().unwrap();
+        assert_eq!(table[0][1].points(), 1.5);
+        assert_eq!(table[1][0].points(), 1.5);
+        assert_eq!(table[0][1].games(), 3);
+    }
+
+    #[test]
+    fn different_setup_not_resumed() {
+        let setup = |games| Setup {
+            engines: vec!["a".into(), "b".into()],
+            schedule: Schedule::Gauntlet,
+            games,
+            openings: vec!["startpos".into()],
+        };
+        assert!(setup(2).ensure_resumable(&setup(2)).is_ok());
+        assert!(setup(2).ensure_resumable(&setup(4)).is_err());
+    }
+}
@@ -0,0 +1,164 @@
+//! Engine benchmark - fixed positions searched to a fixed depth
+
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{Context, OptionExt};
+use color_eyre::Result;
+use shakmaty::fen::Fen;
+use shakmaty::{CastlingMode, Chess};
+use structopt::StructOpt;
+use tracing::{debug, info, instrument, warn};
+
+use crate::shutdown::Shutdown;
+use crate::uci;
+use crate::Config;
+
+/// Benchmark positions - openings, middlegames and endgames
+const POSITIONS: &[&str] = &[
+    "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+    "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 10",
+    "r1bqkb1r/pppp1ppp/2n2n2/4p3/2B1P3/5N2/PPPP1PPP/RNBQK2R w KQkq - 4 4",
+    "rnbq1rk1/ppp1bppp/4pn2/3p4/2PP4/2N2N2/PP2PPPP/R1BQKB1R w KQ - 4 6",
+    "r2q1rk1/pp2bppp/2n1pn2/2pp4/3P1B2/2PBPN2/PP1N1PPP/R2QK2R w KQ - 2 9",
+    "2rq1rk1/pb1nbppp/1p2pn2/2pp4/2PP4/1PN1PN2/PB2BPPP/2RQ1RK1 w - - 2 12",
+    "r1b2rk1/2q1bppp/p2p1n2/np2p3/3PP3/5N1P/PPBN1PP1/R1BQR1K1 b - - 1 13",
+    "3r1rk1/pp3ppp/2n1b3/2q1p3/4P3/2P1BN2/PP1Q1PPP/R4RK1 w - - 0 17",
+    "r4rk1/1pp1qppp/p1np1n2/2b1p1B1/2B1P1b1/P1NP1N2/1PP1QPPP/R4RK1 w - - 0 10",
+    "2r2rk1/1bqnbppp/p2ppn2/1p6/3NP3/1BN1BP2/PPPQ2PP/2KR3R w - - 4 14",
+    "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+    "6k1/5ppp/8/8/8/8/5PPP/3R2K1 w - - 0 1",
+    "8/8/4k3/3p4/3P4/4K3/8/8 w - - 0 1",
+    "8/5pk1/6p1/7p/7P/6P1/5PK1/3r4 b - - 0 1",
+    "4r1k1/1p3ppp/p7/3R4/8/1P3P2/P5PP/6K1 w - - 0 1",
+    "8/8/1p1k4/p1p5/P1P1K3/1P6/8/8 w - - 0 1",
+];
+
+/// Single position result
+#[derive(Debug)]
+struct Sample {
+    nodes: u64,
+    /// Time to the depth (until the best move is sent)
+    time: Duration,
+    /// Last speed reported by the engine
+    nps: Option<u64>,
+}
+
+/// Engine benchmark. Fixed positions are searched to the depth, the nodes and speed reported by
+/// the engine are collected. Engine options (eg. `Threads` or `Hash`) can be overridden to compare
+/// their effect.
+#[derive(Debug, StructOpt)]
+pub struct Bench {
+    /// Search depth
+    #[structopt(short, long, default_value = "13")]
+    depth: u8,
+    /// Engine name, the main `engine` by default
+    #[structopt(short, long)]
+    engine: Option<String>,
+    /// Engine option overrides as `Name=Value`
+    #[structopt(short = "O", long = "option")]
+    options: Vec<String>,
+}
+
+impl Bench {
+    /// Searches the single position
+    #[instrument(skip(engine, shutdown), err)]
+    async fn search(
+        engine: &mut uci::Engine,
+        position: Chess,
+        depth: u8,
+        shutdown: &mut Shutdown,
+    ) -> Result<Option<Sample>> {
+        engine.new_game().await?;
+
+        let started = Instant::now();
+        let mut stream = engine.go(position, &[], Some(depth), None).await?;
+        let (mut nodes, mut nps) = (0, None);
+
+        loop {
+            tokio::select! {
+                info = stream.info() => match info? {
+                    Some(info) => {
+                        nodes = info.nodes;
+                        nps = info.nps.or(nps);
+                    }
+                    None => break,
+                },
+                _ = shutdown.wait() => {
+                    stream.stop_wait().await?;
+                    return Ok(None);
+                },
+            }
+        }
+
+        stream.best().await?;
+        Ok(Some(Sample {
+            nodes,
+            time: started.elapsed(),
+            nps,
+        }))
+    }
+
+    #[instrument(skip(self, config), err)]
+    pub async fn run(self, config: Config) -> Result<()> {
+        info!(?self, "Benchmark");
+        let mut shutdown = Shutdown::listen()?;
+
+        let engine_config = match &self.engine {
+            Some(name) => config.engine_named(name),
+            None => config.engine.as_ref(),
+        };
+        let mut engine_config = engine_config.ok_or_eyre("No engine configuration")?.clone();
+        for option in &self.options {
+            let (name, value) = option
+                .split_once('=')
+                .ok_or_eyre("Option has to be given as `Name=Value`")?;
+            engine_config
+                .options
+                .insert(name.trim().to_owned(), value.trim().to_owned());
+        }
+
+        let mut engine = uci::Engine::run(engine_config).await?;
+        let (mut nodes, mut time) = (0, Duration::ZERO);
+        let mut reported = vec![];
+
+        for (idx, &fen) in POSITIONS.iter().enumerate() {
+            let fen: Fen = fen.parse().wrap_err("Invalid benchmark position")?;
+            let position = fen.into_position(CastlingMode::Standard)?;
+
+            let Some(result) =
+                Self::search(&mut engine, position, self.depth, &mut shutdown).await?
+            else {
+                warn!("Benchmark interrupted");
+                break;
+            };
+            debug!(?result, "Position searched");
+
+            let nps = result.nps.map_or("-".to_owned(), |nps| nps.to_string());
+            println!(
+                "Position {:>2}/{}: nodes {:>10}, time {:>7.3}s, nps {:>10}",
+                idx + 1,
+                POSITIONS.len(),
+                result.nodes,
+                result.time.as_secs_f64(),
+                nps
+            );
+
+            nodes += result.nodes;
+            time += result.time;
+            reported.extend(result.nps);
+        }
+
+        engine.quit().await?;
+
+        println!(
+            "Total nodes {nodes}, time to depth {:.3}s",
+            time.as_secs_f64()
+        );
+        println!("NPS {:.0}", nodes as f64 / time.as_secs_f64());
+        if !reported.is_empty() {
+            let average = reported.iter().sum::<u64>() / reported.len() as u64;
+            println!("Engine reported NPS (average) {average}");
+        }
+        Ok(())
+    }
+}
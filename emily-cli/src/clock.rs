@@ -0,0 +1,114 @@
+//! Game clocks for the games played on time
+
+use std::time::Duration;
+
+use color_eyre::eyre::{ensure, Context};
+use color_eyre::Result;
+use shakmaty::{ByColor, Color};
+
+use crate::uci;
+
+/// Time control - base time per side and the increment per move
+#[derive(Debug, Clone, Copy)]
+pub struct TimeControl {
+    pub base: Duration,
+    pub inc: Duration,
+}
+
+impl TimeControl {
+    /// PGN `TimeControl` tag value
+    pub fn tag(&self) -> String {
+        format!("{}+{}", self.base.as_secs_f64(), self.inc.as_secs_f64())
+    }
+}
+
+/// Parses the time control as minutes and increment seconds, eg. `5+3` or `0.5+0.1`
+pub fn parse_time_control(tc: &str) -> Result<TimeControl> {
+    let (base, inc) = tc.split_once('+').unwrap_or((tc, "0"));
+    let base: f64 = base.parse().wrap_err("Invalid base time")?;
+    let inc: f64 = inc.parse().wrap_err("Invalid increment")?;
+    ensure!(base > 0., "Base time has to be positive");
+    ensure!(inc >= 0., "Increment cannot be negative");
+
+    Ok(TimeControl {
+        base: Duration::try_from_secs_f64(base * 60.).wrap_err("Invalid base time")?,
+        inc: Duration::try_from_secs_f64(inc).wrap_err("Invalid increment")?,
+    })
+}
+
+/// Formats the clock as `m:ss.d`
+pub fn fmt_clock(clock: Duration) -> String {
+    let tenths = clock.as_millis() / 100;
+    format!("{}:{:02}.{}", tenths / 600, tenths / 10 % 60, tenths % 10)
+}
+
+/// Clocks of both sides
+#[derive(Debug, Clone)]
+pub struct Clocks {
+    inc: Duration,
+    remaining: ByColor<Duration>,
+}
+
+impl Clocks {
+    pub fn new(tc: TimeControl) -> Self {
+        Self {
+            inc: tc.inc,
+            remaining: ByColor::new_with(|_| tc.base),
+        }
+    }
+
+    /// Time left for the `color` side
+    pub fn remaining(&self, color: Color) -> Duration {
+        self.remaining[color]
+    }
+
+    /// Clocks in the form passed to the engine
+    pub fn uci(&self) -> uci::Clock {
+        uci::Clock {
+            wtime: self.remaining.white,
+            btime: self.remaining.black,
+            winc: self.inc,
+            binc: self.inc,
+        }
+    }
+
+    /// Stops the `color` clock after the move which took `elapsed`, adding the increment. Returns
+    /// `false` if the time run out before the move was made.
+    pub fn punch(&mut self, color: Color, elapsed: Duration) -> bool {
+        match self.remaining[color].checked_sub(elapsed) {
+            Some(remaining) => {
+                self.remaining[color] = remaining + self.inc;
+                true
+            }
+            None => {
+                self.remaining[color] = Duration::ZERO;
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_control_parsed() {
+        let tc = parse_time_control("0.5+0.1").unwrap();
+        assert_eq!(tc.base, Duration::from_secs(30));
+        assert_eq!(tc.inc, Duration::from_millis(100));
+
+        let tc = parse_time_control("3").unwrap();
+        assert_eq!(tc.base, Duration::from_secs(180));
+        assert_eq!(tc.inc, Duration::ZERO);
+    }
+
+    #[test]
+    fn invalid_time_control_rejected() {
+        for tc in [
+            "0+1", "-1+0", "5+-1", "inf+0", "5+inf", "NaN+0", "5+NaN", "x+0",
+        ] {
+            assert!(parse_time_control(tc).is_err(), "{tc}");
+        }
+    }
+}
@@ -1,4 +1,6 @@
 use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
 use std::time::Duration;
 
 use derivative::Derivative;
@@ -13,16 +15,38 @@ pub struct Config {
     /// Engine configuration
     #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
     pub engine: Option<Engine>,
+    /// Additional named engines (`[[engines]]`) for the commands using multiple engines
+    #[serde(default)]
+    pub engines: Vec<Engine>,
     /// Game review configuration
     #[serde(default)]
     pub rev: Rev,
+    /// Engine matches configuration
+    #[serde(default, rename = "match")]
+    pub matches: Match,
+    /// EPD test suites configuration
+    #[serde(default)]
+    pub epd: Epd,
+    /// UCI proxy configuration (emily acting as an engine)
+    #[serde(default)]
+    pub uci: Proxy,
     /// Logging configuration
     #[serde(default)]
     pub logging: Logging,
 }
 
+impl Config {
+    /// Engine configuration by its name, either the main `engine` or one of `engines`
+    pub fn engine_named(&self, name: &str) -> Option<&Engine> {
+        self.engine
+            .iter()
+            .chain(&self.engines)
+            .find(|engine| engine.name == name)
+    }
+}
+
 /// Cross-functionality engine configuration
-#[derive(Derivative, Deserialize)]
+#[derive(Derivative, Deserialize, Clone)]
 #[derivative(Debug)]
 pub struct Engine {
     /// Engine name for debugging and caching
@@ -41,10 +65,39 @@ pub struct Engine {
     /// Debug mode (all debug information would be forwarded to the log)
     #[serde(default)]
     pub debug: bool,
+    /// Protocol the engine speaks
+    #[serde(default)]
+    pub protocol: Protocol,
+}
+
+impl Engine {
+    /// Engine instances the CPUs can run concurrently, every one with the threads (the `Threads`
+    /// option) of the most demanding of the `engines`. Engines don't ponder, so an instance
+    /// searches only on its turn.
+    pub fn concurrency<'a>(engines: impl IntoIterator<Item = &'a Engine>) -> usize {
+        let threads = engines
+            .into_iter()
+            .filter_map(|engine| engine.options.get("Threads")?.parse::<usize>().ok())
+            .max()
+            .unwrap_or(1);
+        let cpus = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        (cpus / threads.max(1)).max(1)
+    }
+}
+
+/// Engine communication protocol
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Uci,
+    /// Chess Engine Communication Protocol (xboard/WinBoard)
+    #[serde(alias = "xboard")]
+    Cecp,
 }
 
 /// Game review configuration
-#[derive(Derivative, Deserialize, Default)]
+#[derive(Derivative, Deserialize, Default, Clone)]
 #[derivative(Debug)]
 pub struct Rev {
     /// Analysis depth limit (per move)
@@ -53,6 +106,207 @@ pub struct Rev {
     /// Analysis time limit (per move)
     #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
     pub time: Option<Duration>,
+    /// Limits of the whole review
+    #[serde(default)]
+    pub budget: Budget,
+    /// Deeper re-search of positions with large evaluation swings
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub research: Option<Research>,
+    /// Anytime mode - intermediate engine results are applied to the knowledge as they arrive
+    /// (marked provisional until the search ends), so the interrupted searches are not lost
+    #[serde(default)]
+    pub anytime: bool,
+    /// Threats analysis (what the opponent would play if it was their move)
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub threats: Option<Threats>,
+}
+
+/// Threats analysis configuration. Threats are analysed by a separate engine instance.
+#[derive(Derivative, Deserialize, Clone)]
+#[derivative(Debug)]
+pub struct Threats {
+    /// Threat analysis depth limit (per move)
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub depth: Option<u8>,
+    /// Threat analysis time limit (per move)
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub time: Option<Duration>,
+}
+
+/// Re-search of the positions which evaluation differs much from their parent's one. Shallow
+/// analysis of tactical positions is prone to the horizon effect. At least one of the limits is
+/// required.
+#[derive(Derivative, Deserialize, Clone)]
+#[derivative(Debug)]
+#[serde(try_from = "ResearchLimits")]
+pub struct Research {
+    /// Evaluation difference triggering the re-search (in centipawns). Changes to and from mate
+    /// always trigger it.
+    pub swing: u16,
+    /// Re-search depth limit (per move)
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub depth: Option<u8>,
+    /// Re-search time limit (per move)
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub time: Option<Duration>,
+}
+
+/// Error of the re-search configured without limits. Unlike other config errors it is not
+/// recovered from by using defaults, as the re-searches would never finish.
+pub const RESEARCH_UNLIMITED: &str = "re-search requires the depth or time limit";
+
+/// `Research` as configured, before the limits are checked
+#[derive(Deserialize)]
+struct ResearchLimits {
+    swing: u16,
+    depth: Option<u8>,
+    time: Option<Duration>,
+}
+
+impl TryFrom<ResearchLimits> for Research {
+    type Error = &'static str;
+
+    fn try_from(limits: ResearchLimits) -> Result<Self, Self::Error> {
+        if limits.depth.is_none() && limits.time.is_none() {
+            return Err(RESEARCH_UNLIMITED);
+        }
+
+        Ok(Self {
+            swing: limits.swing,
+            depth: limits.depth,
+            time: limits.time,
+        })
+    }
+}
+
+/// Review budget - the review finishes when any of the limits is reached, even if there are
+/// positions left to analyse
+#[derive(Derivative, Deserialize, Default, Clone)]
+#[derivative(Debug)]
+pub struct Budget {
+    /// Maximum halfmoves from the root to analyse
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub max_plies: Option<usize>,
+    /// Maximum positions analysed
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub max_positions: Option<usize>,
+    /// Total analysis time, searches still in progress when it elapses are stopped
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub max_time: Option<Duration>,
+    /// Total nodes searched by engines
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub max_nodes: Option<u64>,
+    /// Lines are not analysed further once the absolute evaluation exceeds this limit (in
+    /// centipawns). Mates always exceed the limit.
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub eval_limit: Option<i16>,
+}
+
+/// EPD test suites configuration
+#[derive(Derivative, Deserialize, Default)]
+#[derivative(Debug)]
+pub struct Epd {
+    /// Search depth limit (per position)
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub depth: Option<u8>,
+    /// Search time limit (per position)
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub time: Option<Duration>,
+}
+
+/// UCI proxy configuration. Searches are answered from the book, the analysis cache or the
+/// tablebase engine when possible, otherwise they are forwarded to the engine.
+#[derive(Derivative, Deserialize, Default)]
+#[derivative(Debug)]
+pub struct Proxy {
+    /// Polyglot opening book
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub book: Option<PathBuf>,
+    /// Analysis files (stored by `rev --analysis`) the evaluations are cached from
+    #[serde(default)]
+    pub analysis: Vec<PathBuf>,
+    /// Minimum depth of the cached evaluation to be used
+    #[serde(default)]
+    pub min_depth: u8,
+    /// Engine searching the endgames, with the tablebases configured
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub tablebase: Option<ProxyTablebase>,
+}
+
+/// Endgames searched by the dedicated engine (eg. with the `SyzygyPath` option). The tablebases
+/// are not probed by emily itself, the engine's search reports their results.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ProxyTablebase {
+    /// Name of the engine
+    pub engine: String,
+    /// Maximum number of pieces (including kings) of the position searched by the engine
+    pub pieces: u8,
+}
+
+/// Engine matches configuration
+#[derive(Derivative, Deserialize, Default, Clone)]
+#[derivative(Debug)]
+pub struct Match {
+    /// Adjudicating the game as lost when both engines agree on it
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub resign: Option<Resign>,
+    /// Adjudicating the game as drawn when both engines agree on it
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub draw: Option<Draw>,
+    /// Adjudicating the endgames by the engine with tablebases
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub tablebase: Option<Tablebase>,
+    /// Sequential probability ratio test - the match stops once the test is decided
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub sprt: Option<Sprt>,
+}
+
+/// Resign adjudication. Evaluations are the engines' ones.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Resign {
+    /// Evaluation (in centipawns) at least one side is considered lost
+    pub score: u16,
+    /// Consecutive moves of each side with the evaluation over the `score`
+    pub moves: u8,
+}
+
+/// Draw adjudication. Evaluations are the engines' ones.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Draw {
+    /// Maximum absolute evaluation (in centipawns) of the drawn position
+    pub score: u16,
+    /// Consecutive moves of each side with the evaluation within the `score`
+    pub moves: u8,
+    /// First fullmove the game can be adjudicated at
+    #[serde(default)]
+    pub after: u32,
+}
+
+/// Tablebase adjudication. Positions with few pieces are evaluated by the dedicated engine (which
+/// should have the tablebases configured, eg. with the `SyzygyPath` option). Its mates and
+/// tablebase wins are taken as the game result. Draws are left to the evaluation adjudication, as
+/// a zero evaluation doesn't prove the position drawn.
+#[derive(Derivative, Deserialize, Clone)]
+#[derivative(Debug)]
+pub struct Tablebase {
+    /// Name of the adjudicating engine
+    pub engine: String,
+    /// Maximum number of pieces (including kings) of the adjudicated position
+    pub pieces: u8,
+    /// Evaluation (in centipawns) considered a tablebase win. Mates always are.
+    pub win_score: u16,
+    /// Adjudication search depth
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub depth: Option<u8>,
+}
+
+/// SPRT hypotheses (Elo difference of the first engine) and error probabilities
+#[derive(Debug, Deserialize, Clone)]
+pub struct Sprt {
+    pub elo0: f64,
+    pub elo1: f64,
+    pub alpha: f64,
+    pub beta: f64,
 }
 
 #[derive(Deserialize, Default, Debug)]
@@ -60,3 +314,32 @@ pub struct Logging {
     /// Filter directives to attach
     pub filter: Vec<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rev(toml: &str) -> Result<Rev, toml::de::Error> {
+        toml::from_str(toml)
+    }
+
+    #[test]
+    fn research_limits_required() {
+        let research = rev("[research]\nswing = 150\ndepth = 20")
+            .unwrap()
+            .research
+            .unwrap();
+        assert_eq!(research.swing, 150);
+        assert_eq!(research.depth, Some(20));
+
+        let research = rev("[research]\nswing = 150\ntime = { secs = 2, nanos = 0 }").unwrap();
+        assert_eq!(
+            research.research.unwrap().time,
+            Some(Duration::from_secs(2))
+        );
+
+        let err = rev("[research]\nswing = 150").unwrap_err();
+        assert_eq!(err.message(), RESEARCH_UNLIMITED);
+        assert!(rev("depth = 12").unwrap().research.is_none());
+    }
+}
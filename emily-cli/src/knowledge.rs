@@ -5,6 +5,7 @@ use std::fmt::Formatter;
 
 use color_eyre::eyre::ensure;
 use derivative::Derivative;
+use serde::{Deserialize, Serialize};
 use shakmaty::{Chess, Move, Outcome, Position};
 use tracing::{debug, instrument, trace};
 
@@ -13,8 +14,11 @@ use crate::uci::Score;
 use crate::Result;
 
 use self::pgn::Pgn;
+pub use self::pgn::{PgnReader, PgnWriter};
 
+mod minimax;
 mod pgn;
+mod session;
 
 /// The single variation considered. Variations describes a particular way a position is reached
 /// and it is possible for a variation to repeat a position (up to three times after which draw is
@@ -62,7 +66,7 @@ impl Variation {
 }
 
 /// Single position details
-#[derive(Derivative)]
+#[derive(Derivative, Clone)]
 #[derivative(Debug)]
 pub struct PosInfo {
     /// Position itself
@@ -74,6 +78,27 @@ pub struct PosInfo {
     /// Engine evaluation of the position.
     #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
     eval: Option<Score>,
+    /// Best move found by the engine
+    #[derivative(Debug(format_with = "PosInfo::fmt_best"))]
+    best: Option<Move>,
+    /// Depth of the engine analysis
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    depth: Option<u8>,
+    /// Principal variation found by the engine, starting with the best move
+    #[derivative(Debug(format_with = "PosInfo::fmt_line"))]
+    pv: Option<Vec<Move>>,
+    /// If the engine analysis is still in progress (or was interrupted), so the evaluation, best
+    /// move and principal variation are not final
+    provisional: bool,
+    /// If the engine analysis is a deeper re-search
+    researched: bool,
+    /// Evaluation propagated from the explored moves (see `Knowledge::propagate`)
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    minimax: Option<Score>,
+    /// Opponent's threat - the line the opponent would play if it was their move. Moves are
+    /// played from the position with the side to move swapped.
+    #[derivative(Debug(format_with = "PosInfo::fmt_line"))]
+    threat: Option<Vec<Move>>,
 }
 
 impl PosInfo {
@@ -85,11 +110,26 @@ impl PosInfo {
         map.finish()
     }
 
+    fn fmt_best(best: &Option<Move>, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", best.as_ref().map(|mov| mov.d_mov()).d_opt())
+    }
+
+    fn fmt_line(line: &Option<Vec<Move>>, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", line.as_deref().map(|line| line.d_line()).d_opt())
+    }
+
     fn new(pos: Chess) -> Self {
         Self {
             pos,
             moves: HashMap::new(),
             eval: None,
+            best: None,
+            depth: None,
+            pv: None,
+            provisional: false,
+            researched: false,
+            minimax: None,
+            threat: None,
         }
     }
 
@@ -98,21 +138,98 @@ impl PosInfo {
         &self.pos
     }
 
+    /// Engine evaluation
+    pub fn eval(&self) -> Option<Score> {
+        self.eval
+    }
+
     /// Updates engine evaluation
     pub fn update_eval(&mut self, eval: Score) -> &mut Self {
         self.eval = Some(eval);
         self
     }
+
+    /// Best move found by the engine
+    pub fn best(&self) -> Option<&Move> {
+        self.best.as_ref()
+    }
+
+    /// Updates the best move found by the engine
+    pub fn update_best(&mut self, best: Move) -> &mut Self {
+        self.best = Some(best);
+        self
+    }
+
+    /// Depth of the engine analysis
+    pub fn depth(&self) -> Option<u8> {
+        self.depth
+    }
+
+    /// Updates the depth of the engine analysis
+    pub fn update_depth(&mut self, depth: u8) -> &mut Self {
+        self.depth = Some(depth);
+        self
+    }
+
+    /// Principal variation found by the engine
+    pub fn pv(&self) -> Option<&[Move]> {
+        self.pv.as_deref()
+    }
+
+    /// Updates the principal variation found by the engine
+    pub fn update_pv(&mut self, pv: Vec<Move>) -> &mut Self {
+        self.pv = Some(pv);
+        self
+    }
+
+    /// Checks if the engine analysis is not final
+    pub fn is_provisional(&self) -> bool {
+        self.provisional
+    }
+
+    /// Marks the engine analysis as provisional (or final)
+    pub fn set_provisional(&mut self, provisional: bool) -> &mut Self {
+        self.provisional = provisional;
+        self
+    }
+
+    /// Checks if the engine analysis is a deeper re-search
+    pub fn is_researched(&self) -> bool {
+        self.researched
+    }
+
+    /// Marks the engine analysis as a re-search
+    pub fn set_researched(&mut self, researched: bool) -> &mut Self {
+        self.researched = researched;
+        self
+    }
+
+    /// Opponent's threat line, played from the position with the side to move swapped
+    pub fn threat(&self) -> Option<&[Move]> {
+        self.threat.as_deref()
+    }
+
+    /// Updates the opponent's threat line
+    pub fn update_threat(&mut self, threat: Vec<Move>) -> &mut Self {
+        self.threat = Some(threat);
+        self
+    }
+
+    /// Evaluation propagated from the explored moves, or the engine evaluation if no move after
+    /// the position was evaluated. Updated by `Knowledge::propagate`.
+    pub fn minimax(&self) -> Option<Score> {
+        self.minimax.or(self.eval)
+    }
 }
 
 /// Move after the position details. Sometimes the same position might slightly differ depending on
 /// where it was achieved from - such information is stored in this type.
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MoveInfo;
 
 /// All we know about the analyzed moves. This type has to be exportable (and importable) from/into
 /// PGN.
-#[derive(Derivative)]
+#[derive(Derivative, Clone)]
 #[derivative(Debug)]
 pub struct Knowledge {
     /// Information per position - same position reached the same way are considered the same, even
@@ -128,6 +245,8 @@ pub struct Knowledge {
     variations: Vec<Variation>,
     /// Main line index
     main: usize,
+    /// PGN tags of the game, in the order they were added
+    tags: Vec<(String, String)>,
 }
 
 impl Knowledge {
@@ -140,7 +259,37 @@ impl Knowledge {
             positions: vec![PosInfo::new(root.clone())],
             variations: vec![Variation::new(root.outcome())],
             main: 0,
+            tags: vec![],
+        }
+    }
+
+    /// Sets the PGN tag, overwriting the previous value if the tag was already present
+    pub fn set_tag(&mut self, name: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        let (name, value) = (name.into(), value.into());
+        trace!(name, value, "Setting tag");
+
+        match self.tags.iter_mut().find(|(tag, _)| *tag == name) {
+            Some((_, old)) => *old = value,
+            None => self.tags.push((name, value)),
         }
+        self
+    }
+
+    /// Removes the PGN tag if present
+    pub fn remove_tag(&mut self, name: &str) -> &mut Self {
+        trace!(name, "Removing tag");
+        self.tags.retain(|(tag, _)| tag != name);
+        self
+    }
+
+    /// PGN tags of the game
+    pub fn tags(&self) -> &[(String, String)] {
+        &self.tags
+    }
+
+    /// Accesses the main line and its index
+    pub fn mainline(&self) -> (usize, &Variation) {
+        (self.main, &self.variations[self.main])
     }
 
     /// Accesses the variation and the position information after `hm` halfmoves.
@@ -191,6 +340,38 @@ impl Knowledge {
         vidx: usize,
         hm: usize,
         mov: Move,
+    ) -> Result<(usize, &Variation, &mut PosInfo)> {
+        self.insert_move(vidx, hm, mov, false)
+    }
+
+    /// Adds new move as a new variation branched from `vidx` variation after `hm` halfmoves, even
+    /// if it is the last move of `vidx`, so the `vidx` variation itself is never extended.
+    #[instrument(skip(self, mov), fields(mov = ?mov.d_mov()), err)]
+    pub fn branch_move(
+        &mut self,
+        vidx: usize,
+        hm: usize,
+        mov: Move,
+    ) -> Result<(usize, &Variation, &mut PosInfo)> {
+        self.insert_move(vidx, hm, mov, true)
+    }
+
+    /// Index of the variation branched from `vidx` variation after `hm` halfmoves with `mov`
+    pub fn branched(&self, vidx: usize, hm: usize, mov: &Move) -> Option<usize> {
+        let prefix = &self.variations[vidx].positions[..=hm];
+        self.variations.iter().position(|variation| {
+            variation.positions.get(..=hm) == Some(prefix) && variation.moves.get(hm) == Some(mov)
+        })
+    }
+
+    /// Adds the move to the `vidx` variation, or to its new branch if the variation continues
+    /// differently or if `branch` is requested.
+    fn insert_move(
+        &mut self,
+        vidx: usize,
+        hm: usize,
+        mov: Move,
+        branch: bool,
     ) -> Result<(usize, &Variation, &mut PosInfo)> {
         trace!("Adding move");
 
@@ -200,10 +381,10 @@ impl Knowledge {
             "Extending variation after its last move"
         );
 
-        if variation.moves.get(hm + 1) == Some(&mov) {
+        if variation.moves.get(hm) == Some(&mov) {
             // This variation includes move that is being added
             let variation = &self.variations[vidx];
-            let posidx = variation.positions[hm + 2];
+            let posidx = variation.positions[hm + 1];
             let position = &mut self.positions[posidx];
 
             trace!(
@@ -247,7 +428,7 @@ impl Knowledge {
             debug!(idx = afteridx, "Position added to the knowledge");
         }
 
-        let vidx = match variation.moves.len() == hm {
+        let vidx = match variation.moves.len() == hm && !branch {
             // Adding move to existing variation
             true => vidx,
             false => {
@@ -286,14 +467,6 @@ impl Knowledge {
         Ok((vidx, variation, position))
     }
 
-    /// Updates mainline if `from` is mainline right now
-    pub fn update_mainline(&mut self, from: usize, to: usize) {
-        if from == self.main && to < self.variations.len() {
-            trace!(from, to, "Updating mainline");
-            self.main = to;
-        }
-    }
-
     /// Acceses position by its index
     pub fn position(&self, idx: usize) -> &PosInfo {
         let position = &self.positions[idx];
@@ -301,6 +474,11 @@ impl Knowledge {
         position
     }
 
+    /// All the known positions
+    pub fn positions(&self) -> &[PosInfo] {
+        &self.positions
+    }
+
     /// Return root `PosInfo`
     pub fn root(&self) -> &PosInfo {
         let mainline = &self.variations[self.main];
@@ -316,7 +494,7 @@ impl Knowledge {
     }
 
     /// Retrieves PGN representation for storage
-    pub fn pgn(&self) -> Pgn {
+    pub fn pgn(&self) -> Pgn<'_> {
         trace!("Generating PGN");
         Pgn::new(self)
     }
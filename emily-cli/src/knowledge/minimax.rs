@@ -0,0 +1,311 @@
+//! Minimax back-propagation of the evaluations through the knowledge tree
+
+use shakmaty::{Color, Outcome, Position};
+use tracing::{debug, instrument, trace};
+
+use super::Knowledge;
+use crate::uci::Score;
+
+/// Explored move
+struct Edge {
+    /// Position after the move
+    child: usize,
+    /// If the move is the best move found by the engine
+    best: bool,
+    /// Line conclusion after the move (mate, draw or repetition)
+    outcome: Option<Outcome>,
+}
+
+/// Position state during the propagation
+#[derive(Clone, Copy)]
+enum Visit {
+    New,
+    /// The position is on the line being currently visited, at the given depth
+    OnPath(usize),
+    /// Propagated evaluation, `None` if nothing is known about the position
+    Done(Option<Score>),
+}
+
+/// Single propagation pass
+struct Propagation<'a> {
+    knowledge: &'a Knowledge,
+    /// Explored moves from every position
+    edges: Vec<Vec<Edge>>,
+    visits: Vec<Visit>,
+}
+
+impl<'a> Propagation<'a> {
+    fn new(knowledge: &'a Knowledge) -> Self {
+        let mut edges: Vec<Vec<Edge>> = knowledge.positions.iter().map(|_| vec![]).collect();
+
+        // Variations share their prefixes, so the same moves are met multiple times
+        for variation in &knowledge.variations {
+            for (hm, mov) in variation.moves.iter().enumerate() {
+                let parent = variation.positions[hm];
+                let child = variation.positions[hm + 1];
+                let outcome = variation
+                    .outcome
+                    .filter(|_| hm + 1 == variation.moves.len());
+
+                let edges = &mut edges[parent];
+                if edges
+                    .iter()
+                    .any(|edge| edge.child == child && edge.outcome == outcome)
+                {
+                    continue;
+                }
+
+                edges.push(Edge {
+                    child,
+                    best: knowledge.positions[parent].best.as_ref() == Some(mov),
+                    outcome,
+                });
+            }
+        }
+
+        Self {
+            knowledge,
+            visits: vec![Visit::New; edges.len()],
+            edges,
+        }
+    }
+
+    /// Evaluation of the move concluding the line. Only the side making the move can deliver a
+    /// mate.
+    fn outcome_score(outcome: Outcome) -> Score {
+        match outcome {
+            Outcome::Draw => Score::Cp(0),
+            Outcome::Decisive {
+                winner: Color::White,
+            } => Score::Mate(1),
+            Outcome::Decisive {
+                winner: Color::Black,
+            } => Score::Mate(-1),
+        }
+    }
+
+    /// Evaluation after the move as seen before the move, when `turn` is to move. The mate
+    /// delivered by the side to move is one move further.
+    fn backup(score: Score, turn: Color) -> Score {
+        match (score, turn) {
+            (Score::Mate(n), Color::White) if n > 0 => Score::Mate(n.saturating_add(1)),
+            (Score::Mate(n), Color::Black) if n < 0 => Score::Mate(n.saturating_sub(1)),
+            (score, _) => score,
+        }
+    }
+
+    /// Better evaluation for the side to move (evaluations are from the white PoV)
+    fn better(turn: Color, a: Score, b: Score) -> Score {
+        match turn {
+            Color::White => a.max(b),
+            Color::Black => a.min(b),
+        }
+    }
+
+    /// Propagates evaluation of the position at `depth` of the visited line from the moves
+    /// explored after it. Returns the evaluation, and the lowest depth of the line positions it
+    /// depends on (`usize::MAX` if none).
+    ///
+    /// The engine evaluation is only taken into account if its best move was not explored, as the
+    /// deeper analysis of the best move is more reliable. Returning to the position on the
+    /// currently visited line is a repetition, and is evaluated as a draw. Propagated evaluations
+    /// are shared by transpositions, so the evaluation depending on a repetition of the earlier
+    /// line position is not stored - it is only valid for the current line.
+    fn visit(&mut self, idx: usize, depth: usize) -> (Option<Score>, usize) {
+        match self.visits[idx] {
+            Visit::Done(score) => return (score, usize::MAX),
+            Visit::OnPath(depth) => return (Some(Score::Cp(0)), depth),
+            Visit::New => self.visits[idx] = Visit::OnPath(depth),
+        }
+
+        let position = &self.knowledge.positions[idx];
+        let turn = position.pos.turn();
+        let edges = std::mem::take(&mut self.edges[idx]);
+
+        let mut minimax = None;
+        let mut best_explored = false;
+        let mut lowest = usize::MAX;
+        for edge in &edges {
+            let score = match edge.outcome {
+                Some(outcome) => Some(Self::outcome_score(outcome)),
+                None => {
+                    let (score, low) = self.visit(edge.child, depth + 1);
+                    lowest = lowest.min(low);
+                    score.map(|score| Self::backup(score, turn))
+                }
+            };
+
+            let Some(score) = score else { continue };
+            best_explored |= edge.best;
+            minimax = Some(match minimax {
+                Some(minimax) => Self::better(turn, minimax, score),
+                None => score,
+            });
+        }
+
+        let score = match (minimax, position.eval) {
+            (Some(minimax), Some(eval)) if !best_explored => {
+                Some(Self::better(turn, minimax, eval))
+            }
+            (None, eval) => eval,
+            (minimax, _) => minimax,
+        };
+
+        trace!(idx, eval = ?position.eval, ?score, lowest, "Evaluation propagated");
+        self.edges[idx] = edges;
+        match lowest < depth {
+            true => {
+                self.visits[idx] = Visit::New;
+                (score, lowest)
+            }
+            false => {
+                self.visits[idx] = Visit::Done(score);
+                (score, usize::MAX)
+            }
+        }
+    }
+}
+
+impl Knowledge {
+    /// Back-propagates evaluations from the explored lines, updating `PosInfo::minimax` for every
+    /// position.
+    #[instrument(skip(self))]
+    pub fn propagate(&mut self) {
+        let mut propagation = Propagation::new(self);
+
+        let root = self.variations[self.main].positions[0];
+        propagation.visit(root, 0);
+        // Positions not reachable from the main line root, or evaluated only for the line they
+        // were visited by
+        for idx in 0..self.positions.len() {
+            propagation.visit(idx, 0);
+        }
+
+        let scores: Vec<_> = propagation
+            .visits
+            .into_iter()
+            .map(|visit| match visit {
+                Visit::Done(score) => score,
+                _ => None,
+            })
+            .collect();
+
+        for (position, score) in self.positions.iter_mut().zip(scores) {
+            position.minimax = score;
+        }
+
+        let root = &self.positions[root];
+        debug!(eval = ?root.eval, minimax = ?root.minimax, "Evaluations propagated");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::san::San;
+    use shakmaty::Chess;
+
+    use super::*;
+
+    /// Plays the SAN `moves` from the root, returns the index of the final position
+    fn play(knowledge: &mut Knowledge, moves: &str) -> usize {
+        let (mut vidx, mut hm) = (knowledge.main, 0);
+        for san in moves.split_whitespace() {
+            let (_, position) = knowledge.variation_hm(vidx, hm);
+            let mov = san
+                .parse::<San>()
+                .unwrap()
+                .to_move(position.position())
+                .unwrap();
+            (vidx, _, _) = knowledge.add_move(vidx, hm, mov).unwrap();
+            hm += 1;
+        }
+        knowledge.variations[vidx].positions[hm]
+    }
+
+    fn evaluate(knowledge: &mut Knowledge, moves: &str, eval: Score) -> usize {
+        let idx = play(knowledge, moves);
+        knowledge.positions[idx].eval = Some(eval);
+        idx
+    }
+
+    fn minimax(knowledge: &Knowledge, idx: usize) -> Option<Score> {
+        knowledge.positions[idx].minimax
+    }
+
+    #[test]
+    fn best_moves_propagated() {
+        let mut knowledge = Knowledge::new(Chess::default());
+        evaluate(&mut knowledge, "e4 e5", Score::Cp(20));
+        evaluate(&mut knowledge, "e4 c5", Score::Cp(-10));
+        evaluate(&mut knowledge, "d4 d5", Score::Cp(40));
+        let e4 = play(&mut knowledge, "e4");
+        knowledge.propagate();
+
+        assert_eq!(minimax(&knowledge, e4), Some(Score::Cp(-10)));
+        assert_eq!(minimax(&knowledge, 0), Some(Score::Cp(40)));
+    }
+
+    #[test]
+    fn engine_eval_used_unless_best_move_explored() {
+        let mut knowledge = Knowledge::new(Chess::default());
+        evaluate(&mut knowledge, "e4", Score::Cp(20));
+        knowledge.positions[0].eval = Some(Score::Cp(30));
+        knowledge.propagate();
+        assert_eq!(minimax(&knowledge, 0), Some(Score::Cp(30)));
+
+        let e4 = "e4".parse::<San>().unwrap();
+        knowledge.positions[0].best = Some(e4.to_move(&Chess::default()).unwrap());
+        knowledge.propagate();
+        assert_eq!(minimax(&knowledge, 0), Some(Score::Cp(20)));
+    }
+
+    #[test]
+    fn mates_counted_back() {
+        let mut knowledge = Knowledge::new(Chess::default());
+        let g4 = play(&mut knowledge, "f3 e5 g4");
+        let mate = play(&mut knowledge, "f3 e5 g4 Qh4#");
+        knowledge.propagate();
+
+        assert_eq!(minimax(&knowledge, mate), None);
+        assert_eq!(minimax(&knowledge, g4), Some(Score::Mate(-1)));
+        assert_eq!(minimax(&knowledge, 0), Some(Score::Mate(-2)));
+    }
+
+    #[test]
+    fn repetition_is_draw() {
+        let mut knowledge = Knowledge::new(Chess::default());
+        play(&mut knowledge, "Nf3 Nf6 Ng1 Ng8");
+        knowledge.positions[0].eval = Some(Score::Cp(30));
+        knowledge.positions[0].best = knowledge.variations[0].moves.first().cloned();
+        knowledge.propagate();
+
+        assert_eq!(minimax(&knowledge, 0), Some(Score::Cp(0)));
+    }
+
+    #[test]
+    fn repetition_evaluated_per_line() {
+        // The position before returning to the root is a draw only on the line through the root,
+        // its own evaluation depends on the root one
+        let mut knowledge = Knowledge::new(Chess::default());
+        let ng1 = play(&mut knowledge, "Nf3 Nf6 Ng1");
+        play(&mut knowledge, "Nf3 Nf6 Ng1 Ng8");
+        evaluate(&mut knowledge, "e4", Score::Cp(50));
+        knowledge.propagate();
+
+        assert_eq!(minimax(&knowledge, 0), Some(Score::Cp(50)));
+        assert_eq!(minimax(&knowledge, ng1), Some(Score::Cp(50)));
+    }
+
+    #[test]
+    fn transpositions_shared() {
+        let mut knowledge = Knowledge::new(Chess::default());
+        let a = evaluate(&mut knowledge, "e4 e5 Nf3", Score::Cp(25));
+        let b = play(&mut knowledge, "Nf3 e5 e4");
+        let e5 = play(&mut knowledge, "Nf3 e5");
+        knowledge.propagate();
+
+        assert_eq!(a, b);
+        assert_eq!(minimax(&knowledge, e5), Some(Score::Cp(25)));
+        assert_eq!(minimax(&knowledge, 0), Some(Score::Cp(25)));
+    }
+}
@@ -12,6 +12,10 @@ use super::{Knowledge, MoveInfo, PosInfo, Variation};
 use crate::adapters::debug::{FlatOptExt, MovExt};
 use crate::Result;
 
+pub use self::reader::PgnReader;
+
+mod reader;
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 struct MoveNo(NonZeroU32, Color);
 
@@ -98,8 +102,31 @@ impl Mov<'_> {
         if let Some(eval) = self.posinfo.eval {
             writer.write_all(b"Eval: ").await?;
             writer.write_all(eval.to_string().as_bytes()).await?;
+            if self.posinfo.is_provisional() {
+                writer.write_all(b" (provisional)").await?;
+            }
             writer.write_all(b", ").await?;
         }
+        // Propagated evaluation is only written when the explored lines changed it
+        match self.posinfo.minimax() {
+            Some(minimax) if Some(minimax) != self.posinfo.eval => {
+                writer.write_all(b"Minimax: ").await?;
+                writer.write_all(minimax.to_string().as_bytes()).await?;
+                writer.write_all(b", ").await?;
+            }
+            _ => (),
+        }
+        if let Some(threat) = self.posinfo.threat().and_then(|threat| threat.first()) {
+            // Threat is played with the side to move swapped, which is always legal when it was
+            // analysed
+            if let Ok(pos) = self.posinfo.position().clone().swap_turn() {
+                writer.write_all(b"Threat: ").await?;
+                writer
+                    .write_all(San::from_move(&pos, threat).to_string().as_bytes())
+                    .await?;
+                writer.write_all(b", ").await?;
+            }
+        }
         writer.write_all(b"}\n").await?;
 
         Ok(())
@@ -125,14 +152,14 @@ struct Node<'a> {
     outcome: Option<Outcome>,
 }
 
-impl Node<'_> {
-    async fn write_line<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
-        for mov in &self.line {
-            mov.write(writer).await?;
-        }
-
-        Ok(())
-    }
+/// Single step of writing the PGN tree
+enum Step<'a, 'n> {
+    /// Single move
+    Move(&'n Mov<'a>),
+    /// Node starting from the given move
+    Node(&'n Node<'a>, usize),
+    /// Raw text (variation boundaries)
+    Text(&'static [u8]),
 }
 
 impl<'a> Node<'a> {
@@ -147,11 +174,21 @@ impl<'a> Node<'a> {
         movinfo: Option<&'a MoveInfo>,
         posinfo: &'a PosInfo,
     ) -> (&mut Self, usize) {
+        // Number of the move after `hm`. The node line is empty only for the root node branched
+        // on the very first move.
+        let no = match hm.checked_sub(1) {
+            Some(prev) => self.line[prev].no.next(),
+            None => match self.line.first() {
+                Some(first) => first.no,
+                None => self.branches[0].line[0].no,
+            },
+        };
+
         if hm == self.line.len() && self.branches.is_empty() {
             // Adding a move to the variation
             self.line.push(Mov {
                 mov,
-                no: self.line[hm - 1].no.next(),
+                no,
                 movinfo,
                 posinfo,
             });
@@ -171,7 +208,7 @@ impl<'a> Node<'a> {
                     self.branches.push(Node {
                         line: vec![Mov {
                             mov,
-                            no: self.line[hm - 1].no.next(),
+                            no,
                             movinfo,
                             posinfo,
                         }],
@@ -185,17 +222,19 @@ impl<'a> Node<'a> {
             // Following the main line
             (self, hm + 1)
         } else {
-            // Creating branching point
-            self.branches.push(Node {
+            // Creating branching point - the rest of the line with all its branches becomes the
+            // main continuation
+            let continuation = Node {
                 line: self.line.split_off(hm),
-                branches: vec![],
+                branches: std::mem::take(&mut self.branches),
                 outcome: self.outcome,
-            });
+            };
+            self.branches.push(continuation);
 
             self.branches.push(Node {
                 line: vec![Mov {
                     mov,
-                    no: self.line[0].no.next(),
+                    no,
                     movinfo,
                     posinfo,
                 }],
@@ -208,9 +247,15 @@ impl<'a> Node<'a> {
     }
 }
 
+/// Tags always written, in the order required by the PGN standard. The `Result` tag is also part
+/// of the roster, but it is derived from the game outcome.
+const ROSTER: [&str; 6] = ["Event", "Site", "Date", "Round", "White", "Black"];
+
 /// [Knowledge] preprocessed for `PGN` storage
 #[derive(Debug)]
 pub struct Pgn<'a> {
+    /// Game tags
+    tags: &'a [(String, String)],
     /// Starting position
     rootinfo: &'a PosInfo,
     /// Starting node
@@ -223,10 +268,11 @@ impl<'a> Pgn<'a> {
     /// The main line would always end up first, and will never be empty. The result would be empty
     /// if and only if all the variations are empty
     ///
-    /// The multi-game PGNs are not supported, and variations not strting on the same position
-    /// as the mainline would be ingnored.
+    /// Variations not strting on the same position as the mainline would be ingnored - every game
+    /// of the multi-game PGN is a separate `Knowledge`.
     ///
-    /// The mainline can be extended by later variation if it is its prefix.
+    /// The mainline can be extended by later variation if it is its prefix. The empty mainline (a
+    /// reviewed position) is replaced by the first non-empty variation.
     fn order_variations(knowledge: &Knowledge) -> Vec<&Variation> {
         let main = &knowledge.variations[knowledge.main];
         let main = match main.moves.is_empty() {
@@ -240,20 +286,19 @@ impl<'a> Pgn<'a> {
         let Some(main) = main else { return vec![] };
         let root = main.positions[0];
 
-        let mut variations: Vec<_> = knowledge
-            .variations
-            .iter()
-            .filter(|variation| variation.positions[0] == root)
-            .collect();
-        variations.swap(0, knowledge.main);
-
-        variations
+        let others = knowledge.variations.iter().filter(|variation| {
+            !std::ptr::eq(*variation, main)
+                && !variation.moves.is_empty()
+                && variation.positions[0] == root
+        });
+        std::iter::once(main).chain(others).collect()
     }
 
     /// Prepares PGN form the Knowledge
     pub fn new(knowledge: &'a Knowledge) -> Self {
         let variations = Self::order_variations(knowledge);
         let mut pgn = Self {
+            tags: knowledge.tags(),
             rootinfo: knowledge.root(),
             line: Node {
                 line: vec![],
@@ -309,8 +354,18 @@ impl<'a> Pgn<'a> {
         pgn
     }
 
-    async fn write_result<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
-        let result = match self.line.outcome {
+    /// Tag value
+    fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(tag, _)| tag == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Game result. The main line outcome, or the original game result if the game did not end
+    /// on the board (eg. by resignation).
+    fn result(&self) -> &str {
+        match self.line.outcome {
             Some(Outcome::Draw) => "1/2-1/2",
             Some(Outcome::Decisive {
                 winner: Color::White,
@@ -318,42 +373,61 @@ impl<'a> Pgn<'a> {
             Some(Outcome::Decisive {
                 winner: Color::Black,
             }) => "0-1",
-            None => "*",
-        };
+            None => match self.tag("Result") {
+                Some(result @ ("1-0" | "0-1" | "1/2-1/2")) => result,
+                _ => "*",
+            },
+        }
+    }
+
+    async fn write_result<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(self.result().as_bytes()).await?;
+        Ok(())
+    }
 
-        writer.write_all(result.as_bytes()).await?;
+    async fn write_tag<W: AsyncWrite + Unpin>(
+        writer: &mut W,
+        name: &str,
+        value: &str,
+    ) -> Result<()> {
+        let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+
+        writer.write_all(b"[").await?;
+        writer.write_all(name.as_bytes()).await?;
+        writer.write_all(b" \"").await?;
+        writer.write_all(value.as_bytes()).await?;
+        writer.write_all(b"\"]\n").await?;
         Ok(())
     }
 
     async fn write_tags<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
-        let date = Local::now();
-
-        writer.write_all(b"[Event \"?\"]\n").await?;
-        writer.write_all(b"[Event \"?\"]\n").await?;
-        writer.write_all(b"[Site \"?\"]\n").await?;
-        writer.write_all(b"[Date \"").await?;
-        writer
-            .write_all(date.format("%Y.%m.%d").to_string().as_bytes())
-            .await?;
-        writer.write_all(b"\"]\n").await?;
-        writer.write_all(b"[Round \"?\"]\n").await?;
-        writer.write_all(b"[White \"?\"]\n").await?;
-        writer.write_all(b"[Black \"?\"]\n").await?;
-        writer.write_all(b"[Result \"").await?;
-        self.write_result(writer).await?;
-        writer.write_all(b"\"]\n").await?;
+        let date = Local::now().format("%Y.%m.%d").to_string();
+
+        for name in ROSTER {
+            let value = match (self.tag(name), name) {
+                (Some(value), _) => value,
+                (None, "Date") => &date,
+                (None, _) => "?",
+            };
+            Self::write_tag(writer, name, value).await?;
+        }
+        Self::write_tag(writer, "Result", self.result()).await?;
+
+        // Remaining tags are copied as they are, except the ones describing the starting position
+        // which are always recalculated
+        for (name, value) in self.tags {
+            if ROSTER.contains(&name.as_str())
+                || ["Result", "SetUp", "FEN"].contains(&name.as_str())
+            {
+                continue;
+            }
+            Self::write_tag(writer, name, value).await?;
+        }
 
         if *self.rootinfo.position() != Chess::new() {
-            writer.write_all(b"[SetUp \"1\"]").await?;
-            writer.write_all(b"[FEN \"").await?;
-            writer
-                .write_all(
-                    Fen::from_position(self.rootinfo.position().clone(), EnPassantMode::Always)
-                        .to_string()
-                        .as_bytes(),
-                )
-                .await?;
-            writer.write_all(b"\"]\n").await?;
+            let fen = Fen::from_position(self.rootinfo.position().clone(), EnPassantMode::Always);
+            Self::write_tag(writer, "SetUp", "1").await?;
+            Self::write_tag(writer, "FEN", &fen.to_string()).await?;
         }
 
         Ok(())
@@ -362,41 +436,40 @@ impl<'a> Pgn<'a> {
     #[instrument(skip_all)]
     async fn write_moves<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
         debug!("Storing PGN");
-        self.line.write_line(writer).await?;
 
-        if self.line.branches.is_empty() {
-            // Flat PGN
-            return Ok(());
-        }
+        // Alternatives has to be written right after the move they replace, before the main line
+        // continues. The stack holds what is left to write in reversed order.
+        let mut stack = vec![Step::Node(&self.line, 0)];
 
-        let mut stack: Vec<_> = vec![(&self.line, 0)];
+        while let Some(step) = stack.pop() {
+            let (node, from) = match step {
+                Step::Move(mov) => {
+                    mov.write(writer).await?;
+                    continue;
+                }
+                Step::Text(text) => {
+                    writer.write_all(text).await?;
+                    continue;
+                }
+                Step::Node(node, from) => (node, from),
+            };
 
-        while let Some((line, branchidx)) = stack.last_mut() {
-            if *branchidx >= line.branches.len() {
-                // Visited all the branches
-                stack.pop();
-                writer.write_all(b")").await?;
-                continue;
+            for mov in &node.line[from..] {
+                mov.write(writer).await?;
             }
 
-            if *branchidx > 0 {
-                // Opening new variation (0-th branch is mainline continuation)
-                writer.write_all(b"(").await?;
-            }
+            // The 0-th branch is the main line continuation
+            let Some((main, alternatives)) = node.branches.split_first() else {
+                continue;
+            };
 
-            let branch = &line.branches[*branchidx];
-            branch.write_line(writer).await?;
-
-            *branchidx += 1;
-            match branch.branches.is_empty() {
-                // Flat branch, immediately close the variation. Note that `branchidx` is already
-                // incremented
-                true if *branchidx > 1 => writer.write_all(b")").await?,
-                // Branching, add variation to the stack
-                false => stack.push((branch, 0)),
-                // Flat branch, main line - don't need to close
-                _ => (),
+            stack.push(Step::Node(main, 1));
+            for alternative in alternatives.iter().rev() {
+                stack.push(Step::Text(b") "));
+                stack.push(Step::Node(alternative, 0));
+                stack.push(Step::Text(b"("));
             }
+            stack.push(Step::Move(&main.line[0]));
         }
 
         Ok(())
@@ -411,3 +484,34 @@ impl<'a> Pgn<'a> {
         Ok(())
     }
 }
+
+/// Writer storing games one after another in a single PGN stream. Every game is flushed as soon as
+/// it is written, so the output is usable even if the process is interrupted.
+pub struct PgnWriter<W> {
+    writer: W,
+    /// Games written so far
+    games: usize,
+}
+
+impl<W: AsyncWrite + Unpin> PgnWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer, games: 0 }
+    }
+
+    /// Appends the game to the output
+    #[instrument(skip_all, fields(game = self.games), err)]
+    pub async fn write(&mut self, knowledge: &Knowledge) -> Result<()> {
+        knowledge.pgn().write_pgn(&mut self.writer).await?;
+        self.writer.write_all(b"\n\n").await?;
+        self.writer.flush().await?;
+
+        self.games += 1;
+        debug!(games = self.games, "Game stored");
+        Ok(())
+    }
+
+    /// Number of games written so far
+    pub fn games(&self) -> usize {
+        self.games
+    }
+}
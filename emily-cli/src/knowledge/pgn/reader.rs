@@ -0,0 +1,392 @@
+//! Streaming reader of multi-game PGN files
+
+use color_eyre::eyre::{eyre, OptionExt};
+use color_eyre::Report;
+use pgn_reader::{BufferedReader, RawHeader, SanPlus, Skip, Visitor};
+use shakmaty::fen::Fen;
+use shakmaty::{CastlingMode, Chess};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, Lines};
+use tracing::{debug, instrument, trace, warn};
+
+use crate::adapters::debug::{DFenExt, MovExt};
+use crate::knowledge::Knowledge;
+use crate::Result;
+
+/// Game termination markers, ending the movetext
+const RESULTS: [&str; 4] = ["1-0", "0-1", "1/2-1/2", "*"];
+
+/// Reads games from the PGN stream one by one, never keeping more than a single game in memory.
+///
+/// The stream is split into games on the text level (a game ends with its result token, or with a
+/// tag line following the movetext), and every game is then parsed separately. Thanks to that a
+/// malformed game doesn't prevent reading the following ones.
+pub struct PgnReader<R> {
+    lines: Lines<R>,
+    /// The first line of the next game, consumed while looking for the end of the previous one
+    pending: Option<String>,
+    /// Games read so far (including malformed ones)
+    games: usize,
+}
+
+impl<R: AsyncBufRead + Unpin> PgnReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            lines: reader.lines(),
+            pending: None,
+            games: 0,
+        }
+    }
+
+    /// Reads the raw text of the next game
+    async fn next_chunk(&mut self) -> Result<Option<String>> {
+        let mut game = String::new();
+        // Tags are allowed only before the movetext, so the tag after the movetext starts the next
+        // game, as does anything after the result. Comments have to be tracked, as they can
+        // contain anything. Braces in the tag values do not open comments.
+        let mut movetext = false;
+        let mut comment = false;
+
+        let mut line = self.pending.take();
+        if line.is_none() {
+            line = self.lines.next_line().await?;
+        }
+
+        while let Some(l) = line {
+            let trimmed = l.trim_start();
+
+            if !comment && trimmed.starts_with('[') && movetext {
+                self.pending = Some(l);
+                break;
+            }
+
+            let tags = !comment && trimmed.starts_with(['[', '%']);
+            if !comment && !tags && !trimmed.is_empty() {
+                movetext = true;
+            }
+
+            let mut end = None;
+            let mut token = 0;
+            for (i, c) in l.char_indices() {
+                match c {
+                    '{' if !tags => comment = true,
+                    '}' if !tags => {
+                        comment = false;
+                        token = i + 1;
+                    }
+                    ';' if !comment => break,
+                    c if !comment && !tags && c.is_whitespace() => {
+                        if RESULTS.contains(&&l[token..i]) {
+                            end = Some(i);
+                            break;
+                        }
+                        token = i + c.len_utf8();
+                    }
+                    _ => (),
+                }
+            }
+            if end.is_none() && !comment && !tags && RESULTS.contains(&l[token..].trim_end()) {
+                end = Some(l.len());
+            }
+
+            if let Some(end) = end {
+                game.push_str(&l[..end]);
+                game.push('\n');
+                let rest = l[end..].trim();
+                if !rest.is_empty() {
+                    self.pending = Some(rest.to_owned());
+                }
+                break;
+            }
+
+            game.push_str(&l);
+            game.push('\n');
+            line = self.lines.next_line().await?;
+        }
+
+        match game.trim().is_empty() {
+            true => Ok(None),
+            false => Ok(Some(game)),
+        }
+    }
+
+    /// Reads the next game. Returns `None` when the stream is exhausted. The game parsing error
+    /// is reported as the inner `Result`, and the reader can be still used to read further games.
+    #[instrument(skip(self), fields(game = self.games), err)]
+    pub async fn next_game(&mut self) -> Result<Option<Result<Knowledge>>> {
+        let Some(game) = self.next_chunk().await? else {
+            trace!("No more games");
+            return Ok(None);
+        };
+        self.games += 1;
+
+        let mut visitor = GameVisitor::default();
+        let game = BufferedReader::new_cursor(game.as_bytes())
+            .read_game(&mut visitor)?
+            .ok_or_eyre("Empty game");
+
+        Ok(Some(game.and_then(|game| game)))
+    }
+
+    /// Skips the next game without parsing it. Returns `false` if the stream is exhausted.
+    pub async fn skip_game(&mut self) -> Result<bool> {
+        let skipped = self.next_chunk().await?.is_some();
+        self.games += skipped as usize;
+        Ok(skipped)
+    }
+
+    /// Number of games read so far
+    pub fn games(&self) -> usize {
+        self.games
+    }
+}
+
+/// Builds the `Knowledge` out of the single game.
+#[derive(Default)]
+struct GameVisitor {
+    /// Tags read so far
+    tags: Vec<(String, String)>,
+    /// Knowledge being build, available after the headers are processed
+    knowledge: Option<Knowledge>,
+    /// The variation and the halfmove for the next move, for every nested variation level
+    stack: Vec<(usize, usize)>,
+    /// First error met. After error the rest of the game is ignored.
+    error: Option<Report>,
+}
+
+impl GameVisitor {
+    fn root(&self) -> Result<Chess> {
+        let fen = self
+            .tags
+            .iter()
+            .find(|(tag, _)| tag == "FEN")
+            .map(|(_, fen)| fen);
+
+        match fen {
+            Some(fen) => {
+                let fen: Fen = fen.parse()?;
+                Ok(fen.into_position(CastlingMode::Standard)?)
+            }
+            None => Ok(Chess::default()),
+        }
+    }
+
+    fn play(&mut self, san: SanPlus) -> Result<()> {
+        let knowledge = self.knowledge.as_mut().ok_or_eyre("Move before headers")?;
+        let (vidx, hm) = self.stack.last_mut().ok_or_eyre("Move outside of line")?;
+
+        let (_, position) = knowledge.variation_hm(*vidx, *hm);
+        let mov = san.san.to_move(position.position())?;
+        trace!(mov = ?mov.d_mov(), "Move read");
+
+        let (idx, _, _) = knowledge.add_move(*vidx, *hm, mov)?;
+        *vidx = idx;
+        *hm += 1;
+
+        Ok(())
+    }
+}
+
+impl Visitor for GameVisitor {
+    type Result = Result<Knowledge>;
+
+    fn header(&mut self, key: &[u8], value: RawHeader<'_>) {
+        let key = String::from_utf8_lossy(key).into_owned();
+        let value = value.decode_utf8_lossy().into_owned();
+        self.tags.push((key, value));
+    }
+
+    fn end_headers(&mut self) -> Skip {
+        match self.root() {
+            Ok(root) => {
+                debug!(root = ?root.d_fen(), "Game read");
+                let mut knowledge = Knowledge::new(root);
+                for (name, value) in self.tags.drain(..) {
+                    knowledge.set_tag(name, value);
+                }
+
+                self.knowledge = Some(knowledge);
+                self.stack = vec![(0, 0)];
+                Skip(false)
+            }
+            Err(err) => {
+                self.error = Some(err);
+                Skip(true)
+            }
+        }
+    }
+
+    fn san(&mut self, san: SanPlus) {
+        if self.error.is_some() {
+            return;
+        }
+
+        if let Err(err) = self.play(san) {
+            self.error = Some(err);
+        }
+    }
+
+    fn begin_variation(&mut self) -> Skip {
+        // Variation is an alternative to the last move played on the current level. Note that
+        // `end_variation` is called even for skipped variations, so the stack is always extended.
+        let (vidx, hm) = self.stack.last().copied().unwrap_or_default();
+        match hm.checked_sub(1) {
+            Some(hm) if self.error.is_none() => {
+                self.stack.push((vidx, hm));
+                Skip(false)
+            }
+            _ => {
+                warn!("Variation without preceding move ignored");
+                self.stack.push((vidx, hm));
+                Skip(true)
+            }
+        }
+    }
+
+    fn end_variation(&mut self) {
+        self.stack.pop();
+    }
+
+    fn end_game(&mut self) -> Self::Result {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+
+        self.knowledge
+            .take()
+            .ok_or_else(|| eyre!("Game without headers"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::san::San;
+    use shakmaty::Position;
+
+    use super::*;
+    use crate::knowledge::PgnWriter;
+
+    async fn read_all(pgn: &str) -> Vec<Result<Knowledge>> {
+        let mut reader = PgnReader::new(pgn.as_bytes());
+        let mut games = vec![];
+        while let Some(game) = reader.next_game().await.unwrap() {
+            games.push(game);
+        }
+        assert_eq!(reader.games(), games.len());
+        games
+    }
+
+    fn mainline(knowledge: &Knowledge) -> Vec<String> {
+        let (main, variation) = knowledge.mainline();
+        (0..variation.moves().len())
+            .map(|hm| {
+                let (_, info) = knowledge.variation_hm(main, hm);
+                San::from_move(info.position(), &variation.moves()[hm]).to_string()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn multiple_games_with_tags() {
+        let pgn = "[Event \"A\"]\n\n1. e4 e5 1-0\n\n[Event \"B\"]\n\n1. d4 { [not a tag] } d5 *\n";
+        let games = read_all(pgn).await;
+
+        assert_eq!(games.len(), 2);
+        let a = games[0].as_ref().unwrap();
+        let b = games[1].as_ref().unwrap();
+        assert!(a.tags().contains(&("Event".into(), "A".into())));
+        assert_eq!(mainline(a), ["e4", "e5"]);
+        assert_eq!(mainline(b), ["d4", "d5"]);
+    }
+
+    #[tokio::test]
+    async fn games_split_on_results() {
+        let pgn = "1. e4 e5 1-0\n1. d4 d5 0-1 1. c4 { 1-0 } c5 1/2-1/2\n1. Nf3 *";
+        let games = read_all(pgn).await;
+
+        let lines: Vec<_> = games
+            .iter()
+            .map(|g| mainline(g.as_ref().unwrap()))
+            .collect();
+        let expected = [
+            vec!["e4", "e5"],
+            vec!["d4", "d5"],
+            vec!["c4", "c5"],
+            vec!["Nf3"],
+        ];
+        assert_eq!(lines, expected);
+    }
+
+    #[tokio::test]
+    async fn braces_in_tags() {
+        let pgn = "[Annotator \"{x\"]\n\n1. e4 e5 1-0\n\n[Event \"B\"]\n\n1. d4 *\n";
+        let games = read_all(pgn).await;
+
+        assert_eq!(games.len(), 2);
+        let a = games[0].as_ref().unwrap();
+        assert!(a.tags().contains(&("Annotator".into(), "{x".into())));
+        assert_eq!(mainline(a), ["e4", "e5"]);
+        assert_eq!(mainline(games[1].as_ref().unwrap()), ["d4"]);
+    }
+
+    #[tokio::test]
+    async fn malformed_game_skipped() {
+        let pgn = "[Event \"A\"]\n\n1. e4 e5 2. Ke3 *\n\n[Event \"B\"]\n\n1. d4 *\n";
+        let games = read_all(pgn).await;
+
+        assert_eq!(games.len(), 2);
+        assert!(games[0].is_err());
+        assert_eq!(mainline(games[1].as_ref().unwrap()), ["d4"]);
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let pgn = "[Event \"A\"]\n[FEN \"4k3/8/8/8/8/8/4P3/4K3 w - - 0 1\"]\n\n\
+                   1. e4 (1. e3 Kd7) Ke7 2. Kd2 1-0\n\n1. d4 d5 *\n";
+        let games = read_all(pgn).await;
+
+        let mut written = vec![];
+        let mut writer = PgnWriter::new(&mut written);
+        for game in &games {
+            writer.write(game.as_ref().unwrap()).await.unwrap();
+        }
+        assert_eq!(writer.games(), 2);
+
+        let read = read_all(std::str::from_utf8(&written).unwrap()).await;
+        assert_eq!(read.len(), 2);
+        for (game, read) in games.iter().zip(&read) {
+            let (game, read) = (game.as_ref().unwrap(), read.as_ref().unwrap());
+            assert_eq!(mainline(game), mainline(read));
+            assert_eq!(game.positions().len(), read.positions().len());
+            assert_eq!(
+                game.root().position().board(),
+                read.root().position().board()
+            );
+        }
+        assert_eq!(mainline(read[0].as_ref().unwrap()), ["e4", "Ke7", "Kd2"]);
+    }
+
+    #[tokio::test]
+    async fn variation_of_empty_mainline_written() {
+        let mut knowledge = Knowledge::new(Chess::default());
+        knowledge.set_tag("Result", "0-1");
+        let mov = San::from_ascii(b"e4")
+            .unwrap()
+            .to_move(knowledge.root().position())
+            .unwrap();
+        let (idx, _, _) = knowledge.branch_move(0, 0, mov.clone()).unwrap();
+        assert_ne!(idx, 0);
+        assert_eq!(knowledge.branched(0, 0, &mov), Some(idx));
+        assert!(knowledge.mainline().1.moves().is_empty());
+
+        let mut written = vec![];
+        PgnWriter::new(&mut written)
+            .write(&knowledge)
+            .await
+            .unwrap();
+        let written = String::from_utf8(written).unwrap();
+        assert!(written.contains("[Result \"0-1\"]"));
+
+        let read = read_all(&written).await;
+        assert_eq!(mainline(read[0].as_ref().unwrap()), ["e4"]);
+    }
+}
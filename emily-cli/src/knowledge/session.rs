@@ -0,0 +1,249 @@
+//! Conversion of the `Knowledge` to the analysis file format. Positions are stored as FENs and
+//! moves as UCI moves, the position index and propagated evaluations are rebuilt when loaded.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use color_eyre::eyre::{ensure, Context};
+use emily_analysis::{Analysis, Format};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use shakmaty::fen::Fen;
+use shakmaty::uci::UciMove;
+use shakmaty::{CastlingMode, Chess, EnPassantMode, Move, Outcome, Position};
+use tracing::{debug, instrument};
+
+use super::{Knowledge, MoveInfo, PosInfo, Variation};
+use crate::uci::Score;
+use crate::Result;
+
+impl From<Score> for emily_analysis::Score {
+    fn from(score: Score) -> Self {
+        match score {
+            Score::Cp(cp) => Self::Cp(cp),
+            Score::Mate(mate) => Self::Mate(mate),
+        }
+    }
+}
+
+impl From<emily_analysis::Score> for Score {
+    fn from(score: emily_analysis::Score) -> Self {
+        match score {
+            emily_analysis::Score::Cp(cp) => Self::Cp(cp),
+            emily_analysis::Score::Mate(mate) => Self::Mate(mate),
+        }
+    }
+}
+
+/// Line in UCI notation
+fn uci_line(line: &[Move]) -> Vec<String> {
+    line.iter()
+        .map(|mov| UciMove::from_standard(mov).to_string())
+        .collect()
+}
+
+/// Parses the line in UCI notation played from `pos`
+fn parse_line(mut pos: Chess, line: &[String]) -> Result<Vec<Move>> {
+    line.iter()
+        .map(|mov| {
+            let mov = mov.parse::<UciMove>()?.to_move(&pos)?;
+            pos.play_unchecked(&mov);
+            Ok(mov)
+        })
+        .collect()
+}
+
+impl From<&Knowledge> for Analysis {
+    fn from(knowledge: &Knowledge) -> Self {
+        let positions = knowledge
+            .positions
+            .iter()
+            .map(|position| emily_analysis::Position {
+                fen: Fen::from_position(position.pos.clone(), EnPassantMode::Always).to_string(),
+                eval: position.eval.map(Into::into),
+                best: position
+                    .best
+                    .as_ref()
+                    .map(|mov| UciMove::from_standard(mov).to_string()),
+                depth: position.depth,
+                pv: position.pv.as_deref().map(uci_line),
+                provisional: position.provisional,
+                researched: position.researched,
+                threat: position.threat.as_deref().map(uci_line),
+                moves: position
+                    .moves
+                    .keys()
+                    .map(|mov| {
+                        let mov = UciMove::from_standard(mov).to_string();
+                        (mov, emily_analysis::MoveInfo)
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        let variations = knowledge
+            .variations
+            .iter()
+            .map(|variation| emily_analysis::Variation {
+                moves: variation
+                    .moves
+                    .iter()
+                    .map(|mov| UciMove::from_standard(mov).to_string())
+                    .collect(),
+                positions: variation.positions.clone(),
+                outcome: variation.outcome.map(|outcome| outcome.to_string()),
+            })
+            .collect();
+
+        Self {
+            positions,
+            variations,
+            main: knowledge.main,
+            tags: knowledge.tags.clone(),
+        }
+    }
+}
+
+impl TryFrom<Analysis> for Knowledge {
+    type Error = color_eyre::Report;
+
+    fn try_from(data: Analysis) -> Result<Self> {
+        let positions = data
+            .positions
+            .into_iter()
+            .map(|position| {
+                let fen: Fen = position.fen.parse()?;
+                let pos: Chess = fen.into_position(CastlingMode::Standard)?;
+                let mut info = PosInfo::new(pos);
+                info.eval = position.eval.map(Into::into);
+                info.depth = position.depth;
+
+                if let Some(best) = position.best {
+                    let best: UciMove = best.parse()?;
+                    info.best = Some(best.to_move(&info.pos)?);
+                }
+
+                info.provisional = position.provisional;
+                info.researched = position.researched;
+                if let Some(pv) = position.pv {
+                    info.pv = Some(parse_line(info.pos.clone(), &pv)?);
+                }
+                if let Some(threat) = position.threat {
+                    info.threat = Some(parse_line(info.pos.clone().swap_turn()?, &threat)?);
+                }
+
+                for mov in position.moves.into_keys() {
+                    let mov: UciMove = mov.parse()?;
+                    let mov = mov.to_move(&info.pos)?;
+                    info.moves.insert(mov, MoveInfo);
+                }
+
+                Ok(info)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let index: HashMap<_, _> = positions
+            .iter()
+            .enumerate()
+            .map(|(idx, position)| (position.pos.clone(), idx))
+            .collect();
+        ensure!(index.len() == positions.len(), "Duplicated position");
+
+        let variations = data
+            .variations
+            .into_iter()
+            .map(|variation| {
+                ensure!(
+                    variation.positions.len() == variation.moves.len() + 1,
+                    "Variation positions not matching moves"
+                );
+                ensure!(
+                    variation.positions.iter().all(|pos| *pos < positions.len()),
+                    "Invalid position index"
+                );
+
+                let moves = variation
+                    .moves
+                    .iter()
+                    .zip(&variation.positions)
+                    .map(|(mov, pos)| {
+                        let mov: UciMove = mov.parse()?;
+                        Ok(mov.to_move(&positions[*pos].pos)?)
+                    })
+                    .collect::<Result<_>>()?;
+
+                let outcome = variation
+                    .outcome
+                    .map(|outcome| outcome.parse::<Outcome>())
+                    .transpose()?;
+
+                Ok(Variation {
+                    moves,
+                    positions: variation.positions,
+                    outcome,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        ensure!(data.main < variations.len(), "Invalid main line index");
+
+        let mut knowledge = Self {
+            positions,
+            index,
+            variations,
+            main: data.main,
+            tags: data.tags,
+        };
+        knowledge.propagate();
+
+        Ok(knowledge)
+    }
+}
+
+impl Serialize for Knowledge {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        Analysis::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Knowledge {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        Analysis::deserialize(deserializer)?
+            .try_into()
+            .map_err(|err| D::Error::custom(format!("{err:#}")))
+    }
+}
+
+impl Knowledge {
+    /// Serializes the whole analysis as the analysis file in the given format
+    #[instrument(skip(self), err)]
+    pub fn to_bytes(&self, format: Format) -> Result<Vec<u8>> {
+        let data = Analysis::from(self).to_bytes(format)?;
+        debug!(size = data.len(), "Knowledge serialized");
+        Ok(data)
+    }
+
+    /// Restores the analysis serialized with `to_bytes`. The format is detected automatically.
+    #[instrument(skip(data), fields(size = data.len()), err)]
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        Analysis::from_bytes(data)?.try_into()
+    }
+
+    /// Stores the analysis in the file. The format is chosen basing on the file extension.
+    #[instrument(skip(self), err)]
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let data = self.to_bytes(Format::from_path(path))?;
+        tokio::fs::write(path, data)
+            .await
+            .wrap_err("While writing analysis file")
+    }
+
+    /// Loads the analysis stored with `save`
+    #[instrument(err)]
+    pub async fn load(path: &Path) -> Result<Self> {
+        let data = tokio::fs::read(path)
+            .await
+            .wrap_err("While reading analysis file")?;
+        Self::from_bytes(&data)
+    }
+}
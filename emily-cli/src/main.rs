@@ -1,6 +1,6 @@
 use std::path::{Path, PathBuf};
 
-use color_eyre::Result;
+use color_eyre::{eyre::WrapErr, Result};
 use structopt::StructOpt;
 use tracing::{debug, error, warn};
 
@@ -9,9 +9,19 @@ use config::Config;
 use self::config::Logging;
 
 mod adapters;
+mod analyse;
+mod arena;
+mod bench;
+mod clock;
 mod config;
 mod knowledge;
+mod perft;
+mod play;
+mod proxy;
 mod rev;
+mod serve;
+mod shutdown;
+mod suite;
 mod uci;
 
 #[derive(Debug, StructOpt)]
@@ -31,8 +41,30 @@ struct Opt {
 
 #[derive(Debug, StructOpt)]
 enum Command {
+    // Interactive infinite analysis of a position
+    Analyse(analyse::Analyse),
+    // Engine benchmark
+    Bench(bench::Bench),
+    // EPD test suite run
+    Epd(suite::Suite),
+    // Match between the engines
+    #[structopt(name = "match")]
+    Match(arena::Match),
+    // Move generation validation
+    Perft(perft::Perft),
+    // Game against the engine
+    Play(play::Play),
     // Position analysis and review
     Rev(rev::Rev),
+    // Parallel review of all games in PGN database
+    RevBatch(rev::RevBatch),
+    // Local HTTP/JSON API server
+    Serve(serve::Serve),
+    // Tournament between the engines
+    Tournament(arena::Tournament),
+    // UCI engine proxying the configured engine
+    #[structopt(name = "uci")]
+    Uci(proxy::Proxy),
 }
 
 impl Command {
@@ -40,26 +72,41 @@ impl Command {
         use Command::*;
 
         match self {
+            Analyse(analyse) => analyse.run(config).await,
+            Bench(bench) => bench.run(config).await,
+            Epd(suite) => suite.run(config).await,
+            Match(arena) => arena.run(config).await,
+            Perft(perft) => perft.run(config).await,
+            Play(play) => play.run(config).await,
             Rev(rev) => rev.run(config).await,
+            RevBatch(rev) => rev.run(config).await,
+            Serve(serve) => serve.run(config).await,
+            Tournament(tournament) => tournament.run(config).await,
+            Uci(proxy) => proxy.run(config).await,
         }
     }
 }
 
-async fn read_config(path: &Path) -> Config {
+/// Reads the config file, defaults are used if it cannot be read or parsed. The re-search without
+/// limits is an error.
+async fn read_config(path: &Path) -> Result<Config> {
     let config = match tokio::fs::read_to_string(path).await {
         Err(err) => {
             warn!(?err, ?path, "Error while reading config, using defaults");
-            return Config::default();
+            return Ok(Config::default());
         }
         Ok(config) => config,
     };
 
     match toml::from_str(&config) {
+        Err(err) if err.message() == config::RESEARCH_UNLIMITED => {
+            Err(err).wrap_err_with(|| format!("While parsing config {path:?}"))
+        }
         Err(err) => {
             error!(?err, ?path, "Error parsing config, using defaults");
-            Config::default()
+            Ok(Config::default())
         }
-        Ok(config) => config,
+        Ok(config) => Ok(config),
     }
 }
 
@@ -69,7 +116,8 @@ fn setup_tracing(config: &Logging, opt: &Opt) {
     use tracing_subscriber::fmt;
     use tracing_subscriber::prelude::*;
 
-    let fmt_layer = fmt::layer().pretty();
+    // Stdout is reserved for the command output (the protocol in the UCI mode)
+    let fmt_layer = fmt::layer().pretty().with_writer(std::io::stderr);
     let filter_layer = EnvFilter::try_from_default_env()
         .or_else(|_| EnvFilter::try_new("info"))
         .unwrap();
@@ -94,11 +142,11 @@ async fn main() -> Result<()> {
     let opt = Opt::from_args();
     debug!(?opt, "Emily CLI started");
 
-    let config = read_config(&opt.config).await;
+    color_eyre::install()?;
+    let config = read_config(&opt.config).await?;
     debug!(?config, "Emily config loaded");
 
     setup_tracing(&config.logging, &opt);
-    color_eyre::install()?;
 
     opt.cmd.run(config).await
 }
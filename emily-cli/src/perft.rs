@@ -0,0 +1,214 @@
+//! Move generation validation - counting the leaf nodes of the legal moves tree
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{ensure, Context, OptionExt};
+use color_eyre::Result;
+use shakmaty::fen::Fen;
+use shakmaty::uci::UciMove;
+use shakmaty::{CastlingMode, Chess, EnPassantMode, Position};
+use structopt::StructOpt;
+use tracing::{info, instrument};
+
+use crate::rev::parse_chess;
+use crate::uci;
+use crate::Config;
+
+/// Leaf nodes after every root move, by the UCI notation
+fn divide(position: &Chess, depth: u8) -> BTreeMap<String, u64> {
+    position
+        .legal_moves()
+        .into_iter()
+        .map(|mov| {
+            let mut after = position.clone();
+            after.play_unchecked(&mov);
+            let nodes = shakmaty::perft(&after, depth as u32 - 1);
+            (UciMove::from_standard(&mov).to_string(), nodes)
+        })
+        .collect()
+}
+
+/// FEN of the position for the report
+fn fen(position: &Chess) -> Fen {
+    Fen::from_position(position.clone(), EnPassantMode::Legal)
+}
+
+/// Parses the FEN, or the position part of the EPD line
+fn parse_position(line: &str) -> Result<Chess> {
+    let fields: Vec<_> = line.split_whitespace().collect();
+    // Halfmove clock and fullmove number are optional
+    let len = fields
+        .iter()
+        .skip(4)
+        .take(2)
+        .take_while(|field| field.parse::<u32>().is_ok())
+        .count();
+    let fen: Fen = fields[..fields.len().min(4 + len)]
+        .join(" ")
+        .parse()
+        .wrap_err("Invalid FEN")?;
+    Ok(fen.into_position(CastlingMode::Standard)?)
+}
+
+/// Perft of the positions - leaf nodes of the legal moves tree to the depth, divided by the root
+/// moves. With `--engine` the engine perft (`go perft`) is compared against it.
+#[derive(Debug, StructOpt)]
+pub struct Perft {
+    /// Depth of the tree
+    #[structopt(short, long)]
+    depth: u8,
+    /// Position, the starting one by default
+    #[structopt(short, long, parse(try_from_str = parse_chess))]
+    fen: Option<Chess>,
+    /// File with the positions, one FEN (or EPD) per line
+    #[structopt(short, long, conflicts_with = "fen")]
+    input: Option<PathBuf>,
+    /// Compares the results with the engine perft. The main engine is used if no name is given.
+    #[structopt(short, long)]
+    engine: Option<Option<String>>,
+}
+
+impl Perft {
+    /// Positions to check
+    async fn positions(&self) -> Result<Vec<Chess>> {
+        let Some(input) = &self.input else {
+            return Ok(vec![self.fen.clone().unwrap_or_default()]);
+        };
+
+        let positions = tokio::fs::read_to_string(input).await?;
+        positions
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| parse_position(line).wrap_err_with(|| format!("Position {line}")))
+            .collect()
+    }
+
+    /// Prints the divide of the position, with the engine's one side by side. Returns `false` on
+    /// any difference.
+    fn report(
+        position: &Chess,
+        divide: &BTreeMap<String, u64>,
+        engine: Option<&uci::Perft>,
+        verbose: bool,
+    ) -> bool {
+        let nodes: u64 = divide.values().sum();
+        let Some(engine) = engine else {
+            if verbose {
+                for (mov, nodes) in divide {
+                    println!("{mov}: {nodes}");
+                }
+            }
+            println!("{}: {nodes}", fen(position));
+            return true;
+        };
+
+        let engine_divide: BTreeMap<_, _> = engine
+            .moves
+            .iter()
+            .map(|(mov, nodes)| (mov.to_string(), *nodes))
+            .collect();
+        let matches = engine_divide == *divide && engine.nodes == nodes;
+
+        // Divide is always shown on differences, so the faulty move can be found
+        if verbose || !matches {
+            let moves: BTreeSet<_> = divide.keys().chain(engine_divide.keys()).collect();
+            for mov in moves {
+                let ours = divide.get(mov);
+                let theirs = engine_divide.get(mov);
+                let marker = if ours == theirs { "" } else { "  <- differs" };
+                let fmt = |nodes: Option<&u64>| nodes.map_or("-".to_owned(), u64::to_string);
+                println!("{mov}: {} {}{marker}", fmt(ours), fmt(theirs));
+            }
+        }
+
+        let status = if matches { "ok" } else { "MISMATCH" };
+        println!(
+            "{}: {nodes}, engine {}, {status}",
+            fen(position),
+            engine.nodes
+        );
+        matches
+    }
+
+    #[instrument(skip(self, config), err)]
+    pub async fn run(self, config: Config) -> Result<()> {
+        info!(?self, "Perft");
+        ensure!(self.depth > 0, "Depth has to be positive");
+        let positions = self.positions().await?;
+
+        let mut engine = match &self.engine {
+            None => None,
+            Some(name) => {
+                let engine = match name {
+                    Some(name) => config.engine_named(name),
+                    None => config.engine.as_ref(),
+                };
+                let engine = engine.ok_or_eyre("No engine configuration")?;
+                Some(uci::Engine::run(engine.clone()).await?)
+            }
+        };
+
+        let verbose = positions.len() == 1;
+        let mut mismatches = 0;
+        let (mut total, mut elapsed) = (0, Duration::ZERO);
+
+        for position in &positions {
+            let started = Instant::now();
+            let divide = divide(position, self.depth);
+            elapsed += started.elapsed();
+            total += divide.values().sum::<u64>();
+
+            let engine_perft = match &mut engine {
+                Some(engine) => Some(engine.perft(position.clone(), self.depth).await?),
+                None => None,
+            };
+            if !Self::report(position, &divide, engine_perft.as_ref(), verbose) {
+                mismatches += 1;
+            }
+        }
+
+        if let Some(engine) = engine {
+            engine.quit().await?;
+        }
+
+        println!(
+            "Nodes {total}, time {:.2}s, {:.0} nps",
+            elapsed.as_secs_f64(),
+            total as f64 / elapsed.as_secs_f64()
+        );
+
+        ensure!(
+            mismatches == 0,
+            "Engine perft differs in {mismatches} positions"
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn startpos_divided() {
+        let divided = divide(&Chess::default(), 3);
+        assert_eq!(divided.len(), 20);
+        assert_eq!(divided["e2e4"], 600);
+        assert_eq!(divided["g1f3"], 440);
+        assert_eq!(divided.values().sum::<u64>(), 8902);
+    }
+
+    #[test]
+    fn epd_position_parsed() {
+        let kiwipete = "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq -";
+        let epd = parse_position(&format!("{kiwipete} D1 48; D2 2039;")).unwrap();
+        let full = parse_position(&format!("{kiwipete} 0 1")).unwrap();
+        assert_eq!(fen(&epd), fen(&full));
+        assert_eq!(shakmaty::perft(&epd, 2), 2039);
+
+        assert!(parse_position("8/8/8/8 w - -").is_err());
+    }
+}
@@ -0,0 +1,337 @@
+//! Playing a game against the engine in the terminal
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{Context, OptionExt};
+use color_eyre::Result;
+use shakmaty::san::SanPlus;
+use shakmaty::{Chess, Color, Move, Position};
+use structopt::StructOpt;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, BufReader, Lines, Stdin};
+use tokio::time::sleep;
+use tracing::{debug, info, instrument, trace, warn};
+
+use crate::adapters::board::{use_colors, BoardExt};
+use crate::adapters::debug::MovExt;
+use crate::clock::{fmt_clock, parse_time_control, Clocks, TimeControl};
+use crate::knowledge::{Knowledge, PgnWriter};
+use crate::rev::{parse_chess, review_game};
+use crate::shutdown::Shutdown;
+use crate::uci::{self, Clock};
+use crate::Config;
+
+/// Decision of the side to move
+#[derive(Debug)]
+enum Decision {
+    Move(Move),
+    Resign,
+    /// Clock run out while deciding
+    Flag,
+    /// Game interrupted
+    Abandon,
+}
+
+/// Game played against the engine. The engine plays on the clock, its strength can be limited
+/// by the `UCI_Elo` option (if the engine supports it) or by the nodes searched per move. Moves
+/// are entered in SAN, `resign` resigns the game.
+#[derive(Debug, StructOpt)]
+pub struct Play {
+    /// Output PGN file the game is stored in
+    #[structopt(short, long)]
+    output: PathBuf,
+    /// Starting position
+    #[structopt(short, long, parse(try_from_str = parse_chess))]
+    fen: Option<Chess>,
+    /// Side played by the user (`white` or `black`)
+    #[structopt(short, long, default_value = "white")]
+    color: Color,
+    /// Time control as minutes and increment seconds
+    #[structopt(short, long, default_value = "5+3", parse(try_from_str = parse_time_control))]
+    time_control: TimeControl,
+    /// Engine strength limit (`UCI_LimitStrength` and `UCI_Elo` options)
+    #[structopt(long)]
+    elo: Option<u16>,
+    /// Engine nodes limit per move
+    #[structopt(long)]
+    nodes: Option<u64>,
+    /// Reviews the finished game, the output contains the reviewed game
+    #[structopt(long)]
+    review: bool,
+    /// Draws the board with the piece letters instead of the chess symbols
+    #[structopt(long)]
+    ascii: bool,
+}
+
+impl Play {
+    /// Prints the position and clocks before the move
+    fn show(&self, position: &Chess, last: Option<&Move>, clocks: &Clocks) {
+        let board = position
+            .d_board()
+            .last_move(last)
+            .ascii(self.ascii)
+            .colors(use_colors(&std::io::stdout()));
+
+        println!();
+        println!("{board}");
+        println!(
+            "White {}, Black {}",
+            fmt_clock(clocks.remaining(Color::White)),
+            fmt_clock(clocks.remaining(Color::Black))
+        );
+    }
+
+    /// Reads the user move. Invalid moves are reported and the user is asked again.
+    #[instrument(skip_all, err)]
+    async fn user_move(
+        position: &Chess,
+        input: &mut Lines<BufReader<Stdin>>,
+        clock: Duration,
+        shutdown: &mut Shutdown,
+    ) -> Result<Decision> {
+        let started = Instant::now();
+
+        loop {
+            let Some(remaining) = clock.checked_sub(started.elapsed()) else {
+                return Ok(Decision::Flag);
+            };
+
+            print!("Your move: ");
+            std::io::stdout().flush()?;
+
+            let line = tokio::select! {
+                line = input.next_line() => line?,
+                _ = sleep(remaining) => return Ok(Decision::Flag),
+                _ = shutdown.wait() => return Ok(Decision::Abandon),
+            };
+
+            let Some(line) = line else {
+                debug!("Input closed");
+                return Ok(Decision::Abandon);
+            };
+
+            let line = line.trim();
+            match line {
+                "" => continue,
+                "resign" => return Ok(Decision::Resign),
+                _ => (),
+            }
+
+            let mov = line
+                .parse::<SanPlus>()
+                .map_err(|err| err.to_string())
+                .and_then(|san| san.san.to_move(position).map_err(|err| err.to_string()));
+
+            match mov {
+                Ok(mov) => return Ok(Decision::Move(mov)),
+                Err(err) => println!("Invalid move {line}: {err}"),
+            }
+        }
+    }
+
+    /// Lets the engine find its move
+    #[instrument(skip_all, err)]
+    async fn engine_move(
+        &self,
+        engine: &mut uci::Engine,
+        knowledge: &Knowledge,
+        clock: Clock,
+        shutdown: &mut Shutdown,
+    ) -> Result<Decision> {
+        let (main, variation) = knowledge.mainline();
+        let (_, position) = knowledge.variation_hm(main, variation.moves().len());
+        let remaining = match position.position().turn() {
+            Color::White => clock.wtime,
+            Color::Black => clock.btime,
+        };
+
+        let mut stream = engine
+            .play(
+                knowledge.root().position().clone(),
+                variation.moves(),
+                Some(clock),
+                self.nodes,
+            )
+            .await?;
+        let timeout = sleep(remaining);
+        tokio::pin!(timeout);
+
+        loop {
+            tokio::select! {
+                info = stream.info() => if info?.is_none() {
+                    break;
+                },
+                _ = &mut timeout => {
+                    stream.stop_wait().await?;
+                    return Ok(Decision::Flag);
+                },
+                _ = shutdown.wait() => {
+                    stream.stop_wait().await?;
+                    return Ok(Decision::Abandon);
+                },
+            }
+        }
+
+        let best = stream.best().await?;
+        let mov = best.to_move(position.position()).wrap_err_with(|| {
+            let board = position.position().d_board().colors(false);
+            format!("Engine played illegal move {best} in {board:?}")
+        })?;
+        Ok(Decision::Move(mov))
+    }
+
+    /// Sets up the game tags
+    fn tags(&self, knowledge: &mut Knowledge, engine: &str) {
+        let user = std::env::var("USER").unwrap_or_else(|_| "Player".to_owned());
+        let (white, black) = match self.color {
+            Color::White => (user.as_str(), engine),
+            Color::Black => (engine, user.as_str()),
+        };
+
+        knowledge
+            .set_tag("Event", "Emily game")
+            .set_tag("White", white)
+            .set_tag("Black", black)
+            .set_tag("TimeControl", self.time_control.tag());
+
+        if let Some(elo) = self.elo {
+            let tag = match self.color {
+                Color::White => "BlackElo",
+                Color::Black => "WhiteElo",
+            };
+            knowledge.set_tag(tag, elo.to_string());
+        }
+    }
+
+    /// Plays the game. Returns `false` if the game was abandoned.
+    #[instrument(skip_all, err)]
+    async fn play(
+        &self,
+        engine: &mut uci::Engine,
+        knowledge: &mut Knowledge,
+        shutdown: &mut Shutdown,
+    ) -> Result<bool> {
+        let mut clocks = Clocks::new(self.time_control);
+        let mut input = BufReader::new(tokio::io::stdin()).lines();
+
+        loop {
+            let (main, variation) = knowledge.mainline();
+            let hm = variation.moves().len();
+            let (_, position) = knowledge.variation_hm(main, hm);
+            let position = position.position().clone();
+            self.show(&position, variation.moves().last(), &clocks);
+
+            if let Some(outcome) = variation.outcome() {
+                println!("Game over, {outcome}");
+                knowledge.set_tag("Termination", "normal");
+                return Ok(true);
+            }
+
+            let turn = position.turn();
+            let started = Instant::now();
+            let decision = match turn == self.color {
+                true => {
+                    Self::user_move(&position, &mut input, clocks.remaining(turn), shutdown).await?
+                }
+                false => {
+                    self.engine_move(engine, knowledge, clocks.uci(), shutdown)
+                        .await?
+                }
+            };
+            debug!(?decision, "Move decided");
+
+            // Move played after the clock run out is too late
+            let decision = match decision {
+                Decision::Move(_) if !clocks.punch(turn, started.elapsed()) => Decision::Flag,
+                decision => decision,
+            };
+
+            let side = match turn {
+                Color::White => "White",
+                Color::Black => "Black",
+            };
+
+            let winner = match decision {
+                Decision::Move(mov) => {
+                    if turn != self.color {
+                        let san = SanPlus::from_move(position, &mov);
+                        println!("Engine plays {san}");
+                    }
+
+                    trace!(mov = ?mov.d_mov(), "Move played");
+                    knowledge.add_move(main, hm, mov)?;
+                    continue;
+                }
+                Decision::Resign => {
+                    println!("{side} resigns");
+                    knowledge.set_tag("Termination", "normal");
+                    !turn
+                }
+                Decision::Flag => {
+                    println!("{side} lost on time");
+                    knowledge.set_tag("Termination", "time forfeit");
+                    !turn
+                }
+                Decision::Abandon => {
+                    warn!("Game abandoned");
+                    knowledge.set_tag("Termination", "abandoned");
+                    return Ok(false);
+                }
+            };
+
+            let result = match winner {
+                Color::White => "1-0",
+                Color::Black => "0-1",
+            };
+            knowledge.set_tag("Result", result);
+            return Ok(true);
+        }
+    }
+
+    #[instrument(skip(self, config), err)]
+    pub async fn run(self, config: Config) -> Result<()> {
+        info!(?self, "Playing game");
+        let mut shutdown = Shutdown::listen()?;
+
+        let engine_config = config
+            .engine
+            .clone()
+            .ok_or_eyre("No engine configuration")?;
+        let name = engine_config.name.clone();
+        let mut engine = uci::Engine::run(engine_config).await?;
+
+        if let Some(elo) = self.elo {
+            engine.set_option("UCI_LimitStrength", "true").await?;
+            engine.set_option("UCI_Elo", &elo.to_string()).await?;
+        }
+        engine.new_game().await?;
+
+        let mut knowledge = Knowledge::new(self.fen.clone().unwrap_or_default());
+        self.tags(&mut knowledge, &name);
+
+        // The moves played so far are stored even if the game failed
+        let played = self.play(&mut engine, &mut knowledge, &mut shutdown).await;
+        if played.is_err() {
+            knowledge.set_tag("Termination", "abandoned");
+        }
+
+        let mut output = PgnWriter::new(File::create(&self.output).await?);
+        output.write(&knowledge).await?;
+        info!(file = ?self.output, "Game stored");
+
+        let finished = played?;
+        engine.quit().await?;
+
+        if self.review && finished {
+            info!("Reviewing game");
+            let knowledge = review_game(knowledge, &config, &shutdown).await?;
+            let mut output = PgnWriter::new(File::create(&self.output).await?);
+            output.write(&knowledge).await?;
+            info!(file = ?self.output, "Reviewed game stored");
+        }
+
+        Ok(())
+    }
+}
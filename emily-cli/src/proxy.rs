@@ -0,0 +1,371 @@
+//! UCI proxy - emily acting as an engine, answering the searches from the opening book, the
+//! analysis cache or the tablebase engine before forwarding them to the configured engine
+
+use std::collections::{HashSet, VecDeque};
+
+use color_eyre::eyre::{eyre, OptionExt};
+use color_eyre::Result;
+use derivative::Derivative;
+use shakmaty::fen::Fen;
+use shakmaty::uci::UciMove;
+use shakmaty::{CastlingMode, Chess, Move, Position};
+use structopt::StructOpt;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines, Stdin, Stdout};
+use tracing::{debug, info, instrument, trace, warn};
+
+use self::book::Book;
+use self::cache::Cache;
+use crate::adapters::debug::{DFenExt, LineExt};
+use crate::uci::{self, Command, Info, Limits, Msg};
+use crate::Config;
+
+mod book;
+mod cache;
+
+/// GUI side of the protocol - commands read from stdin, messages written to stdout
+struct Gui {
+    input: Lines<BufReader<Stdin>>,
+    output: Stdout,
+    /// Commands received during the search, handled after it
+    deferred: VecDeque<Command>,
+}
+
+impl Gui {
+    fn new() -> Self {
+        Self {
+            input: BufReader::new(tokio::io::stdin()).lines(),
+            output: tokio::io::stdout(),
+            deferred: VecDeque::new(),
+        }
+    }
+
+    /// Next GUI command, the deferred ones first. `None` when the input is closed.
+    async fn recv(&mut self) -> Result<Option<Command>> {
+        match self.deferred.pop_front() {
+            Some(command) => Ok(Some(command)),
+            None => self.read().await,
+        }
+    }
+
+    /// Next command read from the input. Unknown commands are skipped.
+    async fn read(&mut self) -> Result<Option<Command>> {
+        while let Some(line) = self.input.next_line().await? {
+            trace!(line, "GUI command");
+            match Command::parse(&line) {
+                Ok(Some(command)) => return Ok(Some(command)),
+                Ok(None) => debug!(line, "Unknown command, ignoring"),
+                Err(err) => warn!(%err, line, "Invalid command, ignoring"),
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn send(&mut self, msg: Msg) -> Result<()> {
+        trace!(%msg, "GUI message");
+        self.output.write_all(format!("{msg}\n").as_bytes()).await?;
+        self.output.flush().await?;
+        Ok(())
+    }
+}
+
+/// Position set up by the GUI
+#[derive(Derivative, Default)]
+#[derivative(Debug)]
+struct Setup {
+    #[derivative(Debug(format_with = "DFenExt::fmt"))]
+    root: Chess,
+    #[derivative(Debug(format_with = "LineExt::fmt"))]
+    moves: Vec<Move>,
+    /// Position after the moves
+    #[derivative(Debug = "ignore")]
+    position: Chess,
+    /// Some position repeated on the way, so the evaluation has to consider the repetition draws
+    repeated: bool,
+}
+
+impl Setup {
+    fn new(fen: Option<Fen>, line: Vec<UciMove>) -> Result<Self> {
+        let root: Chess = match fen {
+            Some(fen) => fen.into_position(CastlingMode::Standard)?,
+            None => Chess::default(),
+        };
+
+        let mut position = root.clone();
+        let mut moves = Vec::with_capacity(line.len());
+        let mut seen = HashSet::from([Cache::key(&position)]);
+        let mut repeated = false;
+        for mov in line {
+            let mov = mov
+                .to_move(&position)
+                .map_err(|_| eyre!("Illegal move {mov}"))?;
+            position.play_unchecked(&mov);
+            moves.push(mov);
+            repeated |= !seen.insert(Cache::key(&position));
+        }
+
+        Ok(Self {
+            root,
+            moves,
+            position,
+            repeated,
+        })
+    }
+}
+
+/// Searches answered without the engine
+enum Answer {
+    Book(Move),
+    Cache(Info, Move),
+}
+
+/// UCI engine on stdin/stdout. Searches are answered from the `uci` configuration sources when
+/// possible - the opening book, the cached evaluations deep enough and the tablebase engine in
+/// the endgames - and forwarded to the engine otherwise. Infinite, pondering and mate searches are
+/// always forwarded.
+#[derive(Debug, StructOpt)]
+pub struct Proxy {
+    /// Engine name, the main `engine` by default
+    #[structopt(short, long)]
+    engine: Option<String>,
+}
+
+impl Proxy {
+    /// Answer for the search from the book or the cache. Infinite, pondering and mate searches
+    /// are never answered, and the answer has to be one of the `searchmoves` if they are given.
+    /// Cached evaluations are not aware of the repetitions, so they are not used after one.
+    fn answer(
+        setup: &Setup,
+        limits: &Limits,
+        book: Option<&Book>,
+        cache: &Cache,
+        min_depth: u8,
+    ) -> Option<Answer> {
+        if limits.is_infinite() || limits.ponder || limits.mate.is_some() {
+            return None;
+        }
+
+        let allowed = |mov: &Move| {
+            limits.search_moves.is_empty()
+                || limits.search_moves.contains(&UciMove::from_standard(mov))
+        };
+
+        if let Some(mov) = book
+            .and_then(|book| book.best(&setup.position))
+            .filter(allowed)
+        {
+            return Some(Answer::Book(mov));
+        }
+
+        if setup.repeated {
+            return None;
+        }
+
+        let entry = cache.get(&setup.position)?;
+        if entry.depth < min_depth.max(limits.depth.unwrap_or(0)) || !allowed(&entry.best) {
+            return None;
+        }
+
+        let info = Info {
+            multipv: 1,
+            score: entry.eval.pov(setup.position.turn()),
+            line: entry.pv.iter().map(UciMove::from_standard).collect(),
+            depth: entry.depth,
+            nodes: 0,
+            nps: None,
+            time: None,
+        };
+        Some(Answer::Cache(info, entry.best.clone()))
+    }
+
+    /// Forwards the search to the engine, passing the `stop` and `ponderhit` received meanwhile.
+    /// Other commands are deferred until the search finishes. Returns `false` if the GUI quit
+    /// during the search.
+    #[instrument(skip(engine, gui), err)]
+    async fn forward(
+        engine: &mut uci::Engine,
+        setup: &Setup,
+        limits: Limits,
+        gui: &mut Gui,
+    ) -> Result<bool> {
+        let mut stream = engine
+            .search(setup.root.clone(), &setup.moves, limits)
+            .await?;
+
+        loop {
+            tokio::select! {
+                info = stream.info() => match info? {
+                    Some(info) => gui.send(Msg::Info(info)).await?,
+                    None => break,
+                },
+                command = gui.read() => match command? {
+                    Some(Command::Stop) => stream.stop().await?,
+                    Some(Command::PonderHit) => stream.ponder_hit().await?,
+                    Some(Command::IsReady) => gui.send(Msg::ReadyOk).await?,
+                    Some(Command::Quit) | None => {
+                        stream.stop_wait().await?;
+                        return Ok(false);
+                    }
+                    Some(command) => gui.deferred.push_back(command),
+                },
+            }
+        }
+
+        let best = stream.best().await?;
+        gui.send(Msg::BestMove(best)).await?;
+        Ok(true)
+    }
+
+    #[instrument(skip(self, config), err)]
+    pub async fn run(self, config: Config) -> Result<()> {
+        info!(?self, "UCI proxy");
+
+        let engine_config = match &self.engine {
+            Some(name) => config.engine_named(name),
+            None => config.engine.as_ref(),
+        };
+        let engine_config = engine_config.ok_or_eyre("No engine configuration")?;
+        let book = match &config.uci.book {
+            Some(path) => Some(Book::load(path).await?),
+            None => None,
+        };
+        let cache = Cache::load(&config.uci.analysis).await?;
+
+        let mut engine = uci::Engine::run(engine_config.clone()).await?;
+        let mut tablebase = match &config.uci.tablebase {
+            Some(tablebase) => {
+                let tb_config = config
+                    .engine_named(&tablebase.engine)
+                    .ok_or_eyre("No tablebase engine configuration")?;
+                Some((tablebase.pieces, uci::Engine::run(tb_config.clone()).await?))
+            }
+            None => None,
+        };
+
+        let mut gui = Gui::new();
+        // No position to search after the GUI set up an invalid one
+        let mut setup = Some(Setup::default());
+
+        while let Some(command) = gui.recv().await? {
+            debug!(?command, "Command received");
+            match command {
+                // Options are the engine ones, they are passed to it
+                Command::Uci => {
+                    let name = format!("Emily ({})", engine_config.name);
+                    let author = engine.author().unwrap_or("Emily").to_owned();
+                    gui.send(Msg::Id {
+                        name: Some(name),
+                        author: Some(author),
+                    })
+                    .await?;
+                    for option in engine.options() {
+                        gui.send(Msg::Option(option.clone())).await?;
+                    }
+                    gui.send(Msg::UciOk).await?;
+                }
+                Command::Debug => (),
+                Command::IsReady => gui.send(Msg::ReadyOk).await?,
+                Command::SetOption(name, value) => engine.set_option(&name, &value).await?,
+                Command::NewGame => {
+                    engine.new_game().await?;
+                    if let Some((_, tablebase)) = &mut tablebase {
+                        tablebase.new_game().await?;
+                    }
+                }
+                Command::Position { fen, line } => {
+                    setup = Setup::new(fen, line)
+                        .inspect_err(|err| warn!(%err, "Invalid position"))
+                        .ok();
+                }
+                Command::Go(limits) => {
+                    // The search is answered with the null move, so the GUI doesn't wait for it
+                    let Some(setup) = &setup else {
+                        warn!("No valid position, refusing the search");
+                        gui.send(Msg::BestMove(UciMove::Null)).await?;
+                        continue;
+                    };
+                    let min_depth = config.uci.min_depth;
+                    match Self::answer(setup, &limits, book.as_ref(), &cache, min_depth) {
+                        Some(Answer::Book(mov)) => {
+                            debug!(?setup, "Book move");
+                            gui.send(Msg::BestMove(UciMove::from_standard(&mov)))
+                                .await?;
+                        }
+                        Some(Answer::Cache(info, mov)) => {
+                            debug!(?setup, ?info, "Cached evaluation");
+                            gui.send(Msg::Info(info)).await?;
+                            gui.send(Msg::BestMove(UciMove::from_standard(&mov)))
+                                .await?;
+                        }
+                        None => {
+                            let men = setup.position.board().occupied().count();
+                            let engine = match &mut tablebase {
+                                Some((pieces, tablebase)) if men <= *pieces as usize => {
+                                    debug!(men, "Searching with the tablebase engine");
+                                    tablebase
+                                }
+                                _ => &mut engine,
+                            };
+                            if !Self::forward(engine, setup, limits, &mut gui).await? {
+                                break;
+                            }
+                        }
+                    }
+                }
+                Command::Perft(depth) => {
+                    let Some(setup) = &setup else {
+                        warn!("No valid position, refusing the perft");
+                        continue;
+                    };
+                    let perft = engine.perft(setup.position.clone(), depth).await?;
+                    for (mov, nodes) in perft.moves {
+                        gui.send(Msg::PerftMove(mov, nodes)).await?;
+                    }
+                    gui.send(Msg::PerftNodes(perft.nodes)).await?;
+                }
+                // No search in progress
+                Command::Stop | Command::PonderHit => (),
+                Command::Quit => break,
+            }
+        }
+
+        engine.quit().await?;
+        if let Some((_, tablebase)) = tablebase {
+            tablebase.quit().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(fen: Option<&str>, line: &str) -> Result<Setup> {
+        let fen = fen.map(|fen| fen.parse()).transpose()?;
+        let line = line.split_whitespace().map(|mov| mov.parse().unwrap());
+        Setup::new(fen, line.collect())
+    }
+
+    #[test]
+    fn repetition_detected() {
+        let played = setup(None, "e2e4 e7e5 g1f3 b8c6").unwrap();
+        assert_eq!(played.moves.len(), 4);
+        assert!(!played.repeated);
+
+        let repeated = setup(None, "g1f3 g8f6 f3g1 f6g8 e2e4").unwrap();
+        assert!(repeated.repeated);
+        assert_eq!(
+            Cache::key(&repeated.position),
+            Cache::key(&setup(None, "e2e4").unwrap().position)
+        );
+
+        let fen = "4k3/8/8/8/8/8/8/4K3 w - - 0 1";
+        assert!(setup(Some(fen), "e1d1 e8d8 d1e1 d8e8").unwrap().repeated);
+    }
+
+    #[test]
+    fn illegal_move_rejected() {
+        assert!(setup(None, "e2e5").is_err());
+    }
+}
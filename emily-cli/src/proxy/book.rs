@@ -0,0 +1,85 @@
+//! Polyglot opening books
+
+use std::path::Path;
+
+use color_eyre::eyre::{ensure, Context};
+use color_eyre::Result;
+use shakmaty::zobrist::{Zobrist64, ZobristHash};
+use shakmaty::{Chess, EnPassantMode, Move, Position, Role, Square};
+use tracing::{debug, instrument};
+
+/// Book entry, the move is in the Polyglot encoding
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    key: u64,
+    mov: u16,
+    weight: u16,
+}
+
+/// Polyglot book, entries sorted by the position key
+#[derive(Debug)]
+pub struct Book {
+    entries: Vec<Entry>,
+}
+
+impl Book {
+    #[instrument(err)]
+    pub async fn load(path: &Path) -> Result<Self> {
+        let data = tokio::fs::read(path)
+            .await
+            .wrap_err("While reading opening book")?;
+        ensure!(data.len() % 16 == 0, "Invalid opening book size");
+
+        // Entry is big-endian key, move, weight and the learn data (ignored)
+        let mut entries: Vec<_> = data
+            .chunks_exact(16)
+            .map(|entry| Entry {
+                key: u64::from_be_bytes(entry[..8].try_into().unwrap()),
+                mov: u16::from_be_bytes([entry[8], entry[9]]),
+                weight: u16::from_be_bytes([entry[10], entry[11]]),
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.key);
+
+        debug!(entries = entries.len(), "Opening book loaded");
+        Ok(Self { entries })
+    }
+
+    /// Decodes the Polyglot move. Castling is encoded as the king capturing its rook.
+    fn decode(position: &Chess, mov: u16) -> Option<Move> {
+        let to = Square::new((mov & 0x3f) as u32);
+        let from = Square::new((mov >> 6 & 0x3f) as u32);
+        let promotion = match mov >> 12 & 0x7 {
+            1 => Some(Role::Knight),
+            2 => Some(Role::Bishop),
+            3 => Some(Role::Rook),
+            4 => Some(Role::Queen),
+            _ => None,
+        };
+
+        position
+            .legal_moves()
+            .into_iter()
+            .find(|candidate| match candidate {
+                Move::Castle { king, rook } => *king == from && *rook == to,
+                _ => {
+                    candidate.from() == Some(from)
+                        && candidate.to() == to
+                        && candidate.promotion() == promotion
+                }
+            })
+    }
+
+    /// The book move with the highest weight
+    pub fn best(&self, position: &Chess) -> Option<Move> {
+        let key = position.zobrist_hash::<Zobrist64>(EnPassantMode::Legal).0;
+        let start = self.entries.partition_point(|entry| entry.key < key);
+
+        self.entries[start..]
+            .iter()
+            .take_while(|entry| entry.key == key)
+            .filter(|entry| entry.weight > 0)
+            .max_by_key(|entry| entry.weight)
+            .and_then(|entry| Self::decode(position, entry.mov))
+    }
+}
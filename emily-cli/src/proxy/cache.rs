@@ -0,0 +1,91 @@
+//! Evaluations cached from the analysis files
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use color_eyre::Result;
+use derivative::Derivative;
+use shakmaty::zobrist::{Zobrist64, ZobristHash};
+use shakmaty::{Chess, EnPassantMode, Move};
+use tracing::{debug, instrument};
+
+use crate::adapters::debug::{LineExt, MovExt};
+use crate::knowledge::Knowledge;
+use crate::uci::Score;
+
+/// Cached position evaluation
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct Entry {
+    #[derivative(Debug(format_with = "MovExt::fmt"))]
+    pub best: Move,
+    /// Evaluation from the white PoV
+    pub eval: Score,
+    pub depth: u8,
+    /// Principal variation, starting with the best move
+    #[derivative(Debug(format_with = "LineExt::fmt"))]
+    pub pv: Vec<Move>,
+}
+
+/// Final (not provisional) evaluations of the analysed positions, by the position hash
+#[derive(Debug, Default)]
+pub struct Cache {
+    entries: HashMap<u64, Entry>,
+}
+
+impl Cache {
+    /// Position key, not considering how the position was reached
+    pub fn key(position: &Chess) -> u64 {
+        position.zobrist_hash::<Zobrist64>(EnPassantMode::Legal).0
+    }
+
+    /// Loads the evaluations from all the analysis files, the deepest one is kept for every
+    /// position
+    #[instrument(err)]
+    pub async fn load(paths: &[PathBuf]) -> Result<Self> {
+        let mut cache = Self::default();
+
+        for path in paths {
+            let knowledge = Knowledge::load(path).await?;
+            for info in knowledge.positions() {
+                if info.is_provisional() {
+                    continue;
+                }
+
+                let (Some(best), Some(eval), Some(depth)) =
+                    (info.best(), info.eval(), info.depth())
+                else {
+                    continue;
+                };
+
+                let key = Self::key(info.position());
+                if cache
+                    .entries
+                    .get(&key)
+                    .is_some_and(|entry| entry.depth >= depth)
+                {
+                    continue;
+                }
+
+                let pv = match info.pv() {
+                    Some(pv) if pv.first() == Some(best) => pv.to_vec(),
+                    _ => vec![best.clone()],
+                };
+                let entry = Entry {
+                    best: best.clone(),
+                    eval,
+                    depth,
+                    pv,
+                };
+                cache.entries.insert(key, entry);
+            }
+        }
+
+        debug!(positions = cache.entries.len(), "Evaluation cache loaded");
+        Ok(cache)
+    }
+
+    pub fn get(&self, position: &Chess) -> Option<&Entry> {
+        self.entries.get(&Self::key(position))
+    }
+}
@@ -1,72 +1,376 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use color_eyre::eyre::OptionExt;
+use color_eyre::eyre::{ensure, OptionExt};
 use shakmaty::fen::Fen;
 use shakmaty::{CastlingMode, Chess};
 use structopt::StructOpt;
 use tokio::fs::File;
+use tokio::io::{AsyncBufRead, BufWriter};
 use tokio::spawn;
-use tracing::{error, info, instrument, trace};
+use tokio::task::JoinHandle;
+use tracing::{error, info, instrument, trace, warn};
 
+use crate::adapters::board::{use_colors, BoardExt};
 use crate::adapters::debug::DFenExt;
-use crate::knowledge::Knowledge;
-use crate::Config;
+use crate::knowledge::{Knowledge, PgnReader};
+use crate::shutdown::Shutdown;
+use crate::{config, Config};
 use color_eyre::Result;
 
+use self::checkpoint::{GameState, SessionFile, SessionOpt};
 use self::dispatcher::Dispatcher;
+use self::output::{open_input, open_output, output_len, Format, Output};
+use self::processor::{Priority, Scheduled};
 
+pub use self::batch::RevBatch;
+pub use self::event::Events;
+
+mod batch;
+mod checkpoint;
 mod dispatcher;
 mod engine;
+mod event;
+mod output;
 mod processor;
+mod threat;
 
-fn parse_chess(fen: &str) -> Result<Chess> {
+pub(crate) fn parse_chess(fen: &str) -> Result<Chess> {
     let fen: Fen = fen.parse()?;
     let fen: Chess = fen.into_position(CastlingMode::Standard)?;
     Ok(fen)
 }
 
+/// Starts writing the review events to `path` as JSON lines, if given. Writing finishes when all
+/// the `events` senders are dropped.
+async fn write_events(
+    path: Option<&Path>,
+    events: &Events,
+) -> Result<Option<JoinHandle<Result<()>>>> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+
+    let file = BufWriter::new(File::create(path).await?);
+    Ok(Some(spawn(event::write_lines(events.subscribe(), file))))
+}
+
+/// Reads the next game from the input, skipping games already reviewed or resumed from the
+/// session. Returns the game number and the game.
+async fn next_game<R: AsyncBufRead + Unpin>(
+    games: &mut PgnReader<R>,
+    session: Option<&SessionFile>,
+) -> Result<Option<(usize, Result<Knowledge>)>> {
+    loop {
+        let no = games.games() + 1;
+        let skip = match session {
+            Some(session) => session.is_finished(no).await || session.is_started(no).await,
+            None => false,
+        };
+
+        if !skip {
+            let game = games.next_game().await?;
+            return Ok(game.map(|game| (no, game)));
+        }
+
+        if !games.skip_game().await? {
+            return Ok(None);
+        }
+    }
+}
+
+/// Single game to review
+struct Game {
+    /// Game number in the input (starting with 1)
+    no: usize,
+    knowledge: Knowledge,
+    /// Positions left to analyse when the review is resumed
+    pending: Option<Vec<Scheduled>>,
+}
+
+impl Game {
+    fn new(no: usize, knowledge: Knowledge) -> Self {
+        Self {
+            no,
+            knowledge,
+            pending: None,
+        }
+    }
+
+    fn resumed(no: usize, state: GameState) -> Self {
+        Self {
+            no,
+            knowledge: state.knowledge,
+            pending: Some(state.pending),
+        }
+    }
+
+    /// Game reopened from the stored analysis. Only main line positions not evaluated yet (or
+    /// evaluated provisionally) are analysed.
+    fn reopened(knowledge: Knowledge) -> Self {
+        let (main, variation) = knowledge.mainline();
+        let pending = (0..=variation.moves().len())
+            .filter(|hm| {
+                let (_, position) = knowledge.variation_hm(main, *hm);
+                position.eval().is_none() || position.is_provisional()
+            })
+            .map(|hm| Scheduled::new(main, hm, Priority::main()))
+            .collect();
+
+        Self {
+            no: 1,
+            knowledge,
+            pending: Some(pending),
+        }
+    }
+
+    /// Reviews the game. Unless the review is resumed, every position on the game main line is
+    /// analysed.
+    ///
+    /// Returns `false` if the review is incomplete (eg. interrupted by the shutdown). The game is
+    /// then marked with the `[Review "Incomplete"]` tag, which is removed once the review
+    /// completes.
+    #[instrument(skip_all, fields(game = self.no), err)]
+    async fn review(
+        &mut self,
+        engines: &mut engine::Engines,
+        session: Option<&SessionFile>,
+        budget: &config::Budget,
+        shutdown: &Shutdown,
+        events: &Events,
+    ) -> Result<bool> {
+        let schedule = match self.pending.take() {
+            Some(pending) => pending,
+            None => {
+                let (main, variation) = self.knowledge.mainline();
+                (0..=variation.moves().len())
+                    .map(|hm| Scheduled::new(main, hm, Priority::main()))
+                    .collect()
+            }
+        };
+
+        let events = events.game(self.no);
+        // Searches in progress are stopped when the review time is over
+        let stop = shutdown.with_timeout(budget.max_time);
+        let mut dispatcher = Dispatcher::builder();
+        let provisional = dispatcher.provisional();
+        dispatcher.with(
+            engines
+                .main
+                .new_game(stop.clone(), events.clone(), provisional)
+                .await?,
+        );
+        if let Some(threats) = &mut engines.threats {
+            dispatcher.with(threats.new_threats_game(stop).await?);
+        }
+        dispatcher.budget(budget.clone());
+        dispatcher.events(events);
+        if let Some(session) = session {
+            dispatcher.checkpoint(session.interval(), session.checkpoint(self.no));
+        }
+
+        let dispatcher = dispatcher.build();
+        let complete = dispatcher.dispatch(&mut self.knowledge, &schedule).await?;
+        match complete {
+            true => self.knowledge.remove_tag("Review"),
+            false => {
+                warn!(game = self.no, "Review incomplete");
+                self.knowledge.set_tag("Review", "Incomplete")
+            }
+        };
+
+        self.summary();
+        Ok(complete)
+    }
+
+    /// Logs the final position of the game main line
+    fn summary(&self) {
+        let (main, variation) = self.knowledge.mainline();
+        let (_, position) = self.knowledge.variation_hm(main, variation.moves().len());
+        let board = position
+            .position()
+            .d_board()
+            .last_move(variation.moves().last())
+            .eval(position.eval())
+            .colors(use_colors(&std::io::stderr()));
+        info!(game = self.no, ?board, "Game reviewed");
+    }
+}
+
+/// Reviews a single game the way the `rev` command does, with a new engine instance. Returns the
+/// game with the review knowledge.
+pub async fn review_game(
+    knowledge: Knowledge,
+    config: &Config,
+    shutdown: &Shutdown,
+) -> Result<Knowledge> {
+    let engine = config
+        .engine
+        .clone()
+        .ok_or_eyre("No engine configuration")?;
+    review_with(knowledge, engine, &config.rev, shutdown, &Events::new()).await
+}
+
+/// Reviews a single game with a new instance of the `engine` and the `rev` configuration, emitting
+/// the progress to `events`. Returns the game with the review knowledge.
+#[instrument(skip_all, err)]
+pub async fn review_with(
+    knowledge: Knowledge,
+    engine: config::Engine,
+    rev: &config::Rev,
+    shutdown: &Shutdown,
+    events: &Events,
+) -> Result<Knowledge> {
+    let mut engines = engine::Engines::new(engine, rev).await?;
+
+    let mut game = Game::new(1, knowledge);
+    game.review(&mut engines, None, &rev.budget, shutdown, events)
+        .await?;
+
+    engines.quit().await?;
+    Ok(game.knowledge)
+}
+
 /// Game review parameters
 #[derive(Debug, StructOpt)]
 pub struct Rev {
-    /// Output PGN file
+    /// Output file, `-` for stdout
     #[structopt(short, long)]
     output: PathBuf,
+    /// Output format - `pgn` (annotated games) or `jsonl` (JSON line per analysed position with
+    /// the FEN, played move, evaluation, depth and PV)
+    #[structopt(long, default_value = "pgn")]
+    format: Format,
     /// Starting position
     #[structopt(short, long, parse(try_from_str = parse_chess))]
     fen: Option<Chess>,
+    /// Input PGN file (`-` for stdin). Every game in the file is reviewed, and all of them are
+    /// stored in the output.
+    #[structopt(short, long, conflicts_with = "fen")]
+    input: Option<PathBuf>,
+    /// Analysis file storing the complete review knowledge (JSON if the extension is `.json`,
+    /// binary otherwise). If the file exists, the stored analysis is reopened and continued, and
+    /// `fen` cannot be given.
+    #[structopt(long, conflicts_with = "input")]
+    analysis: Option<PathBuf>,
+    /// File the review progress events are written to, as JSON lines
+    #[structopt(long)]
+    events: Option<PathBuf>,
+    #[structopt(flatten)]
+    session: SessionOpt,
 }
 
 impl Rev {
     #[instrument(skip(self, config), err)]
     pub async fn run(self, config: Config) -> Result<()> {
         info!(?self, "Position review");
+        if let (Some(analysis), Some(_)) = (&self.analysis, &self.fen) {
+            ensure!(
+                !analysis.exists(),
+                "Analysis file {analysis:?} exists, its position is reviewed instead of --fen"
+            );
+        }
+
+        let shutdown = Shutdown::listen()?;
+        let events = Events::new();
+        let events_writer = write_events(self.events.as_deref(), &events).await?;
 
-        let mut engine = engine::Engine::new(
+        let mut engines = engine::Engines::new(
             config.engine.ok_or_eyre("No engine configuration")?,
             &config.rev,
         )
         .await?;
 
-        let root = self.fen.unwrap_or_default();
-        trace!(pos = ?root.d_fen(), "Analyzing position");
+        let session = self.session.open().await?;
+        let mut resumed = match &session {
+            Some(session) => session.games().await,
+            None => vec![],
+        };
 
-        let mut knowledge = Knowledge::new(root.clone());
+        let written = match (&session, self.session.resume()) {
+            (Some(session), true) => Some(session.written().await),
+            _ => None,
+        };
+        let output = open_output(&self.output, written).await?;
+        let mut output = Output::new(output, self.format);
+        // Single position review finished in the resumed session
+        let mut reviewed = match (&session, &self.input) {
+            (Some(session), None) => session.is_finished(1).await,
+            _ => false,
+        };
 
-        let mut dispatcher = Dispatcher::builder();
-        dispatcher.with(engine.new_game().await?);
-        let dispatcher = dispatcher.build();
-        dispatcher.dispatch(&mut knowledge, 0, 0).await?;
+        let mut games = match &self.input {
+            Some(input) => Some(open_input(input).await?),
+            None => None,
+        };
+
+        while !shutdown.is_requested() {
+            let mut game = match (resumed.pop(), &mut games) {
+                (Some((no, state)), _) => Game::resumed(no, state),
+                (None, Some(games)) => match next_game(games, session.as_ref()).await? {
+                    None => break,
+                    Some((no, Ok(knowledge))) => Game::new(no, knowledge),
+                    Some((no, Err(err))) => {
+                        error!(%err, game = no, "Invalid game, skipping");
+                        continue;
+                    }
+                },
+                // Single position review already finished
+                (None, None) if reviewed => break,
+                (None, None) => match &self.analysis {
+                    Some(analysis) if analysis.exists() => {
+                        Game::reopened(Knowledge::load(analysis).await?)
+                    }
+                    _ => {
+                        let root = self.fen.clone().unwrap_or_default();
+                        trace!(pos = ?root.d_fen(), "Analyzing position");
+                        Game::new(1, Knowledge::new(root))
+                    }
+                },
+            };
+
+            info!(game = game.no, "Reviewing game");
+            let complete = game
+                .review(
+                    &mut engines,
+                    session.as_ref(),
+                    &config.rev.budget,
+                    &shutdown,
+                    &events,
+                )
+                .await?;
+            reviewed = true;
+            if let Some(analysis) = &self.analysis {
+                game.knowledge.save(analysis).await?;
+            }
+
+            // Incomplete review is stored marked as such. The interrupted one also stays in the
+            // session to be resumed, its output is not recorded so it is replaced when resuming.
+            output.write(game.no, &game.knowledge).await?;
+            if let (Some(session), true) = (&session, complete || !shutdown.is_requested()) {
+                let written = output_len(&self.output).await?;
+                session.finish(game.no, written).await?;
+            }
+        }
 
         spawn(async move {
-            if let Err(err) = engine.quit().await {
+            if let Err(err) = engines.quit().await {
                 error!(?err, "Engine teardown failed");
             }
         });
 
-        let mut output = File::create(&self.output).await?;
-        knowledge.pgn().write_pgn(&mut output).await?;
+        match (session, shutdown.is_requested()) {
+            (Some(_), true) => warn!("Review interrupted, it can be continued with --resume"),
+            (Some(session), false) => session.remove().await?,
+            (None, true) => warn!("Review interrupted"),
+            (None, false) => (),
+        }
+
+        drop(events);
+        if let Some(events_writer) = events_writer {
+            events_writer.await??;
+        }
 
-        info!(file = ?self.output, "PGN stored");
+        info!(file = ?self.output, games = output.games(), "Games stored");
 
         Ok(())
     }
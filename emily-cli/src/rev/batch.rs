@@ -0,0 +1,302 @@
+//! Batch review of the multi-game PGN databases
+
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{bail, ensure, OptionExt};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use structopt::StructOpt;
+use tokio::fs::File;
+use tokio::io::BufReader;
+use tokio::spawn;
+use tracing::{debug, error, info, instrument, warn};
+
+use super::checkpoint::SessionOpt;
+use super::engine::Engines;
+use super::event::Events;
+use super::output::{is_stdio, open_output, output_len};
+use super::{next_game, write_events, Game};
+use crate::knowledge::{PgnReader, PgnWriter};
+use crate::shutdown::Shutdown;
+use crate::{Config, Result};
+
+/// Batch review parameters
+#[derive(Debug, StructOpt)]
+pub struct RevBatch {
+    /// Input PGN file, it is read twice (stdin is not supported)
+    #[structopt(short, long)]
+    input: PathBuf,
+    /// Output PGN file (`-` for stdout). Games are stored in the order their review finishes.
+    #[structopt(short, long)]
+    output: PathBuf,
+    /// Number of games reviewed in parallel. Every game is reviewed by its own engine instances.
+    #[structopt(short, long, default_value = "1")]
+    jobs: NonZeroUsize,
+    /// File the review progress events are written to, as JSON lines
+    #[structopt(long)]
+    events: Option<PathBuf>,
+    #[structopt(flatten)]
+    session: SessionOpt,
+}
+
+/// Batch progress tracking
+struct Progress {
+    /// Games in the database
+    total: usize,
+    /// Games reviewed before the session was resumed
+    skipped: usize,
+    /// Games reviewed successfully
+    done: usize,
+    /// Games failed to parse or review, including incomplete reviews
+    failed: usize,
+    /// Review start
+    started: Instant,
+}
+
+impl Progress {
+    fn new(total: usize, skipped: usize) -> Self {
+        Self {
+            total,
+            skipped,
+            done: 0,
+            failed: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// Estimated time to finish all the games
+    fn eta(&self) -> Option<Duration> {
+        let finished = self.done + self.failed;
+        let remaining = self.total.saturating_sub(finished + self.skipped);
+        let per_game = self.started.elapsed().checked_div(finished as u32)?;
+        Some(per_game * remaining as u32)
+    }
+
+    fn fmt_duration(duration: Duration) -> String {
+        let secs = duration.as_secs();
+        format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    }
+
+    /// Reports a finished game on the terminal
+    fn finished(&mut self, success: bool) {
+        match success {
+            true => self.done += 1,
+            false => self.failed += 1,
+        }
+
+        let eta = match self.eta() {
+            Some(eta) => Self::fmt_duration(eta),
+            None => "--:--:--".to_owned(),
+        };
+
+        // Progress is reported on the terminal directly so it is visible regardless of the
+        // logging configuration
+        let mut stderr = std::io::stderr().lock();
+        let _ = writeln!(
+            stderr,
+            "Reviewed {}/{} games ({} failed), elapsed {}, ETA {}",
+            self.skipped + self.done + self.failed,
+            self.total,
+            self.failed,
+            Self::fmt_duration(self.started.elapsed()),
+            eta,
+        );
+    }
+}
+
+impl RevBatch {
+    /// Counts games in the database for progress reporting. The input is read again for the
+    /// review, so it cannot be stdin.
+    async fn count_games(input: &Path) -> Result<usize> {
+        ensure!(
+            !is_stdio(input),
+            "Batch review input has to be a file, games read from stdin cannot be counted"
+        );
+        let mut games = PgnReader::new(BufReader::new(File::open(input).await?));
+        while games.skip_game().await? {}
+        Ok(games.games())
+    }
+
+    /// Gracefully stops the engines in the background
+    fn teardown(engines: Engines) {
+        spawn(async move {
+            if let Err(err) = engines.quit().await {
+                error!(?err, "Engine teardown failed");
+            }
+        });
+    }
+
+    #[instrument(skip(self, config), err)]
+    pub async fn run(self, config: Config) -> Result<()> {
+        info!(?self, "Batch review");
+        let shutdown = Shutdown::listen()?;
+        let events = Events::new();
+        let events_writer = write_events(self.events.as_deref(), &events).await?;
+
+        let engine_config = config.engine.ok_or_eyre("No engine configuration")?;
+        let rev_config = &config.rev;
+        let total = Self::count_games(&self.input).await?;
+
+        let session = self.session.open().await?;
+        let mut resumed = match &session {
+            Some(session) => session.games().await,
+            None => vec![],
+        };
+
+        let mut finished = 0;
+        for no in 1..=total {
+            if let Some(session) = &session {
+                finished += session.is_finished(no).await as usize;
+            }
+        }
+
+        let mut progress = Progress::new(total, finished);
+        info!(
+            games = total,
+            finished,
+            resumed = resumed.len(),
+            "Database loaded"
+        );
+
+        let mut games = PgnReader::new(BufReader::new(File::open(&self.input).await?));
+        let written = match (&session, self.session.resume()) {
+            (Some(session), true) => Some(session.written().await),
+            _ => None,
+        };
+        let output = open_output(&self.output, written).await?;
+        let mut output = PgnWriter::new(output);
+
+        let mut pool = Vec::with_capacity(self.jobs.get());
+        for _ in 0..self.jobs.get() {
+            pool.push(Engines::new(engine_config.clone(), rev_config).await?);
+        }
+
+        let mut reviews = FuturesUnordered::new();
+        let mut exhausted = false;
+        // Interrupted reviews kept in the session
+        let mut partial = vec![];
+
+        loop {
+            // Every idle engine takes the next game, starting with the resumed ones. After the
+            // shutdown request only the reviews in progress are finished.
+            while !shutdown.is_requested() && (!exhausted || !resumed.is_empty()) {
+                let Some(mut engine) = pool.pop() else {
+                    break;
+                };
+
+                let game = match resumed.pop() {
+                    Some((no, state)) => Game::resumed(no, state),
+                    None => match next_game(&mut games, session.as_ref()).await? {
+                        Some((no, Ok(knowledge))) => Game::new(no, knowledge),
+                        Some((no, Err(err))) => {
+                            error!(%err, game = no, "Invalid game, skipping");
+                            progress.finished(false);
+                            pool.push(engine);
+                            continue;
+                        }
+                        None => {
+                            exhausted = true;
+                            pool.push(engine);
+                            continue;
+                        }
+                    },
+                };
+
+                debug!(game = game.no, "Starting game review");
+                let session = session.clone();
+                let shutdown = shutdown.clone();
+                let events = events.clone();
+                reviews.push(async move {
+                    let mut game = game;
+                    let result = game
+                        .review(
+                            &mut engine,
+                            session.as_ref(),
+                            &rev_config.budget,
+                            &shutdown,
+                            &events,
+                        )
+                        .await;
+                    (engine, game, result)
+                });
+            }
+
+            let Some((engine, game, result)) = reviews.next().await else {
+                break;
+            };
+
+            let failed = match result {
+                Ok(complete) => {
+                    // Incomplete review is stored marked as such. The interrupted one also stays
+                    // in the session to be resumed, and is stored after all the finished games,
+                    // so its output is not recorded and it is replaced when resuming.
+                    let interrupted = !complete && shutdown.is_requested();
+                    match (&session, interrupted) {
+                        (Some(session), false) => {
+                            output.write(&game.knowledge).await?;
+                            let written = output_len(&self.output).await?;
+                            session.finish(game.no, written).await?;
+                        }
+                        (Some(_), true) => partial.push(game),
+                        (None, _) => output.write(&game.knowledge).await?,
+                    }
+                    progress.finished(complete);
+                    // Review stopped early on its own means the engine failed
+                    !complete && !interrupted
+                }
+                Err(err) => {
+                    error!(%err, game = game.no, "Game review failed");
+                    progress.finished(false);
+                    true
+                }
+            };
+
+            if !failed {
+                pool.push(engine);
+                continue;
+            }
+
+            // The engine state is unknown after the failure, it is replaced to not affect the
+            // following games
+            Self::teardown(engine);
+            match Engines::new(engine_config.clone(), rev_config).await {
+                Ok(engine) => pool.push(engine),
+                Err(err) => warn!(%err, "Cannot restart engine, continuing with less jobs"),
+            }
+
+            if pool.is_empty() && reviews.is_empty() && !exhausted {
+                bail!("No engines left to continue the review");
+            }
+        }
+
+        pool.into_iter().for_each(Self::teardown);
+
+        for game in partial {
+            output.write(&game.knowledge).await?;
+        }
+
+        match (session, shutdown.is_requested()) {
+            (Some(_), true) => warn!("Review interrupted, it can be continued with --resume"),
+            (Some(session), false) => session.remove().await?,
+            (None, true) => warn!("Review interrupted"),
+            (None, false) => (),
+        }
+
+        drop(events);
+        if let Some(events_writer) = events_writer {
+            events_writer.await??;
+        }
+
+        info!(
+            file = ?self.output,
+            reviewed = progress.done,
+            failed = progress.failed,
+            "Batch review finished"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+//! Review session checkpointing, allowing to resume interrupted reviews
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use color_eyre::eyre::Context;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+use tokio::sync::Mutex;
+use tracing::{debug, info, instrument};
+
+use super::processor::Scheduled;
+use crate::knowledge::Knowledge;
+use crate::Result;
+
+/// Entity storing the review progress
+#[async_trait]
+pub trait Checkpoint {
+    /// Stores the knowledge gathered so far with the positions still waiting for processing
+    async fn save(&mut self, knowledge: &Knowledge, pending: &[Scheduled]) -> Result<()>;
+}
+
+// Session checkpointing parameters. Not a doc comment, as structopt would use it as the about of
+// the subcommands flattening it.
+#[derive(Debug, StructOpt)]
+pub struct SessionOpt {
+    /// Session file the review progress is periodically stored to
+    #[structopt(long)]
+    session: Option<PathBuf>,
+    /// Continues the review stored in the session file
+    #[structopt(long, requires = "session")]
+    resume: bool,
+    /// Seconds between checkpoints
+    #[structopt(long, default_value = "60")]
+    checkpoint_interval: u64,
+}
+
+impl SessionOpt {
+    /// Opens the session file if configured. Existing session is loaded only when resuming.
+    pub async fn open(&self) -> Result<Option<SessionFile>> {
+        match (&self.session, self.resume) {
+            (None, _) => Ok(None),
+            (Some(path), true) => SessionFile::load(path.clone(), self.interval())
+                .await
+                .map(Some),
+            (Some(path), false) => Ok(Some(SessionFile::new(path.clone(), self.interval()))),
+        }
+    }
+
+    /// Should the previous session be resumed
+    pub fn resume(&self) -> bool {
+        self.resume
+    }
+
+    /// Time between checkpoints
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.checkpoint_interval)
+    }
+}
+
+/// State of a single game review
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GameState {
+    /// Knowledge gathered so far
+    pub knowledge: Knowledge,
+    /// Positions waiting for analysis
+    pub pending: Vec<Scheduled>,
+}
+
+/// Review session. Games are identified by their number in the input (starting with 1).
+#[derive(Serialize, Deserialize, Default)]
+struct Session {
+    /// Games already reviewed and stored in the output
+    finished: BTreeSet<usize>,
+    /// Games being reviewed
+    games: BTreeMap<usize, GameState>,
+    /// Length of the output file with the finished games stored. Anything written after that is
+    /// discarded when resuming, so no game is stored twice.
+    #[serde(default)]
+    written: u64,
+}
+
+/// Session shared by all the reviews in progress, stored in the file on every change
+#[derive(Clone)]
+pub struct SessionFile {
+    path: Arc<PathBuf>,
+    session: Arc<Mutex<Session>>,
+    /// Time between checkpoints
+    interval: Duration,
+}
+
+impl SessionFile {
+    /// Creates a new empty session
+    fn new(path: PathBuf, interval: Duration) -> Self {
+        Self {
+            path: Arc::new(path),
+            session: Default::default(),
+            interval,
+        }
+    }
+
+    /// Loads the session stored in the file
+    #[instrument(err)]
+    async fn load(path: PathBuf, interval: Duration) -> Result<Self> {
+        let data = tokio::fs::read(&path)
+            .await
+            .wrap_err("While reading session file")?;
+        let session: Session =
+            serde_json::from_slice(&data).wrap_err("While parsing session file")?;
+
+        info!(
+            finished = session.finished.len(),
+            in_progress = session.games.len(),
+            "Session loaded"
+        );
+
+        Ok(Self {
+            path: Arc::new(path),
+            session: Arc::new(Mutex::new(session)),
+            interval,
+        })
+    }
+
+    /// Stores the session in the file. The file is replaced atomically, so the interruption while
+    /// storing never corrupts the session.
+    async fn store(&self, session: &Session) -> Result<()> {
+        let data = serde_json::to_vec(session)?;
+        let tmp = self.path.with_extension("tmp");
+
+        tokio::fs::write(&tmp, data)
+            .await
+            .wrap_err("While writing session file")?;
+        tokio::fs::rename(&tmp, &*self.path)
+            .await
+            .wrap_err("While writing session file")?;
+
+        debug!(path = ?self.path, "Session stored");
+        Ok(())
+    }
+
+    /// Checks if the game was already reviewed
+    pub async fn is_finished(&self, game: usize) -> bool {
+        self.session.lock().await.finished.contains(&game)
+    }
+
+    /// Checks if the game review was started
+    pub async fn is_started(&self, game: usize) -> bool {
+        self.session.lock().await.games.contains_key(&game)
+    }
+
+    /// Time between checkpoints
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Length of the output file with all the finished games
+    pub async fn written(&self) -> u64 {
+        self.session.lock().await.written
+    }
+
+    /// Unfinished games to resume. They are kept in the session until updated, so the state is
+    /// not lost if interrupted again before the next checkpoint.
+    pub async fn games(&self) -> Vec<(usize, GameState)> {
+        let session = self.session.lock().await;
+        session
+            .games
+            .iter()
+            .map(|(game, state)| (*game, state.clone()))
+            .collect()
+    }
+
+    /// Updates the game state
+    pub async fn update(
+        &self,
+        game: usize,
+        knowledge: &Knowledge,
+        pending: &[Scheduled],
+    ) -> Result<()> {
+        let session = &mut self.session.lock().await;
+        let state = GameState {
+            knowledge: knowledge.clone(),
+            pending: pending.to_vec(),
+        };
+        session.games.insert(game, state);
+        self.store(session).await
+    }
+
+    /// Marks the game as reviewed and stored in the output, `written` is the output length after
+    /// the game is stored (`None` if not known)
+    pub async fn finish(&self, game: usize, written: Option<u64>) -> Result<()> {
+        let session = &mut self.session.lock().await;
+        session.games.remove(&game);
+        session.finished.insert(game);
+        if let Some(written) = written {
+            session.written = written;
+        }
+        self.store(session).await
+    }
+
+    /// Removes the session file after the whole review is complete
+    pub async fn remove(self) -> Result<()> {
+        tokio::fs::remove_file(&*self.path)
+            .await
+            .or_else(|err| match err.kind() {
+                std::io::ErrorKind::NotFound => Ok(()),
+                _ => Err(err),
+            })
+            .wrap_err("While removing session file")
+    }
+
+    /// Creates a checkpoint for a single game
+    pub fn checkpoint(&self, game: usize) -> GameCheckpoint {
+        GameCheckpoint {
+            session: self.clone(),
+            game,
+        }
+    }
+}
+
+/// Checkpoint of a single game in the session
+pub struct GameCheckpoint {
+    session: SessionFile,
+    game: usize,
+}
+
+#[async_trait]
+impl Checkpoint for GameCheckpoint {
+    #[instrument(skip_all, fields(game = self.game), err)]
+    async fn save(&mut self, knowledge: &Knowledge, pending: &[Scheduled]) -> Result<()> {
+        self.session.update(self.game, knowledge, pending).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::san::San;
+    use shakmaty::Chess;
+
+    use super::*;
+    use crate::rev::processor::Priority;
+    use crate::uci::Score;
+
+    #[tokio::test]
+    async fn session_round_trip() {
+        let path = std::env::temp_dir().join(format!("emily-session-{}.json", std::process::id()));
+        let interval = Duration::from_secs(1);
+
+        let mut knowledge = Knowledge::new(Chess::default());
+        let mov = San::from_ascii(b"e4")
+            .unwrap()
+            .to_move(knowledge.root().position())
+            .unwrap();
+        let (_, _, after) = knowledge.add_move(0, 0, mov).unwrap();
+        after.update_eval(Score::Cp(30));
+        let pending = [Scheduled::research(0, 1, Priority::main())];
+
+        let session = SessionFile::new(path.clone(), interval);
+        session
+            .checkpoint(1)
+            .save(&knowledge, &pending)
+            .await
+            .unwrap();
+        session.checkpoint(2).save(&knowledge, &[]).await.unwrap();
+        session.finish(2, Some(123)).await.unwrap();
+
+        let session = SessionFile::load(path.clone(), interval).await.unwrap();
+        assert!(session.is_finished(2).await);
+        assert!(!session.is_finished(1).await);
+        assert!(session.is_started(1).await);
+        assert_eq!(session.written().await, 123);
+
+        let games = session.games().await;
+        assert_eq!(games.len(), 1);
+        let (no, state) = &games[0];
+        assert_eq!(*no, 1);
+        assert_eq!(state.pending.len(), 1);
+        assert!(state.pending[0].research);
+        assert_eq!(state.pending[0].hm, 1);
+        let (main, variation) = state.knowledge.mainline();
+        assert_eq!(variation.moves(), knowledge.mainline().1.moves());
+        let (_, after) = state.knowledge.variation_hm(main, 1);
+        assert_eq!(after.eval(), Some(Score::Cp(30)));
+
+        session.remove().await.unwrap();
+        assert!(!path.exists());
+    }
+}
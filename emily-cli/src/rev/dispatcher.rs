@@ -1,17 +1,38 @@
 //! Dispatches possitions and knowledge update across processors
 
+use std::time::{Duration, Instant};
+
 use futures::stream::FuturesUnordered;
 use futures::StreamExt;
-use tracing::{debug, info, instrument};
+use tokio::sync::mpsc;
+use tracing::{debug, info, instrument, warn};
 
-use super::processor::{Processor, Scheduled};
+use super::checkpoint::Checkpoint;
+use super::event::{EventKind, Events};
+use super::processor::{Processor, Provisional, ProvisionalSender, Scheduled};
 use crate::knowledge::Knowledge;
-use crate::Result;
+use crate::uci::Score;
+use crate::{config, Result};
 
 /// Builder for `Dispatcher`
-#[derive(Default)]
 pub struct DispatcherBuilder<'a> {
     processors: Vec<Box<dyn Processor + 'a>>,
+    checkpoint: Option<Checkpointing<'a>>,
+    budget: config::Budget,
+    events: Events,
+    provisional: (ProvisionalSender, mpsc::UnboundedReceiver<Provisional>),
+}
+
+impl Default for DispatcherBuilder<'_> {
+    fn default() -> Self {
+        Self {
+            processors: vec![],
+            checkpoint: None,
+            budget: config::Budget::default(),
+            events: Events::default(),
+            provisional: mpsc::unbounded_channel(),
+        }
+    }
 }
 
 impl<'a> DispatcherBuilder<'a> {
@@ -20,12 +41,44 @@ impl<'a> DispatcherBuilder<'a> {
         Self::default()
     }
 
+    /// Sender for the provisional results of the processors, applied while the processing is
+    /// still in progress
+    pub fn provisional(&self) -> ProvisionalSender {
+        self.provisional.0.clone()
+    }
+
     /// Adds a new processor
     pub fn with(&mut self, processor: impl Processor + 'a) -> &mut Self {
         self.processors.push(Box::new(processor) as _);
         self
     }
 
+    /// Stores the dispatching progress every `interval`
+    pub fn checkpoint(
+        &mut self,
+        interval: Duration,
+        checkpoint: impl Checkpoint + Send + 'a,
+    ) -> &mut Self {
+        self.checkpoint = Some(Checkpointing {
+            checkpoint: Box::new(checkpoint) as _,
+            interval,
+            last: Instant::now(),
+        });
+        self
+    }
+
+    /// Limits the dispatching with the budget. Unlimited by default.
+    pub fn budget(&mut self, budget: config::Budget) -> &mut Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Reports the dispatching progress to `events`
+    pub fn events(&mut self, events: Events) -> &mut Self {
+        self.events = events;
+        self
+    }
+
     /// Builds a final dispatcher
     pub fn build(self) -> Dispatcher<'a> {
         Dispatcher {
@@ -35,21 +88,147 @@ impl<'a> DispatcherBuilder<'a> {
                 .map(|processor| ProcessorItem {
                     processor,
                     enqueued: 0,
+                    nodes: 0,
                 })
                 .collect(),
             schedule: vec![],
+            checkpoint: self.checkpoint,
+            budget: Budgeting {
+                budget: self.budget,
+                started: Instant::now(),
+                nodes: 0,
+            },
+            events: self.events,
+            provisional: self.provisional.1,
         }
     }
 }
 
 pub struct Dispatcher<'a> {
     processors: Vec<ProcessorItem<'a>>,
+    /// All the positions scheduled so far
     schedule: Vec<Scheduled>,
+    checkpoint: Option<Checkpointing<'a>>,
+    budget: Budgeting,
+    events: Events,
+    provisional: mpsc::UnboundedReceiver<Provisional>,
+}
+
+/// Budget spent so far
+struct Budgeting {
+    budget: config::Budget,
+    /// Dispatching start
+    started: Instant,
+    /// Nodes searched by all the processors
+    nodes: u64,
+}
+
+impl Budgeting {
+    /// Checks if the position is not too far from the root
+    fn within_plies(&self, schedule: &Scheduled) -> bool {
+        self.budget
+            .max_plies
+            .is_none_or(|max_plies| schedule.hm <= max_plies)
+    }
+
+    /// Checks if the position reached by the analysis can be scheduled. Lines are not followed
+    /// after the eval limit is exceeded.
+    fn allows(&self, knowledge: &Knowledge, schedule: &Scheduled) -> bool {
+        if !self.within_plies(schedule) {
+            return false;
+        }
+
+        let (Some(limit), Some(hm)) = (self.budget.eval_limit, schedule.hm.checked_sub(1)) else {
+            return true;
+        };
+
+        let (_, parent) = knowledge.variation_hm(schedule.variation, hm);
+        match parent.eval() {
+            Some(Score::Cp(cp)) => cp.unsigned_abs() <= limit.unsigned_abs(),
+            Some(Score::Mate(_)) => false,
+            None => true,
+        }
+    }
+
+    /// Number of positions that can still be scheduled
+    fn room(&self, scheduled: usize) -> usize {
+        self.budget
+            .max_positions
+            .map_or(usize::MAX, |max_positions| {
+                max_positions.saturating_sub(scheduled)
+            })
+    }
+
+    /// Accounts the nodes searched by the processor
+    fn spend(&mut self, item: &mut ProcessorItem<'_>) {
+        let nodes = item.processor.nodes();
+        self.nodes += nodes.saturating_sub(item.nodes);
+        item.nodes = nodes;
+    }
+
+    /// Checks if the time or nodes budget is exhausted
+    fn exhausted(&self) -> bool {
+        let time = self
+            .budget
+            .max_time
+            .is_some_and(|max_time| self.started.elapsed() >= max_time);
+        let nodes = self
+            .budget
+            .max_nodes
+            .is_some_and(|max_nodes| self.nodes >= max_nodes);
+
+        time || nodes
+    }
+}
+
+struct Checkpointing<'a> {
+    checkpoint: Box<dyn Checkpoint + Send + 'a>,
+    interval: Duration,
+    /// Last checkpoint time
+    last: Instant,
+}
+
+impl Checkpointing<'_> {
+    /// Positions scheduled, but not yet evaluated (or evaluated provisionally), and re-searches
+    /// not done yet
+    fn pending(knowledge: &Knowledge, schedule: &[Scheduled]) -> Vec<Scheduled> {
+        schedule
+            .iter()
+            .filter(|schedule| {
+                let (_, position) = knowledge.variation_hm(schedule.variation, schedule.hm);
+                position.eval().is_none()
+                    || position.is_provisional()
+                    || (schedule.research && !position.is_researched())
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Stores the checkpoint
+    async fn store(&mut self, knowledge: &Knowledge, pending: &[Scheduled]) {
+        debug!(pending = pending.len(), "Storing checkpoint");
+        if let Err(err) = self.checkpoint.save(knowledge, pending).await {
+            warn!(%err, "Checkpoint failed");
+        }
+        self.last = Instant::now();
+    }
+
+    /// Stores the checkpoint if it is due
+    async fn update(&mut self, knowledge: &Knowledge, schedule: &[Scheduled]) {
+        if self.last.elapsed() < self.interval {
+            return;
+        }
+
+        self.store(knowledge, &Self::pending(knowledge, schedule))
+            .await;
+    }
 }
 
 struct ProcessorItem<'a> {
     processor: Box<dyn Processor + 'a>,
     enqueued: usize,
+    /// Nodes searched by the processor already accounted in the budget
+    nodes: u64,
 }
 
 impl ProcessorItem<'_> {
@@ -65,36 +244,123 @@ impl<'a> Dispatcher<'a> {
         DispatcherBuilder::new()
     }
 
+    /// Returns the outcome if the scheduled position is the game conclusion
+    fn conclusion(knowledge: &Knowledge, schedule: &Scheduled) -> Option<String> {
+        let (variation, _) = knowledge.variation_hm(schedule.variation, schedule.hm);
+        variation
+            .outcome()
+            .filter(|_| schedule.hm == variation.moves().len())
+            .map(|outcome| outcome.to_string())
+    }
+
+    /// Checks if the scheduled position is not the game conclusion
+    fn is_open(knowledge: &Knowledge, schedule: &Scheduled) -> bool {
+        Self::conclusion(knowledge, schedule).is_none()
+    }
+
+    /// Reports scheduled positions concluding their games
+    fn concluded(events: &Events, knowledge: &Knowledge, schedule: &[Scheduled]) {
+        for scheduled in schedule {
+            if let Some(outcome) = Self::conclusion(knowledge, scheduled) {
+                events.emit(EventKind::Concluded {
+                    variation: scheduled.variation,
+                    outcome,
+                });
+            }
+        }
+    }
+
+    /// Applies the provisional result
+    fn provisional(knowledge: &mut Knowledge, provisional: Provisional) {
+        if let Err(err) = provisional.apply(knowledge) {
+            warn!(%err, "Invalid provisional result");
+        }
+    }
+
+    /// Reports newly scheduled positions
+    fn queued(events: &Events, schedule: &[Scheduled]) {
+        for scheduled in schedule {
+            events.emit(EventKind::Queued {
+                variation: scheduled.variation,
+                hm: scheduled.hm,
+            });
+        }
+    }
+
     /// Dispatchess position untill they are produced, finishes when no more positions are
-    /// scheduled for analysis
+    /// scheduled for analysis or the budget is exhausted. The `schedule` are the initial positions
+    /// to analyse.
+    ///
+    /// Positions left when the time or nodes budget is exhausted are abandoned. Returns `false` if
+    /// some of the scheduled positions were left without evaluation (for example when the
+    /// processing was stopped early). In such case the final checkpoint is stored, so the review
+    /// can be continued.
     #[instrument(skip(self, knowledge), err)]
     pub async fn dispatch(
         mut self,
         knowledge: &mut Knowledge,
-        variation: usize,
-        hm: usize,
-    ) -> Result<()> {
-        let schedule = &[Scheduled { variation, hm }];
+        schedule: &[Scheduled],
+    ) -> Result<bool> {
+        self.budget.started = Instant::now();
+        Self::concluded(&self.events, knowledge, schedule);
+        self.schedule = schedule
+            .iter()
+            .filter(|schedule| Self::is_open(knowledge, schedule))
+            .filter(|schedule| self.budget.within_plies(schedule))
+            .take(self.budget.room(0))
+            .cloned()
+            .collect();
+        Self::queued(&self.events, &self.schedule);
+
         let mut processing: FuturesUnordered<_> = self
             .processors
             .into_iter()
             .map(|mut item| {
-                item.processor.enqueue(knowledge, schedule);
+                item.processor.enqueue(knowledge, &self.schedule);
+                item.enqueued = self.schedule.len();
                 item.process()
             })
             .collect();
         let mut idle: Vec<ProcessorItem> = Vec::with_capacity(processing.len());
 
         debug!("Dispatching started");
-        while let Some(mut p) = processing.next().await {
-            let schedule = p
-                .processor
-                .apply_results(knowledge)
+        loop {
+            let mut p = tokio::select! {
+                biased;
+                Some(provisional) = self.provisional.recv() => {
+                    Self::provisional(knowledge, provisional);
+                    continue;
+                }
+                p = processing.next() => match p {
+                    Some(p) => p,
+                    None => break,
+                },
+            };
+
+            // Provisional results sent before the processing finished are applied before the
+            // final ones
+            while let Ok(provisional) = self.provisional.try_recv() {
+                Self::provisional(knowledge, provisional);
+            }
+
+            let schedule = p.processor.apply_results(knowledge);
+            self.budget.spend(&mut p);
+
+            // Processors finishing after the budget is exhausted are not given any more work
+            if self.budget.exhausted() {
+                debug!(nodes = self.budget.nodes, "Budget exhausted");
+                continue;
+            }
+
+            Self::concluded(&self.events, knowledge, &schedule);
+            let room = self.budget.room(self.schedule.len());
+            let schedule: Vec<_> = schedule
                 .into_iter()
-                .filter(|schedule| {
-                    let (variation, _) = knowledge.variation_hm(schedule.variation, schedule.hm);
-                    variation.moves().len() < hm || variation.outcome().is_none()
-                });
+                .filter(|schedule| Self::is_open(knowledge, schedule))
+                .filter(|schedule| self.budget.allows(knowledge, schedule))
+                .take(room)
+                .collect();
+            Self::queued(&self.events, &schedule);
 
             self.schedule.extend(schedule);
             let schedule = &self.schedule[p.enqueued..];
@@ -103,25 +369,252 @@ impl<'a> Dispatcher<'a> {
             p.processor.enqueue(knowledge, schedule);
             p.enqueued += schedule.len();
 
-            for mut idl in idle.drain(..) {
+            // Idle processors are only woken up if they got any work
+            for mut idl in std::mem::take(&mut idle) {
                 let schedule = &self.schedule[idl.enqueued..];
                 idl.processor.enqueue(knowledge, schedule);
                 idl.enqueued += schedule.len();
 
-                processing.push(idl.process());
+                match idl.processor.is_idle() {
+                    true => idle.push(idl),
+                    false => processing.push(idl.process()),
+                }
             }
 
             match p.processor.is_idle() {
                 true => idle.push(p),
                 false => processing.push(p.process()),
             }
+
+            if let Some(checkpoint) = &mut self.checkpoint {
+                checkpoint.update(knowledge, &self.schedule).await;
+            }
         }
 
+        knowledge.propagate();
+
+        let mut pending = Checkpointing::pending(knowledge, &self.schedule);
+        let abandoned = match self.budget.exhausted() {
+            true => std::mem::take(&mut pending).len(),
+            false => 0,
+        };
+
+        let analysed = self.schedule.len() - pending.len() - abandoned;
         info!(
-            total_analysed = self.schedule.len() + 1,
+            total_analysed = analysed,
+            pending = pending.len(),
+            abandoned,
+            nodes = self.budget.nodes,
             "Dispathing finished"
         );
+        self.events.emit(EventKind::Finished {
+            analysed,
+            pending: pending.len(),
+            abandoned,
+            nodes: self.budget.nodes,
+        });
+
+        if pending.is_empty() {
+            return Ok(true);
+        }
+
+        if let Some(checkpoint) = &mut self.checkpoint {
+            checkpoint.store(knowledge, &pending).await;
+        }
+
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use shakmaty::{Chess, Position};
+
+    use super::*;
+    use crate::rev::processor::Priority;
+
+    /// Nodes searched by `Line` per position
+    const NODES: u64 = 10;
+
+    /// Processor following the first legal move with the fixed evaluation. It fails (dropping
+    /// its queue) after analysing the `left` positions.
+    struct Line {
+        eval: Score,
+        left: usize,
+        queue: Vec<Scheduled>,
+        done: Vec<Scheduled>,
+        nodes: u64,
+    }
+
+    impl Line {
+        fn new(eval: Score) -> Self {
+            Self {
+                eval,
+                left: usize::MAX,
+                queue: vec![],
+                done: vec![],
+                nodes: 0,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Processor for Line {
+        fn enqueue(&mut self, _knowledge: &mut Knowledge, schedule: &[Scheduled]) {
+            self.queue.extend_from_slice(schedule);
+        }
+
+        async fn process(&mut self) {
+            if self.left == 0 {
+                self.queue.clear();
+                return;
+            }
+
+            if let Some(scheduled) = self.queue.pop() {
+                self.left -= 1;
+                self.nodes += NODES;
+                self.done.push(scheduled);
+            }
+        }
+
+        fn apply_results(&mut self, knowledge: &mut Knowledge) -> Vec<Scheduled> {
+            let mut schedule = vec![];
+            for scheduled in self.done.drain(..) {
+                let (_, position) = knowledge.variation_hm_mut(scheduled.variation, scheduled.hm);
+                let mov = position.position().legal_moves()[0].clone();
+                position.update_eval(self.eval).update_best(mov.clone());
+
+                let (idx, _, _) = knowledge
+                    .add_move(scheduled.variation, scheduled.hm, mov)
+                    .unwrap();
+                let priority = scheduled.priority.next(false);
+                schedule.push(Scheduled::new(idx, scheduled.hm + 1, priority));
+            }
+            schedule
+        }
+
+        fn is_idle(&self) -> bool {
+            self.queue.is_empty()
+        }
+
+        fn nodes(&self) -> u64 {
+            self.nodes
+        }
+    }
+
+    /// Reviews the starting position within the budget, returning if the review is complete and
+    /// the number of positions analysed
+    async fn review(budget: config::Budget, eval: Score) -> (bool, usize) {
+        let mut knowledge = Knowledge::new(Chess::default());
+        let mut dispatcher = Dispatcher::builder();
+        dispatcher.with(Line::new(eval)).budget(budget);
+        let complete = dispatcher
+            .build()
+            .dispatch(&mut knowledge, &[Scheduled::new(0, 0, Priority::main())])
+            .await
+            .unwrap();
+
+        let analysed = knowledge
+            .positions()
+            .iter()
+            .filter(|position| position.eval().is_some())
+            .count();
+        (complete, analysed)
+    }
+
+    #[tokio::test]
+    async fn positions_budget() {
+        let budget = config::Budget {
+            max_positions: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(review(budget, Score::Cp(0)).await, (true, 3));
+    }
+
+    #[tokio::test]
+    async fn plies_budget() {
+        let budget = config::Budget {
+            max_plies: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(review(budget, Score::Cp(0)).await, (true, 5));
+    }
+
+    #[tokio::test]
+    async fn nodes_budget_exhausted() {
+        // Positions scheduled when the budget is exhausted are abandoned
+        let budget = config::Budget {
+            max_nodes: Some(2 * NODES + 1),
+            ..Default::default()
+        };
+        assert_eq!(review(budget, Score::Cp(0)).await, (true, 3));
+    }
+
+    #[tokio::test]
+    async fn eval_limit_stops_lines() {
+        let budget = config::Budget {
+            max_positions: Some(10),
+            eval_limit: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(review(budget.clone(), Score::Cp(-300)).await, (true, 1));
+        assert_eq!(review(budget.clone(), Score::Mate(3)).await, (true, 1));
+        assert_eq!(review(budget, Score::Cp(100)).await, (true, 10));
+    }
+
+    /// Checkpoint remembering the pending positions stored
+    struct Recorded<'a>(&'a mut Vec<Scheduled>);
+
+    #[async_trait]
+    impl Checkpoint for Recorded<'_> {
+        async fn save(&mut self, _knowledge: &Knowledge, pending: &[Scheduled]) -> Result<()> {
+            *self.0 = pending.to_vec();
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn incomplete_review_checkpointed() {
+        let mut knowledge = Knowledge::new(Chess::default());
+        let mut pending = vec![];
+        let line = Line {
+            left: 2,
+            ..Line::new(Score::Cp(0))
+        };
+        let mut dispatcher = Dispatcher::builder();
+        dispatcher
+            .with(line)
+            .checkpoint(Duration::from_secs(60), Recorded(&mut pending));
+
+        let schedule = [Scheduled::new(0, 0, Priority::main())];
+        let complete = dispatcher
+            .build()
+            .dispatch(&mut knowledge, &schedule)
+            .await
+            .unwrap();
+        assert!(!complete);
+        assert_eq!(pending.len(), 1);
+        assert_eq!((pending[0].variation, pending[0].hm), (0, 2));
+    }
+
+    #[test]
+    fn exhausted() {
+        let mut budgeting = Budgeting {
+            budget: config::Budget {
+                max_nodes: Some(100),
+                ..Default::default()
+            },
+            started: Instant::now(),
+            nodes: 99,
+        };
+        assert!(!budgeting.exhausted());
+        budgeting.nodes = 100;
+        assert!(budgeting.exhausted());
 
-        Ok(())
+        budgeting.budget.max_nodes = None;
+        assert!(!budgeting.exhausted());
+        budgeting.budget.max_time = Some(Duration::ZERO);
+        assert!(budgeting.exhausted());
     }
 }
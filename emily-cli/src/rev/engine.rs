@@ -1,22 +1,27 @@
 //! Engine possitions processing entities
 
-use std::collections::VecDeque;
+use std::cmp::Reverse;
+use std::collections::HashSet;
 use std::fmt::{Debug, Formatter};
 use std::time::Duration;
 
 use async_trait::async_trait;
 use color_eyre::eyre::OptionExt;
 use derivative::Derivative;
+use shakmaty::fen::Fen;
 use shakmaty::uci::UciMove;
-use shakmaty::{Chess, Color, Move, Position};
+use shakmaty::{Chess, EnPassantMode, Move, Position};
 use tracing::{debug, error, instrument, trace};
 
 use crate::adapters::debug::{DFenExt, FlatOptExt, LineExt, MovExt};
-use crate::knowledge::Knowledge;
+use crate::knowledge::{Knowledge, PosInfo};
+use crate::shutdown::Shutdown;
 use crate::uci::Score;
 use crate::{config, uci, Result};
 
-use super::processor::{Processor, Scheduled};
+use super::event::{EventKind, Events};
+use super::processor::{Priority, Processor, Provisional, ProvisionalSender, Scheduled};
+use super::threat::ThreatProcessor;
 
 /// Engine analysis outcome
 #[derive(Derivative)]
@@ -26,55 +31,163 @@ pub struct EngineAnalysis {
     variation: usize,
     /// Halfmoves in variation when analysed
     hm: usize,
+    /// Priority of the analysed position
+    priority: Priority,
     /// Choosen move
     #[derivative(Debug(format_with = "MovExt::fmt"))]
     mov: UciMove,
+    /// Principal variation, starting with the choosen move
+    #[derivative(Debug(format_with = "LineExt::fmt"))]
+    pv: Vec<UciMove>,
     /// Engine evaluation
     eval: Score,
+    /// Depth the analysis reached
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    depth: Option<u8>,
+    /// If the analysis is a deeper re-search
+    research: bool,
 }
 
 impl EngineAnalysis {
     /// Creates analysis from engine outcome.
     ///
     /// Note that UCI engines perform analysis in cp from their perspective, our analysis assumes
-    /// that eval is always from white perspective - conversion is performed here.
-    fn new(variation: usize, hm: usize, fen: Chess, mov: UciMove, eval: Score) -> Self {
-        let eval = match fen.turn() {
-            Color::White => eval,
-            Color::Black => eval.rev(),
-        };
+    /// that eval is always from white perspective - conversion is performed here. The `position`
+    /// is the analysed position.
+    fn new(scheduled: &Scheduled, position: &Chess, search: &Search, research: bool) -> Self {
+        let eval = search.eval.pov(position.turn());
 
         let analysis = Self {
-            variation,
-            hm,
-            mov,
+            variation: scheduled.variation,
+            hm: scheduled.hm,
+            priority: scheduled.priority,
+            mov: search.mov.clone(),
+            pv: search.line.clone(),
             eval,
+            depth: Some(search.depth),
+            research,
         };
 
         trace!(?analysis, "Engine analysis created");
         analysis
     }
+
+    /// Creates analysis from the position analysed before (reached by the other variation)
+    fn reused(scheduled: &Scheduled, position: &PosInfo) -> Option<Self> {
+        let analysis = Self {
+            variation: scheduled.variation,
+            hm: scheduled.hm,
+            priority: scheduled.priority,
+            mov: UciMove::from_standard(position.best()?),
+            pv: position
+                .pv()
+                .unwrap_or_default()
+                .iter()
+                .map(UciMove::from_standard)
+                .collect(),
+            eval: position.eval()?,
+            depth: position.depth(),
+            research: false,
+        };
+
+        trace!(?analysis, "Engine analysis reused");
+        Some(analysis)
+    }
+
+    /// Event reporting the analysis being applied
+    fn event(&self) -> EventKind {
+        EventKind::Applied {
+            variation: self.variation,
+            hm: self.hm,
+            eval: self.eval,
+            best: self.mov.to_string(),
+            depth: self.depth,
+        }
+    }
 }
 
 impl EngineAnalysis {
+    /// Applies the analysis. Returns the position after the best move to analyse, if the move was
+    /// not yet considered.
     #[instrument(skip(knowledge))]
-    fn apply(self, knowledge: &mut Knowledge) -> Result<Scheduled> {
+    fn apply(self, knowledge: &mut Knowledge) -> Result<Option<Scheduled>> {
         let (_, position) = knowledge.variation_hm_mut(self.variation, self.hm);
         position.update_eval(self.eval);
+        if let Some(depth) = self.depth {
+            position.update_depth(depth);
+        }
         debug!(pos=?position.position().d_fen(), eval=%self.eval, "Applying analysis");
 
         let mov = self.mov.to_move(position.position())?;
+        position.update_best(mov.clone()).set_provisional(false);
+        if self.research {
+            position.set_researched(true);
+        }
         debug!(mov = ?mov.d_mov(), "Move to schedule");
 
-        let (idx, _, _) = knowledge.add_move(self.variation, self.hm, mov)?;
-        knowledge.update_mainline(self.variation, idx);
-        let scheduled = Scheduled::new(idx, self.hm + 1);
+        // The engine line is followed as long as its moves are legal
+        let mut pos = position.position().clone();
+        let pv: Vec<_> = self
+            .pv
+            .iter()
+            .map_while(|mov| {
+                let mov = mov.to_move(&pos).ok()?;
+                pos.play_unchecked(&mov);
+                Some(mov)
+            })
+            .collect();
+        if !pv.is_empty() {
+            position.update_pv(pv);
+        }
+
+        let (variation, _) = knowledge.variation_hm(self.variation, self.hm);
+        if variation.moves().get(self.hm) == Some(&mov) {
+            debug!("Move already considered");
+            return Ok(None);
+        }
+
+        // The reviewed line is never extended. A game end only keeps the engine line, while a
+        // position without moves is explored in a separate variation.
+        let (main, mainline) = knowledge.mainline();
+        let end = self.variation == main && self.hm == mainline.moves().len();
+        let (idx, _, _) = match end {
+            false => knowledge.add_move(self.variation, self.hm, mov)?,
+            true if self.hm > 0 => {
+                debug!("Reviewed game end reached");
+                return Ok(None);
+            }
+            true if knowledge.branched(self.variation, self.hm, &mov).is_some() => {
+                debug!("Move already considered");
+                return Ok(None);
+            }
+            true => knowledge.branch_move(self.variation, self.hm, mov)?,
+        };
+        let priority = self.priority.next(!end && idx != self.variation);
+        let scheduled = Scheduled::new(idx, self.hm + 1, priority);
         trace!(?scheduled, "Move scheduled");
 
-        Ok(scheduled)
+        Ok(Some(scheduled))
     }
 }
 
+/// Single engine search outcome
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub(super) struct Search {
+    /// Best move
+    #[derivative(Debug(format_with = "MovExt::fmt"))]
+    pub mov: UciMove,
+    /// The engine line starting with the best move
+    #[derivative(Debug(format_with = "LineExt::fmt"))]
+    pub line: Vec<UciMove>,
+    /// Evaluation from the engine PoV
+    pub eval: Score,
+    /// Depth reached
+    pub depth: u8,
+    /// Nodes searched
+    pub nodes: u64,
+}
+
 /// The UCI engine/config wrapper. Not a processor itself, as processor analyses a single
 /// game/position list, while single engine instance can be reused. The final processor wuold be a
 /// wrapped instance of this.
@@ -86,6 +199,10 @@ pub struct Engine {
     depth: Option<u8>,
     #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
     time: Option<Duration>,
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    research: Option<config::Research>,
+    /// Intermediate results are applied as provisional
+    anytime: bool,
 }
 
 impl Engine {
@@ -99,67 +216,193 @@ impl Engine {
             engine,
             depth: config.depth,
             time: config.time,
+            research: config.research.clone(),
+            anytime: config.anytime,
         })
     }
 
-    /// Starts a new game, returns a game processor
+    /// Creates new engine for the threats analysis, starts the process
     #[instrument(err)]
-    pub async fn new_game(&mut self) -> Result<EngineProcessor> {
+    pub async fn threats(engine: config::Engine, config: &config::Threats) -> Result<Self> {
+        trace!("Creating threats engine");
+        let engine = uci::Engine::run(engine).await?;
+
+        Ok(Self {
+            engine,
+            depth: config.depth,
+            time: config.time,
+            research: None,
+            anytime: false,
+        })
+    }
+
+    /// Starts a new game, returns a game processor. The processing is stopped when the `shutdown`
+    /// is requested, the progress is reported to `events`. In the anytime mode intermediate
+    /// results are sent to `provisional`.
+    #[instrument(skip(shutdown, events, provisional), err)]
+    pub async fn new_game(
+        &mut self,
+        shutdown: Shutdown,
+        events: Events,
+        provisional: ProvisionalSender,
+    ) -> Result<EngineProcessor<'_>> {
         trace!("Creating engine processor wrapper");
         self.engine.new_game().await?;
         Ok(EngineProcessor {
             engine: self,
-            queue: VecDeque::new(),
+            queue: vec![],
             results: vec![],
+            shutdown,
+            events,
+            provisional,
+            nodes: 0,
+            analysed: HashSet::new(),
         })
     }
 
+    /// Starts a new game for the threats analysis, returns a game threats processor. The
+    /// processing is stopped when the `shutdown` is requested.
+    #[instrument(skip(shutdown), err)]
+    pub async fn new_threats_game(&mut self, shutdown: Shutdown) -> Result<ThreatProcessor<'_>> {
+        trace!("Creating threats processor wrapper");
+        self.engine.new_game().await?;
+        Ok(ThreatProcessor::new(self, shutdown))
+    }
+
     /// Gracefully stops the engine
     #[instrument(err)]
     pub async fn quit(self) -> Result<()> {
         self.engine.quit().await
     }
 
-    /// Processes a single variation
-    #[instrument(skip(fen, moves), fields(fen=?fen.d_fen(), moves=?moves.d_line()), err)]
-    async fn process(&mut self, fen: Chess, moves: Vec<Move>) -> Result<(UciMove, Score)> {
-        let mut stream = self
-            .engine
-            .go(fen.clone(), &moves, self.depth, self.time)
-            .await?;
+    /// Processes a single variation, with the re-search limits if `research` is set. Every
+    /// engine `info` is passed to `progress`. Returns `None` if the analysis was stopped due to the
+    /// shutdown.
+    #[instrument(skip(fen, moves, shutdown, progress), fields(fen=?fen.d_fen(), moves=?moves.d_line()), err)]
+    pub(super) async fn process(
+        &mut self,
+        fen: Chess,
+        moves: Vec<Move>,
+        research: bool,
+        shutdown: &mut Shutdown,
+        mut progress: impl FnMut(&uci::Info) + Send,
+    ) -> Result<Option<Search>> {
+        let (depth, time) = match (&self.research, research) {
+            (Some(config), true) => (config.depth, config.time),
+            _ => (self.depth, self.time),
+        };
 
-        let mut mov = None;
+        let mut stream = self.engine.go(fen.clone(), &moves, depth, time).await?;
+
+        let mut line = vec![];
         let mut eval = None;
+        let mut depth = 0;
+        let mut nodes = 0;
+
+        loop {
+            let info = tokio::select! {
+                info = stream.info() => info?,
+                _ = shutdown.wait() => {
+                    // Partial analysis is not reliable, it is dropped
+                    debug!("Stopping analysis");
+                    stream.stop_wait().await?;
+                    return Ok(None);
+                }
+            };
 
-        while let Some(info) = stream.info().await? {
-            debug!(?mov, ?eval, "Updating best move");
-            mov = info.line.into_iter().next().or(mov);
+            let Some(info) = info else {
+                break;
+            };
+            progress(&info);
+
+            debug!(line = ?line.d_line(), ?eval, "Updating best move");
+            if !info.line.is_empty() {
+                line = info.line;
+            }
             eval = Some(info.score);
+            depth = depth.max(info.depth);
+            nodes = nodes.max(info.nodes);
         }
 
-        let mov = mov.ok_or_eyre("No move after analysis")?;
-        let eval = eval.ok_or_eyre("No eval after analyis")?;
-        debug!(%mov, %eval, "Position processed");
+        let search = Search {
+            mov: line.first().cloned().ok_or_eyre("No move after analysis")?,
+            line,
+            eval: eval.ok_or_eyre("No eval after analyis")?,
+            depth,
+            nodes,
+        };
+        debug!(?search, "Position processed");
 
-        Ok((mov, eval))
+        Ok(Some(search))
+    }
+}
+
+/// Engines used for a single game review
+#[derive(Debug)]
+pub struct Engines {
+    /// Main analysis engine
+    pub main: Engine,
+    /// Threats analysis engine, if configured
+    pub threats: Option<Engine>,
+}
+
+impl Engines {
+    /// Starts all the engines configured for the review
+    #[instrument(err)]
+    pub async fn new(engine: config::Engine, config: &config::Rev) -> Result<Self> {
+        let threats = match &config.threats {
+            Some(threats) => Some(Engine::threats(engine.clone(), threats).await?),
+            None => None,
+        };
+
+        Ok(Self {
+            main: Engine::new(engine, config).await?,
+            threats,
+        })
+    }
+
+    /// Gracefully stops all the engines
+    #[instrument(err)]
+    pub async fn quit(self) -> Result<()> {
+        if let Some(threats) = self.threats {
+            threats.quit().await?;
+        }
+        self.main.quit().await
     }
 }
 
 #[derive(Derivative, Clone)]
 #[derivative(Debug)]
 struct Enqueued {
-    variation: usize,
-    hm: usize,
+    scheduled: Scheduled,
+    /// Variation root
     #[derivative(Debug(format_with = "DFenExt::fmt"))]
     fen: Chess,
     #[derivative(Debug(format_with = "LineExt::fmt"))]
     moves: Vec<Move>,
+    /// Position to analyse
+    #[derivative(Debug(format_with = "DFenExt::fmt"))]
+    position: Chess,
+    /// Other variations reaching the same position, waiting for this analysis
+    followers: Vec<Scheduled>,
+    /// The highest priority of the variations waiting for this analysis
+    priority: Priority,
+    /// If the position is re-searched with the deeper limits
+    research: bool,
 }
 
 pub struct EngineProcessor<'a> {
     engine: &'a mut Engine,
-    queue: VecDeque<Enqueued>,
+    /// Positions to analyse, in the order they were enqueued
+    queue: Vec<Enqueued>,
     results: Vec<EngineAnalysis>,
+    shutdown: Shutdown,
+    events: Events,
+    provisional: ProvisionalSender,
+    /// Total nodes searched
+    nodes: u64,
+    /// Positions analysed in this review, with the current limits
+    analysed: HashSet<Chess>,
 }
 
 impl Debug for EngineProcessor<'_> {
@@ -170,53 +413,218 @@ impl Debug for EngineProcessor<'_> {
     }
 }
 
+impl EngineProcessor<'_> {
+    /// Takes the most important position from the queue. Positions with the same priority are
+    /// taken in the order they were enqueued.
+    fn next(&mut self) -> Option<Enqueued> {
+        let (idx, _) = self
+            .queue
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, enqueued)| Reverse(enqueued.priority))?;
+        Some(self.queue.remove(idx))
+    }
+
+    /// Reuses the analysis of the position if it was analysed at least as deep as configured.
+    /// Without the depth limit, only the analysis done in this review is reused, as nothing is
+    /// known about the time spent on the earlier ones. Provisional analysis is never reused.
+    fn reuse(&self, scheduled: &Scheduled, position: &PosInfo) -> Option<EngineAnalysis> {
+        if position.is_provisional() {
+            return None;
+        }
+
+        let sufficient = match (self.engine.depth, position.depth()) {
+            (Some(required), Some(depth)) => depth >= required,
+            (Some(_), None) => false,
+            (None, _) => self.analysed.contains(position.position()),
+        };
+
+        match sufficient {
+            true => EngineAnalysis::reused(scheduled, position),
+            false => None,
+        }
+    }
+
+    /// Enqueues the position for the analysis, unless the same position is already waiting for it.
+    /// The re-search cannot wait for the regular analysis.
+    fn push(&mut self, knowledge: &Knowledge, scheduled: &Scheduled) {
+        let research = scheduled.research;
+        let (variation, root) = knowledge.variation_hm(scheduled.variation, 0);
+        let (_, position) = knowledge.variation_hm(scheduled.variation, scheduled.hm);
+
+        let queued = self.queue.iter_mut().find(|enqueued| {
+            enqueued.position == *position.position() && (enqueued.research || !research)
+        });
+        if let Some(queued) = queued {
+            debug!(?scheduled, queued = ?queued.scheduled, "Transposition already enqueued");
+            queued.priority = queued.priority.max(scheduled.priority);
+            queued.followers.push(scheduled.clone());
+            return;
+        }
+
+        let enqueued = Enqueued {
+            scheduled: scheduled.clone(),
+            fen: root.position().clone(),
+            moves: variation.moves()[..scheduled.hm].to_owned(),
+            position: position.position().clone(),
+            followers: vec![],
+            priority: scheduled.priority,
+            research,
+        };
+        debug!(?enqueued, "Scheduling variation");
+        self.queue.push(enqueued);
+    }
+
+    /// Checks if the analysed position should be re-searched deeper - if its evaluation swings
+    /// too much from the evaluation of the previous position in the variation.
+    fn research(&self, knowledge: &Knowledge, analysis: &EngineAnalysis) -> Option<Scheduled> {
+        let config = self.engine.research.as_ref()?;
+        if analysis.research || analysis.hm == 0 {
+            return None;
+        }
+
+        let (_, position) = knowledge.variation_hm(analysis.variation, analysis.hm);
+        if position.is_researched() {
+            return None;
+        }
+
+        if let (Some(required), Some(depth)) = (config.depth, analysis.depth) {
+            if depth >= required {
+                return None;
+            }
+        }
+
+        let (_, parent) = knowledge.variation_hm(analysis.variation, analysis.hm - 1);
+        let swing = swing(parent.eval()?, analysis.eval);
+        if swing < u32::from(config.swing) {
+            return None;
+        }
+
+        let scheduled = Scheduled::research(analysis.variation, analysis.hm, analysis.priority);
+        debug!(?scheduled, swing, "Evaluation swing, re-searching");
+        Some(scheduled)
+    }
+}
+
+/// Difference between evaluations in centipawns. Mates are infinitely far from centipawn scores
+/// and from the opponent's mates.
+fn swing(a: Score, b: Score) -> u32 {
+    match (a, b) {
+        (Score::Cp(a), Score::Cp(b)) => (i32::from(a) - i32::from(b)).unsigned_abs(),
+        (Score::Mate(a), Score::Mate(b)) if (a > 0) == (b > 0) => 0,
+        _ => u32::MAX,
+    }
+}
+
 #[async_trait]
 impl Processor for EngineProcessor<'_> {
+    /// Enqueues positions for analysis. Positions already analysed deep enough (reached by the
+    /// other variation) are not analysed again, the previous result is reused instead. Positions
+    /// reached by multiple variations are analysed once.
     #[instrument(skip(knowledge))]
     fn enqueue(&mut self, knowledge: &mut Knowledge, schedule: &[Scheduled]) {
         let knowledge = &*knowledge;
 
-        let schedule = schedule
-            .iter()
-            .filter(|scheduled| {
-                let (variation, _) = knowledge.variation_hm(scheduled.variation, scheduled.hm);
-                variation.moves().len() <= scheduled.hm
-            })
-            .map(|scheduled| {
-                let (variation, position) = knowledge.variation_hm(scheduled.variation, 0);
-                let fen = position.position().clone();
-                let moves = variation.moves()[..scheduled.hm].to_owned();
-                debug!(
-                    ?scheduled,
-                    fen = ?fen.d_fen(),
-                    moves = ?moves.d_line(),
-                    "Scheduling variation"
-                );
-
-                Enqueued {
-                    variation: scheduled.variation,
-                    hm: scheduled.hm,
-                    fen,
-                    moves,
-                }
-            });
+        for scheduled in schedule {
+            let (_, position) = knowledge.variation_hm(scheduled.variation, scheduled.hm);
+
+            // Re-search is never satisfied by the earlier analysis
+            let reused = match scheduled.research {
+                true => None,
+                false => self.reuse(scheduled, position),
+            };
+            if let Some(analysis) = reused {
+                debug!(?scheduled, "Transposition already analysed, reusing");
+                self.results.push(analysis);
+                continue;
+            }
 
-        self.queue.extend(schedule);
-        debug!(pending = self.queue.len(), "Scheduling complete");
+            self.push(knowledge, scheduled);
+        }
+
+        debug!(
+            pending = self.queue.len(),
+            reused = self.results.len(),
+            "Scheduling complete"
+        );
     }
 
     #[instrument(skip_all)]
     async fn process(&mut self) {
-        let Some(next) = self.queue.pop_front() else {
+        let Some(next) = self.next() else {
             trace!("No positions to process");
             return;
         };
 
-        match self.engine.process(next.fen.clone(), next.moves).await {
-            Ok((mov, eval)) => {
-                let result = EngineAnalysis::new(next.variation, next.hm, next.fen, mov, eval);
-                trace!(?result, "New result");
-                self.results.push(result);
+        if self.shutdown.is_requested() {
+            debug!(
+                dropped = self.queue.len() + 1,
+                "Shutdown requested, dropping queue"
+            );
+            self.queue.clear();
+            return;
+        }
+
+        let (variation, hm) = (next.scheduled.variation, next.scheduled.hm);
+        let fen = Fen::from_position(next.position.clone(), EnPassantMode::Legal);
+        self.events.emit(EventKind::Started {
+            variation,
+            hm,
+            fen: fen.to_string(),
+        });
+
+        let (events, provisional) = (&self.events, &self.provisional);
+        let anytime = self.engine.anytime && !next.research;
+        let turn = next.position.turn();
+        let mut reached = 0;
+        let progress = |info: &uci::Info| {
+            events.emit(EventKind::Info {
+                variation,
+                hm,
+                depth: info.depth,
+                eval: info.score.pov(turn),
+                nodes: info.nodes,
+                line: info.line.iter().map(ToString::to_string).collect(),
+            });
+
+            // Only the deepest lines are applied, as some engines report shallower lines
+            // (eg. `multipv`) during the search
+            if anytime && !info.line.is_empty() && info.depth >= reached {
+                reached = info.depth;
+                let _ = provisional.send(Provisional {
+                    variation,
+                    hm,
+                    eval: info.score.pov(turn),
+                    depth: info.depth,
+                    pv: info.line.clone(),
+                });
+            }
+        };
+
+        let processed = self
+            .engine
+            .process(
+                next.fen.clone(),
+                next.moves,
+                next.research,
+                &mut self.shutdown,
+                progress,
+            )
+            .await;
+
+        match processed {
+            Ok(None) => self.queue.clear(),
+            Ok(Some(search)) => {
+                self.nodes += search.nodes;
+                self.analysed.insert(next.position.clone());
+                let results =
+                    std::iter::once(&next.scheduled)
+                        .chain(&next.followers)
+                        .map(|scheduled| {
+                            EngineAnalysis::new(scheduled, &next.position, &search, next.research)
+                        });
+                self.results.extend(results);
+                trace!(results = self.results.len(), "New results");
             }
             Err(err) => error!(%err, "Engine processing failed"),
         }
@@ -225,19 +633,29 @@ impl Processor for EngineProcessor<'_> {
     #[instrument(skip_all)]
     fn apply_results(&mut self, knowledge: &mut Knowledge) -> Vec<Scheduled> {
         trace!(results = self.results.len(), "Applying results");
-        self.results
-            .drain(..)
-            .filter_map(|res| match res.apply(knowledge) {
-                Ok(scheduled) => Some(scheduled),
-                Err(err) => {
-                    error!(%err, "While applying result to knowledge");
-                    None
+        let mut schedule = vec![];
+        for res in std::mem::take(&mut self.results) {
+            let research = self.research(knowledge, &res);
+            let event = res.event();
+            match res.apply(knowledge) {
+                Ok(scheduled) => {
+                    self.events.emit(event);
+                    schedule.extend(scheduled);
                 }
-            })
-            .collect()
+                Err(err) => error!(%err, "While applying result to knowledge"),
+            }
+
+            schedule.extend(research);
+        }
+        schedule
     }
 
     fn is_idle(&self) -> bool {
-        self.queue.is_empty()
+        // Reused results are created while enqueuing, they still have to be applied
+        self.queue.is_empty() && self.results.is_empty()
+    }
+
+    fn nodes(&self) -> u64 {
+        self.nodes
     }
 }
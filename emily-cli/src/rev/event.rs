@@ -0,0 +1,137 @@
+//! Review progress events. Subscribers (progress reporting, machine readable output, GUI) follow
+//! the review without relying on the logs.
+
+use serde::Serialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{instrument, warn};
+
+use crate::uci::Score;
+use crate::Result;
+
+/// Events buffered for the slow subscribers
+const CAPACITY: usize = 4096;
+
+/// Single review event
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    /// Game number in the input (starting with 1)
+    pub game: usize,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// Review event details. Positions are identified by the variation and halfmoves in it, all the
+/// evaluations are from the white PoV and the moves are in UCI notation.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    /// Position scheduled for the analysis
+    Queued { variation: usize, hm: usize },
+    /// Engine started analysing the position
+    Started {
+        variation: usize,
+        hm: usize,
+        fen: String,
+    },
+    /// Engine search progress
+    Info {
+        variation: usize,
+        hm: usize,
+        depth: u8,
+        eval: Score,
+        nodes: u64,
+        line: Vec<String>,
+    },
+    /// Analysis result applied to the knowledge
+    Applied {
+        variation: usize,
+        hm: usize,
+        eval: Score,
+        best: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        depth: Option<u8>,
+    },
+    /// Variation reached the game conclusion (including the repetition)
+    Concluded { variation: usize, outcome: String },
+    /// Review of the game finished
+    Finished {
+        analysed: usize,
+        pending: usize,
+        abandoned: usize,
+        nodes: u64,
+    },
+}
+
+/// Events sender. Events are only delivered to the subscribers present when they are emitted,
+/// without subscribers emitting is a no-op.
+#[derive(Debug, Clone)]
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+    /// Game the events are emitted for
+    game: usize,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Events {
+    /// Creates new events channel
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Self { sender, game: 0 }
+    }
+
+    /// Subscribes for the events emitted from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// Sender emitting events of the game `no`
+    pub fn game(&self, no: usize) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            game: no,
+        }
+    }
+
+    /// Emits the event
+    pub fn emit(&self, kind: EventKind) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+
+        let _ = self.sender.send(Event {
+            game: self.game,
+            kind,
+        });
+    }
+}
+
+/// Writes events as JSON lines, until all the senders are dropped
+#[instrument(skip_all, err)]
+pub async fn write_lines(
+    mut events: broadcast::Receiver<Event>,
+    mut writer: impl AsyncWrite + Unpin,
+) -> Result<()> {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!(skipped, "Events writer lagging, events skipped");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        let mut line = serde_json::to_vec(&event)?;
+        line.push(b'\n');
+        writer.write_all(&line).await?;
+    }
+
+    writer.flush().await?;
+    Ok(())
+}
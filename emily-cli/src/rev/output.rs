@@ -0,0 +1,233 @@
+//! Review input and output. `-` stands for stdin/stdout, reviewed games are written as the
+//! annotated PGN or as JSON lines with the analysed positions.
+
+use std::path::Path;
+use std::str::FromStr;
+
+use color_eyre::eyre::bail;
+use serde::Serialize;
+use shakmaty::fen::Fen;
+use shakmaty::uci::UciMove;
+use shakmaty::EnPassantMode;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::{debug, instrument, warn};
+
+use crate::knowledge::{Knowledge, PgnReader, PgnWriter};
+use crate::uci::Score;
+use crate::Result;
+
+/// Path standing for stdin (input) or stdout (output)
+const STDIO: &str = "-";
+
+/// Checks if the path stands for stdin or stdout
+pub fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == STDIO
+}
+
+pub type Reader = BufReader<Box<dyn AsyncRead + Unpin + Send>>;
+pub type Writer = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// Opens the input PGN
+pub async fn open_input(path: &Path) -> Result<PgnReader<Reader>> {
+    let reader: Box<dyn AsyncRead + Unpin + Send> = match is_stdio(path) {
+        true => Box::new(tokio::io::stdin()),
+        false => Box::new(File::open(path).await?),
+    };
+    Ok(PgnReader::new(BufReader::new(reader)))
+}
+
+/// Opens the output. When resuming the session, games are appended to the existing output,
+/// truncated to the `written` length stored by the session - games stored but not recorded as
+/// finished are reviewed again.
+pub async fn open_output(path: &Path, written: Option<u64>) -> Result<Writer> {
+    if is_stdio(path) {
+        return Ok(Box::new(tokio::io::stdout()));
+    }
+
+    let file = match written {
+        Some(written) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?;
+            if file.metadata().await?.len() > written {
+                warn!(written, "Discarding output not recorded in the session");
+                file.set_len(written).await?;
+            }
+            file
+        }
+        None => File::create(path).await?,
+    };
+    Ok(Box::new(file))
+}
+
+/// Current length of the output file, `None` for stdout
+pub async fn output_len(path: &Path) -> Result<Option<u64>> {
+    if is_stdio(path) {
+        return Ok(None);
+    }
+
+    Ok(Some(tokio::fs::metadata(path).await?.len()))
+}
+
+/// Format of the reviewed games
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Format {
+    /// Annotated PGN
+    #[default]
+    Pgn,
+    /// JSON line per analysed position of the game
+    Jsonl,
+}
+
+impl FromStr for Format {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pgn" => Ok(Self::Pgn),
+            "jsonl" => Ok(Self::Jsonl),
+            _ => bail!("Unknown format {s}, expected `pgn` or `jsonl`"),
+        }
+    }
+}
+
+/// Analysed position, the evaluation is from the white PoV and the moves are in UCI notation
+#[derive(Debug, Serialize)]
+struct Record {
+    /// Game number in the input (starting with 1)
+    game: usize,
+    /// Halfmoves from the game start
+    hm: usize,
+    fen: String,
+    /// Move played in the game
+    #[serde(rename = "move")]
+    mov: Option<String>,
+    eval: Score,
+    depth: Option<u8>,
+    best: Option<String>,
+    pv: Vec<String>,
+}
+
+impl Record {
+    /// Records of the analysed game positions. The review never extends the game main line, so
+    /// its moves are the ones played in the game.
+    fn records(no: usize, knowledge: &Knowledge) -> Vec<Self> {
+        let uci = |mov| UciMove::from_standard(mov).to_string();
+        let (main, variation) = knowledge.mainline();
+
+        (0..=variation.moves().len())
+            .filter_map(|hm| {
+                let (_, info) = knowledge.variation_hm(main, hm);
+                let fen = Fen::from_position(info.position().clone(), EnPassantMode::Legal);
+                Some(Self {
+                    game: no,
+                    hm,
+                    fen: fen.to_string(),
+                    mov: variation.moves().get(hm).map(uci),
+                    eval: info.eval()?,
+                    depth: info.depth(),
+                    best: info.best().map(uci),
+                    pv: info.pv().unwrap_or_default().iter().map(uci).collect(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Reviewed games output
+pub enum Output {
+    Pgn(PgnWriter<Writer>),
+    Jsonl { writer: Writer, games: usize },
+}
+
+impl Output {
+    pub fn new(writer: Writer, format: Format) -> Self {
+        match format {
+            Format::Pgn => Self::Pgn(PgnWriter::new(writer)),
+            Format::Jsonl => Self::Jsonl { writer, games: 0 },
+        }
+    }
+
+    /// Appends the game `no` to the output
+    #[instrument(skip_all, fields(game = no), err)]
+    pub async fn write(&mut self, no: usize, knowledge: &Knowledge) -> Result<()> {
+        let (writer, games) = match self {
+            Self::Pgn(pgn) => return pgn.write(knowledge).await,
+            Self::Jsonl { writer, games } => (writer, games),
+        };
+
+        for record in Record::records(no, knowledge) {
+            let mut line = serde_json::to_vec(&record)?;
+            line.push(b'\n');
+            writer.write_all(&line).await?;
+        }
+        writer.flush().await?;
+
+        *games += 1;
+        debug!(games, "Game stored");
+        Ok(())
+    }
+
+    /// Number of games written so far
+    pub fn games(&self) -> usize {
+        match self {
+            Self::Pgn(pgn) => pgn.games(),
+            Self::Jsonl { games, .. } => *games,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::san::San;
+    use shakmaty::Chess;
+
+    use super::*;
+
+    /// Plays the SAN move after `hm` halfmoves of the `vidx` variation, evaluating the position
+    /// after it
+    fn play(knowledge: &mut Knowledge, vidx: usize, hm: usize, san: &str, eval: i16) -> usize {
+        let (_, before) = knowledge.variation_hm(vidx, hm);
+        let mov = San::from_ascii(san.as_bytes())
+            .unwrap()
+            .to_move(before.position())
+            .unwrap();
+        let (idx, _, after) = knowledge.add_move(vidx, hm, mov).unwrap();
+        after.update_eval(Score::Cp(eval));
+        idx
+    }
+
+    #[test]
+    fn records_of_played_moves() {
+        let mut knowledge = Knowledge::new(Chess::default());
+        play(&mut knowledge, 0, 0, "e4", 30);
+        play(&mut knowledge, 0, 1, "e5", 20);
+        // Explored alternative is not a part of the game
+        assert_ne!(play(&mut knowledge, 0, 1, "c5", 40), 0);
+        // Best move of the final position is not played
+        let (_, end) = knowledge.variation_hm_mut(0, 2);
+        let best = San::from_ascii(b"Nf3")
+            .unwrap()
+            .to_move(end.position())
+            .unwrap();
+        end.update_best(best.clone()).update_pv(vec![best]);
+
+        let records = Record::records(3, &knowledge);
+        let moves: Vec<_> = records.iter().map(|r| (r.hm, r.mov.as_deref())).collect();
+        // The root position was not analysed
+        assert_eq!(moves, [(1, Some("e7e5")), (2, None)]);
+        assert!(records.iter().all(|record| record.game == 3));
+        assert_eq!(records[1].best.as_deref(), Some("g1f3"));
+        assert_eq!(records[1].pv, ["g1f3"]);
+
+        let json = serde_json::to_value(&records[0]).unwrap();
+        assert_eq!(json["move"], "e7e5");
+        assert_eq!(
+            json["fen"],
+            "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1"
+        );
+    }
+}
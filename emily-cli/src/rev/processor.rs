@@ -1,21 +1,153 @@
 //! The traits for a position processors
 
+use std::cmp::Ordering;
+
 use async_trait::async_trait;
+use color_eyre::eyre::OptionExt;
+use derivative::Derivative;
+use serde::{Deserialize, Serialize};
+use shakmaty::uci::UciMove;
+use shakmaty::Position;
+use tokio::sync::mpsc;
+use tracing::{instrument, trace};
 
+use crate::adapters::debug::LineExt;
 use crate::knowledge::Knowledge;
+use crate::uci::Score;
+use crate::Result;
+
+/// Processing priority of the scheduled position. Positions of the reviewed line are the most
+/// important, then the positions closer to it, so the close alternatives are analysed before the
+/// deep sidelines. Of positions equally distant, the less nested variations are preferred.
+///
+/// The greater priority is the more important one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Priority {
+    /// Halfmoves since leaving the reviewed line, `0` for the reviewed line itself
+    depth: usize,
+    /// Variation nesting level, `0` for the main line
+    level: usize,
+}
+
+impl Priority {
+    /// Priority of the reviewed line position
+    pub fn main() -> Self {
+        Self::default()
+    }
+
+    /// Priority of the position after the next move. The `branched` move starts a new variation.
+    pub fn next(self, branched: bool) -> Self {
+        Self {
+            depth: self.depth + 1,
+            level: self.level + branched as usize,
+        }
+    }
+}
+
+impl Ord for Priority {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Lower depth and level is more important
+        other
+            .depth
+            .cmp(&self.depth)
+            .then(other.level.cmp(&self.level))
+    }
+}
+
+impl PartialOrd for Priority {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
 
 /// Variation to be scheduled for processing
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Scheduled {
     /// Variation to add a move to
     pub variation: usize,
     /// Halfmoves where to process variation
     pub hm: usize,
+    /// Processing priority
+    #[serde(default)]
+    pub priority: Priority,
+    /// If the position is re-searched with the deeper limits
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub research: bool,
 }
 
 impl Scheduled {
-    pub fn new(variation: usize, hm: usize) -> Self {
-        Self { variation, hm }
+    pub fn new(variation: usize, hm: usize, priority: Priority) -> Self {
+        Self {
+            variation,
+            hm,
+            priority,
+            research: false,
+        }
+    }
+
+    /// Re-search of the already analysed position
+    pub fn research(variation: usize, hm: usize, priority: Priority) -> Self {
+        Self {
+            research: true,
+            ..Self::new(variation, hm, priority)
+        }
+    }
+}
+
+/// Provisional result of the processing still in progress, applied to the knowledge as soon as it
+/// arrives
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct Provisional {
+    /// Analysed variation
+    pub variation: usize,
+    /// Halfmoves in variation when analysed
+    pub hm: usize,
+    /// Evaluation (from the white PoV)
+    pub eval: Score,
+    /// Depth reached so far
+    pub depth: u8,
+    /// Principal variation found so far
+    #[derivative(Debug(format_with = "LineExt::fmt"))]
+    pub pv: Vec<UciMove>,
+}
+
+/// Sender of the provisional results to the dispatcher
+pub type ProvisionalSender = mpsc::UnboundedSender<Provisional>;
+
+impl Provisional {
+    /// Applies the provisional result, marking the position analysis as provisional. The final
+    /// analysis is never overwritten.
+    #[instrument(skip(knowledge))]
+    pub fn apply(self, knowledge: &mut Knowledge) -> Result<()> {
+        let (_, position) = knowledge.variation_hm_mut(self.variation, self.hm);
+        if position.eval().is_some() && !position.is_provisional() {
+            trace!("Position already analysed");
+            return Ok(());
+        }
+
+        let mut pos = position.position().clone();
+        let pv = self
+            .pv
+            .iter()
+            .map(|mov| {
+                let mov = mov.to_move(&pos)?;
+                pos.play_unchecked(&mov);
+                Ok(mov)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let best = pv
+            .first()
+            .cloned()
+            .ok_or_eyre("Empty principal variation")?;
+
+        position
+            .update_eval(self.eval)
+            .update_depth(self.depth)
+            .update_best(best)
+            .update_pv(pv)
+            .set_provisional(true);
+        Ok(())
     }
 }
 
@@ -26,6 +158,8 @@ pub trait Processor {
     /// are not relevant or already processed. If the variation can be processed in-place (without
     /// blocking), it can also be processed immediately instead of enqueing.
     ///
+    /// Enqueued variations should be processed in the order of their `Scheduled::priority`.
+    ///
     /// Passed variations are never the concluded games (ie. `Variation::outcome` is always
     /// `None`).
     fn enqueue(&mut self, knowledge: &mut Knowledge, schedule: &[Scheduled]);
@@ -39,4 +173,32 @@ pub trait Processor {
 
     /// Returns if the processor has work to do
     fn is_idle(&self) -> bool;
+
+    /// Total nodes searched so far, accounted in the review budget
+    fn nodes(&self) -> u64 {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn priorities_ordered() {
+        let main = Priority::main();
+        let alternative = main.next(true);
+        let sideline = alternative.next(false);
+        let nested = alternative.next(true);
+
+        assert!(main > alternative);
+        assert!(alternative > sideline);
+        assert!(sideline > nested);
+        assert!(main.next(false) > alternative);
+        assert!(main.next(false).next(false) < alternative);
+
+        let mut priorities = vec![nested, main, sideline, alternative];
+        priorities.sort_by(|a, b| b.cmp(a));
+        assert_eq!(priorities, [main, alternative, sideline, nested]);
+    }
 }
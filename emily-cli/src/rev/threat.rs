@@ -0,0 +1,225 @@
+//! Threats analysis - what the opponent would play if it was their move (null-move search)
+
+use std::cmp::Reverse;
+use std::fmt::{Debug, Formatter};
+
+use async_trait::async_trait;
+use derivative::Derivative;
+use shakmaty::{Chess, Move, Position};
+use tracing::{debug, error, instrument, trace};
+
+use crate::adapters::debug::{DFenExt, LineExt};
+use crate::knowledge::Knowledge;
+use crate::shutdown::Shutdown;
+
+use super::engine::{Engine, Search};
+use super::processor::{Processor, Scheduled};
+
+/// Threat analysis outcome
+#[derive(Derivative)]
+#[derivative(Debug)]
+struct ThreatAnalysis {
+    /// Analysed variation
+    variation: usize,
+    /// Halfmoves in variation when analysed
+    hm: usize,
+    /// Threat line, played from the position with the side to move swapped
+    #[derivative(Debug(format_with = "LineExt::fmt"))]
+    line: Vec<Move>,
+}
+
+impl ThreatAnalysis {
+    /// Creates analysis from the engine outcome. The engine line is followed as long as its moves
+    /// are legal.
+    fn new(scheduled: &Scheduled, position: &Chess, search: &Search) -> Self {
+        let mut position = position.clone();
+        let line = search
+            .line
+            .iter()
+            .map_while(|mov| {
+                let mov = mov.to_move(&position).ok()?;
+                position.play_unchecked(&mov);
+                Some(mov)
+            })
+            .collect();
+
+        let analysis = Self {
+            variation: scheduled.variation,
+            hm: scheduled.hm,
+            line,
+        };
+
+        trace!(?analysis, "Threat analysis created");
+        analysis
+    }
+
+    /// Applies the analysis
+    #[instrument(skip(knowledge))]
+    fn apply(self, knowledge: &mut Knowledge) {
+        if self.line.is_empty() {
+            debug!("No threat found");
+            return;
+        }
+
+        let (_, position) = knowledge.variation_hm_mut(self.variation, self.hm);
+        debug!(pos = ?position.position().d_fen(), "Applying threat");
+        position.update_threat(self.line);
+    }
+}
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+struct Enqueued {
+    scheduled: Scheduled,
+    /// Position with the side to move swapped
+    #[derivative(Debug(format_with = "DFenExt::fmt"))]
+    position: Chess,
+}
+
+/// Processor analysing the opponent's threats. The position is searched with the side to move
+/// swapped (as if the null move was played), which is not possible when in check. Threats are
+/// never explored further.
+pub struct ThreatProcessor<'a> {
+    engine: &'a mut Engine,
+    queue: Vec<Enqueued>,
+    results: Vec<ThreatAnalysis>,
+    shutdown: Shutdown,
+    /// Total nodes searched
+    nodes: u64,
+}
+
+impl Debug for ThreatProcessor<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ThreatProcessor")
+            .field("engine", self.engine)
+            .finish()
+    }
+}
+
+impl<'a> ThreatProcessor<'a> {
+    pub(super) fn new(engine: &'a mut Engine, shutdown: Shutdown) -> Self {
+        Self {
+            engine,
+            queue: vec![],
+            results: vec![],
+            shutdown,
+            nodes: 0,
+        }
+    }
+
+    /// Takes the most important position from the queue
+    fn next(&mut self) -> Option<Enqueued> {
+        let (idx, _) = self
+            .queue
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, enqueued)| Reverse(enqueued.scheduled.priority))?;
+        Some(self.queue.remove(idx))
+    }
+}
+
+#[async_trait]
+impl Processor for ThreatProcessor<'_> {
+    /// Enqueues positions for the threats analysis. Positions with the threat already analysed,
+    /// positions in check and positions reached by multiple variations are analysed at most once.
+    #[instrument(skip(knowledge))]
+    fn enqueue(&mut self, knowledge: &mut Knowledge, schedule: &[Scheduled]) {
+        for scheduled in schedule {
+            // Threats don't depend on the search limits of the position analysis
+            if scheduled.research {
+                continue;
+            }
+
+            let (_, position) = knowledge.variation_hm(scheduled.variation, scheduled.hm);
+            if position.threat().is_some() {
+                trace!(?scheduled, "Threat already analysed");
+                continue;
+            }
+
+            let Ok(swapped) = position.position().clone().swap_turn() else {
+                trace!(?scheduled, "Null move not possible");
+                continue;
+            };
+
+            if swapped.legal_moves().is_empty() {
+                trace!(?scheduled, "No moves after null move");
+                continue;
+            }
+
+            if self
+                .queue
+                .iter()
+                .any(|enqueued| enqueued.position == swapped)
+            {
+                trace!(?scheduled, "Transposition already enqueued");
+                continue;
+            }
+
+            let enqueued = Enqueued {
+                scheduled: scheduled.clone(),
+                position: swapped,
+            };
+            debug!(?enqueued, "Scheduling threat analysis");
+            self.queue.push(enqueued);
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn process(&mut self) {
+        let Some(next) = self.next() else {
+            trace!("No positions to process");
+            return;
+        };
+
+        if self.shutdown.is_requested() {
+            debug!(
+                dropped = self.queue.len() + 1,
+                "Shutdown requested, dropping queue"
+            );
+            self.queue.clear();
+            return;
+        }
+
+        // Null move cannot be passed to the engine, the swapped position is analysed directly
+        let processed = self
+            .engine
+            .process(
+                next.position.clone(),
+                vec![],
+                false,
+                &mut self.shutdown,
+                |_| (),
+            )
+            .await;
+
+        match processed {
+            Ok(None) => self.queue.clear(),
+            Ok(Some(search)) => {
+                self.nodes += search.nodes;
+                self.results.push(ThreatAnalysis::new(
+                    &next.scheduled,
+                    &next.position,
+                    &search,
+                ));
+            }
+            Err(err) => error!(%err, "Threat processing failed"),
+        }
+    }
+
+    #[instrument(skip_all)]
+    fn apply_results(&mut self, knowledge: &mut Knowledge) -> Vec<Scheduled> {
+        trace!(results = self.results.len(), "Applying threats");
+        for res in self.results.drain(..) {
+            res.apply(knowledge);
+        }
+        vec![]
+    }
+
+    fn is_idle(&self) -> bool {
+        self.queue.is_empty() && self.results.is_empty()
+    }
+
+    fn nodes(&self) -> u64 {
+        self.nodes
+    }
+}
@@ -0,0 +1,509 @@
+//! Local HTTP/JSON API - reviews and analyses for scripts and tools, without parsing the command
+//! output files
+//!
+//! - `POST /review` reviews the game (`pgn`) or the position (`fen`), returning the annotated
+//!   knowledge as JSON and PGN
+//! - `GET /review/live` is the same over the WebSocket - the request is the first message, the
+//!   review events follow and the final `result` message has the review
+//! - `POST /analyse` analyses the position, returning the engine best move and evaluation
+
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use color_eyre::eyre::{bail, eyre, Context, OptionExt};
+use color_eyre::Result;
+use derivative::Derivative;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use shakmaty::fen::Fen;
+use shakmaty::uci::UciMove;
+use shakmaty::{EnPassantMode, Move, Position};
+use structopt::StructOpt;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio::task::{spawn_local, LocalSet};
+use tracing::{debug, info, instrument, warn};
+
+use crate::knowledge::{Knowledge, PgnReader};
+use crate::rev::{parse_chess, review_with, Events};
+use crate::shutdown::Shutdown;
+use crate::uci::{self, Limits, Score};
+use crate::{config, Config};
+
+/// API error, responded as `{"error": "..."}`
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn bad_request(err: impl std::fmt::Display) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            message: err.to_string(),
+        }
+    }
+}
+
+impl From<color_eyre::Report> for ApiError {
+    fn from(err: color_eyre::Report) -> Self {
+        Self {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: format!("{err:#}"),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = Json(json!({ "error": self.message }));
+        (self.status, body).into_response()
+    }
+}
+
+/// Review of the game (`pgn`, only the first game is reviewed) or the position (`fen`, the
+/// starting one if neither is given). Limits and budget override the `rev` configuration ones.
+#[derive(Derivative, Deserialize)]
+#[derivative(Debug)]
+struct ReviewRequest {
+    #[derivative(Debug = "ignore")]
+    pgn: Option<String>,
+    fen: Option<String>,
+    /// Analysis depth limit (per move)
+    depth: Option<u8>,
+    /// Analysis time limit in seconds (per move)
+    time: Option<f64>,
+    /// Maximum halfmoves from the root to analyse
+    max_plies: Option<usize>,
+    /// Maximum positions analysed
+    max_positions: Option<usize>,
+    /// Total review time in seconds
+    max_time: Option<f64>,
+}
+
+impl ReviewRequest {
+    /// Game to review
+    async fn knowledge(&self) -> Result<Knowledge, ApiError> {
+        match (&self.pgn, &self.fen) {
+            (Some(pgn), None) => PgnReader::new(pgn.as_bytes())
+                .next_game()
+                .await
+                .map_err(ApiError::bad_request)?
+                .ok_or_else(|| ApiError::bad_request("No game in the PGN"))?
+                .map_err(ApiError::bad_request),
+            (None, fen) => {
+                let root = fen.as_deref().map(parse_chess).transpose();
+                let root = root.map_err(ApiError::bad_request)?;
+                Ok(Knowledge::new(root.unwrap_or_default()))
+            }
+            (Some(_), Some(_)) => Err(ApiError::bad_request("Either `pgn` or `fen` is allowed")),
+        }
+    }
+
+    /// Review configuration with the request limits, one of which is required, and budget. The
+    /// review unlimited by the budget is limited to the `max_time`.
+    fn rev(&self, config: &config::Rev, max_time: Duration) -> Result<config::Rev, ApiError> {
+        let mut rev = config.clone();
+        if let Some(depth) = self.depth {
+            rev.depth = Some(depth);
+        }
+        if let Some(time) = time_limit(self.time)? {
+            rev.time = Some(time);
+        }
+
+        if rev.depth.is_none() && rev.time.is_none() {
+            return Err(ApiError::bad_request("Depth or time limit is required"));
+        }
+
+        let budget = &mut rev.budget;
+        budget.max_plies = self.max_plies.or(budget.max_plies);
+        budget.max_positions = self.max_positions.or(budget.max_positions);
+        budget.max_time = time_limit(self.max_time)?.or(budget.max_time);
+        if budget.max_plies.is_none()
+            && budget.max_positions.is_none()
+            && budget.max_time.is_none()
+            && budget.max_nodes.is_none()
+        {
+            budget.max_time = Some(max_time);
+        }
+        Ok(rev)
+    }
+}
+
+/// Converts the request time limit in seconds
+fn time_limit(secs: Option<f64>) -> Result<Option<Duration>, ApiError> {
+    secs.map(Duration::try_from_secs_f64)
+        .transpose()
+        .map_err(|err| ApiError::bad_request(format!("Invalid time limit: {err}")))
+}
+
+/// Reviewed game
+#[derive(Serialize)]
+struct ReviewResponse {
+    knowledge: Knowledge,
+    pgn: String,
+}
+
+impl ReviewResponse {
+    async fn new(knowledge: Knowledge) -> Result<Self> {
+        let mut pgn = vec![];
+        knowledge.pgn().write_pgn(&mut pgn).await?;
+        Ok(Self {
+            pgn: String::from_utf8(pgn)?,
+            knowledge,
+        })
+    }
+}
+
+/// Analysis of the position - `fen` (the starting one by default) after the `moves` (in UCI
+/// notation). Limits default to the `rev` configuration ones.
+#[derive(Debug, Deserialize)]
+struct AnalyseRequest {
+    fen: Option<String>,
+    #[serde(default)]
+    moves: Vec<String>,
+    /// Search depth limit
+    depth: Option<u8>,
+    /// Search time limit in seconds
+    time: Option<f64>,
+}
+
+/// Engine analysis result, the evaluation is from the white PoV and the moves are in UCI notation
+#[derive(Debug, Serialize)]
+struct AnalyseResponse {
+    fen: String,
+    best: String,
+    eval: Option<Score>,
+    depth: u8,
+    nodes: u64,
+    pv: Vec<String>,
+}
+
+/// Review run by the reviewer task
+struct Job {
+    knowledge: Knowledge,
+    rev: config::Rev,
+    events: Events,
+    reply: oneshot::Sender<Result<Knowledge>>,
+}
+
+/// Shared server state
+#[derive(Debug)]
+struct Server {
+    config: Config,
+    shutdown: Shutdown,
+    /// Reviews are not `Send`, so they are run on the local set by the reviewer
+    jobs: mpsc::UnboundedSender<Job>,
+    /// Requests running the engine at once, the others wait for a permit
+    engines: Semaphore,
+    /// Review time unless the review budget is limited otherwise
+    max_time: Duration,
+}
+
+impl Server {
+    fn engine(&self) -> Result<config::Engine> {
+        let engine = self.config.engine.clone();
+        engine.ok_or_eyre("No engine configuration")
+    }
+
+    /// Runs the review jobs, every one in its own local task. The review is aborted when its
+    /// result is not awaited anymore (the client disconnected).
+    async fn reviewer(self: Arc<Self>, mut jobs: mpsc::UnboundedReceiver<Job>) {
+        while let Some(job) = jobs.recv().await {
+            let server = self.clone();
+            spawn_local(async move {
+                let Job {
+                    knowledge,
+                    rev,
+                    events,
+                    mut reply,
+                } = job;
+                let review = async {
+                    let engine = server.engine()?;
+                    review_with(knowledge, engine, &rev, &server.shutdown, &events).await
+                };
+
+                tokio::select! {
+                    review = review => {
+                        let _ = reply.send(review);
+                    }
+                    _ = reply.closed() => debug!("Review abandoned"),
+                }
+            });
+        }
+    }
+
+    async fn review(
+        &self,
+        request: &ReviewRequest,
+        events: &Events,
+    ) -> Result<Knowledge, ApiError> {
+        let (reply, result) = oneshot::channel();
+        let job = Job {
+            knowledge: request.knowledge().await?,
+            rev: request.rev(&self.config.rev, self.max_time)?,
+            events: events.clone(),
+            reply,
+        };
+
+        let _permit = self.engines.acquire().await.wrap_err("Server stopped")?;
+        self.jobs.send(job).map_err(|_| eyre!("Reviewer stopped"))?;
+        let knowledge = result.await.map_err(|_| eyre!("Review aborted"))??;
+        Ok(knowledge)
+    }
+
+    /// Streams the review events to the socket, finishing with the review result
+    async fn review_live(&self, socket: &mut WebSocket) -> Result<()> {
+        let request = match socket.recv().await {
+            Some(Ok(Message::Text(request))) => request,
+            Some(Ok(_)) => bail!("Review request expected"),
+            Some(Err(err)) => return Err(err.into()),
+            None => return Ok(()),
+        };
+        let request: ReviewRequest =
+            serde_json::from_str(&request).wrap_err("Invalid review request")?;
+        debug!(?request, "Live review");
+
+        let events = Events::new();
+        let mut receiver = events.subscribe();
+        let review = self.review(&request, &events);
+        tokio::pin!(review);
+
+        let knowledge = loop {
+            tokio::select! {
+                knowledge = &mut review => break knowledge,
+                event = receiver.recv() => match event {
+                    Ok(event) => {
+                        let event = serde_json::to_string(&event)?;
+                        socket.send(Message::Text(event.into())).await?;
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "Socket lagging, events skipped");
+                    }
+                    Err(RecvError::Closed) => (),
+                },
+            }
+        };
+
+        while let Ok(event) = receiver.try_recv() {
+            let event = serde_json::to_string(&event)?;
+            socket.send(Message::Text(event.into())).await?;
+        }
+
+        let result = match knowledge {
+            Ok(knowledge) => {
+                let response = ReviewResponse::new(knowledge).await?;
+                json!({ "event": "result", "review": response })
+            }
+            Err(err) => json!({ "event": "error", "error": err.message }),
+        };
+        socket
+            .send(Message::Text(result.to_string().into()))
+            .await?;
+        Ok(())
+    }
+
+    async fn analyse(&self, request: AnalyseRequest) -> Result<AnalyseResponse, ApiError> {
+        let root = request.fen.as_deref().map(parse_chess).transpose();
+        let root = root.map_err(ApiError::bad_request)?.unwrap_or_default();
+
+        let mut position = root.clone();
+        let mut moves: Vec<Move> = vec![];
+        for mov in &request.moves {
+            let mov = mov
+                .parse::<UciMove>()
+                .ok()
+                .and_then(|mov| mov.to_move(&position).ok())
+                .ok_or_else(|| ApiError::bad_request(format!("Illegal move {mov}")))?;
+            position.play_unchecked(&mov);
+            moves.push(mov);
+        }
+
+        let limits = Limits {
+            depth: request.depth.or(self.config.rev.depth),
+            time: time_limit(request.time)?.or(self.config.rev.time),
+            ..Default::default()
+        };
+        if limits.is_infinite() {
+            return Err(ApiError::bad_request("Depth or time limit is required"));
+        }
+
+        let _permit = self.engines.acquire().await.wrap_err("Server stopped")?;
+        let mut engine = uci::Engine::run(self.engine()?).await?;
+        let mut stream = engine.search(root, &moves, limits).await?;
+        let mut last = None;
+        while let Some(info) = stream.info().await? {
+            if info.multipv == 1 {
+                last = Some(info);
+            }
+        }
+        let best = stream.best().await?;
+        engine.quit().await?;
+
+        Ok(AnalyseResponse {
+            fen: Fen::from_position(position.clone(), EnPassantMode::Legal).to_string(),
+            best: best.to_string(),
+            eval: last.as_ref().map(|info| info.score.pov(position.turn())),
+            depth: last.as_ref().map_or(0, |info| info.depth),
+            nodes: last.as_ref().map_or(0, |info| info.nodes),
+            pv: last.map_or(vec![], |info| {
+                info.line.iter().map(UciMove::to_string).collect()
+            }),
+        })
+    }
+}
+
+async fn review(
+    State(server): State<Arc<Server>>,
+    Json(request): Json<ReviewRequest>,
+) -> Result<Json<ReviewResponse>, ApiError> {
+    debug!(?request, "Review");
+    let knowledge = server.review(&request, &Events::new()).await?;
+    Ok(Json(ReviewResponse::new(knowledge).await?))
+}
+
+async fn review_live(State(server): State<Arc<Server>>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(|mut socket| async move {
+        if let Err(err) = server.review_live(&mut socket).await {
+            warn!(%err, "Live review failed");
+            let error = json!({ "event": "error", "error": format!("{err:#}") });
+            let _ = socket.send(Message::Text(error.to_string().into())).await;
+        }
+    })
+}
+
+async fn analyse(
+    State(server): State<Arc<Server>>,
+    Json(request): Json<AnalyseRequest>,
+) -> Result<Json<AnalyseResponse>, ApiError> {
+    debug!(?request, "Analysis");
+    Ok(Json(server.analyse(request).await?))
+}
+
+/// Local HTTP/JSON API server. Every request runs its own engine instance, configured as the main
+/// `engine`.
+#[derive(Debug, StructOpt)]
+pub struct Serve {
+    /// Address to listen on
+    #[structopt(short, long, default_value = "127.0.0.1:7878")]
+    address: SocketAddr,
+    /// Requests served concurrently, the others wait. By default as many as the CPUs allow with
+    /// the engine's `Threads` option.
+    #[structopt(short, long)]
+    jobs: Option<NonZeroUsize>,
+    /// Review time in seconds when neither the request nor the `rev.budget` config limits the
+    /// review, so reviewed positions do not follow the engine lines for ever
+    #[structopt(long, default_value = "300")]
+    max_review_time: u64,
+}
+
+impl Serve {
+    #[instrument(skip(self, config), err)]
+    pub async fn run(self, config: Config) -> Result<()> {
+        info!(?self, "API server");
+        let mut shutdown = Shutdown::listen()?;
+
+        let engines = self.jobs.map_or_else(
+            || config::Engine::concurrency(&config.engine),
+            NonZeroUsize::get,
+        );
+        info!(engines, "Concurrent requests");
+
+        let (jobs, receiver) = mpsc::unbounded_channel();
+        let server = Arc::new(Server {
+            config,
+            shutdown: shutdown.clone(),
+            jobs,
+            engines: Semaphore::new(engines),
+            max_time: Duration::from_secs(self.max_review_time),
+        });
+        let local = LocalSet::new();
+        local.spawn_local(server.clone().reviewer(receiver));
+
+        let app = Router::new()
+            .route("/review", post(review))
+            .route("/review/live", get(review_live))
+            .route("/analyse", post(analyse))
+            .with_state(server);
+
+        let listener = TcpListener::bind(self.address)
+            .await
+            .wrap_err("While binding the server address")?;
+        info!(address = %self.address, "Listening");
+
+        let serve =
+            axum::serve(listener, app).with_graceful_shutdown(async move { shutdown.wait().await });
+        local.run_until(async { serve.await }).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_TIME: Duration = Duration::from_secs(300);
+
+    fn request(json: serde_json::Value) -> ReviewRequest {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn limits_required() {
+        let rev = request(json!({ "max_plies": 10 })).rev(&config::Rev::default(), MAX_TIME);
+        assert_eq!(rev.unwrap_err().status, StatusCode::BAD_REQUEST);
+
+        let config = config::Rev {
+            depth: Some(12),
+            ..Default::default()
+        };
+        let rev = request(json!({ "time": 0.5 }))
+            .rev(&config, MAX_TIME)
+            .unwrap();
+        assert_eq!(rev.depth, Some(12));
+        assert_eq!(rev.time, Some(Duration::from_millis(500)));
+
+        let rev = request(json!({ "time": -1.0 })).rev(&config, MAX_TIME);
+        assert_eq!(rev.unwrap_err().status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn budget_overridden() {
+        let mut config = config::Rev {
+            depth: Some(12),
+            ..Default::default()
+        };
+        config.budget.max_positions = Some(50);
+
+        let rev = request(json!({ "max_plies": 10, "max_time": 20.0 }))
+            .rev(&config, MAX_TIME)
+            .unwrap();
+        assert_eq!(rev.budget.max_plies, Some(10));
+        assert_eq!(rev.budget.max_positions, Some(50));
+        assert_eq!(rev.budget.max_time, Some(Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn unbudgeted_review_limited() {
+        let mut config = config::Rev {
+            depth: Some(12),
+            ..Default::default()
+        };
+        let rev = request(json!({})).rev(&config, MAX_TIME).unwrap();
+        assert_eq!(rev.budget.max_time, Some(MAX_TIME));
+
+        config.budget.max_nodes = Some(1_000_000);
+        let rev = request(json!({})).rev(&config, MAX_TIME).unwrap();
+        assert_eq!(rev.budget.max_time, None);
+    }
+}
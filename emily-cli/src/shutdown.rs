@@ -0,0 +1,120 @@
+//! Graceful shutdown on the termination signals
+
+use std::sync::LazyLock;
+use std::time::Duration;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tracing::{error, instrument, warn};
+
+use crate::Result;
+
+/// Time the engines are given to be killed on the immediate termination
+const KILL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Immediate termination notification for the engine processes (see `Shutdown::terminated`)
+static TERMINATE: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::Sender::new(false));
+
+/// Shutdown request notification. The first Ctrl-C (or SIGTERM on Unix) requests the graceful
+/// shutdown, the second one kills the engines and terminates the process immediately.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    requested: watch::Receiver<bool>,
+    /// Time the shutdown is requested at regardless of the signals
+    deadline: Option<Instant>,
+}
+
+impl Shutdown {
+    /// Starts listening for the termination signals
+    #[instrument(err)]
+    pub fn listen() -> Result<Self> {
+        #[cfg(unix)]
+        let mut terminate = signal(SignalKind::terminate())?;
+        let (tx, requested) = watch::channel(false);
+
+        tokio::spawn(async move {
+            let mut signals = 0;
+            loop {
+                #[cfg(unix)]
+                let terminated = terminate.recv();
+                #[cfg(not(unix))]
+                let terminated = std::future::pending::<Option<()>>();
+
+                tokio::select! {
+                    interrupted = tokio::signal::ctrl_c() => {
+                        if let Err(err) = interrupted {
+                            error!(%err, "Cannot listen for Ctrl-C");
+                            return;
+                        }
+                    }
+                    _ = terminated => (),
+                }
+
+                signals += 1;
+                if signals > 1 {
+                    error!("Terminated");
+                    // Engines listen until they are killed
+                    TERMINATE.send_replace(true);
+                    let _ = tokio::time::timeout(KILL_TIMEOUT, TERMINATE.closed()).await;
+                    std::process::exit(130);
+                }
+
+                warn!("Shutdown requested, finishing. Repeat to terminate immediately.");
+                let _ = tx.send(true);
+            }
+        });
+
+        Ok(Self {
+            requested,
+            deadline: None,
+        })
+    }
+
+    /// Waits until the process is terminated immediately by the repeated signal. Exiting the
+    /// process skips the destructors, so the child processes (running in their own process
+    /// groups) have to be killed on this notification.
+    pub async fn terminated() {
+        let mut terminate = TERMINATE.subscribe();
+        let _ = terminate.wait_for(|terminated| *terminated).await;
+    }
+
+    /// Shutdown requested also after the `timeout` (if any) elapses
+    pub fn with_timeout(&self, timeout: Option<Duration>) -> Self {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        Self {
+            requested: self.requested.clone(),
+            deadline: self.deadline.into_iter().chain(deadline).min(),
+        }
+    }
+
+    /// Checks if the shutdown was requested
+    pub fn is_requested(&self) -> bool {
+        *self.requested.borrow()
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Waits until the shutdown is requested. Never finishes if the shutdown cannot be requested
+    /// anymore.
+    pub async fn wait(&mut self) {
+        let deadline = self.deadline;
+        let timeout = async move {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(timeout);
+
+        let closed = tokio::select! {
+            requested = self.requested.wait_for(|requested| *requested) => requested.is_err(),
+            _ = &mut timeout => false,
+        };
+        if closed {
+            timeout.await;
+        }
+    }
+}
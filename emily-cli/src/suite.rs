@@ -0,0 +1,355 @@
+//! EPD test suites (eg. WAC or STS) - positions with the best (`bm`) or avoided (`am`) moves,
+//! optionally scored by the `c0` comment (STS style `Nc4=10, Nd5=5`)
+
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{bail, ensure, eyre, Context, OptionExt};
+use color_eyre::Result;
+use derivative::Derivative;
+use shakmaty::fen::Epd;
+use shakmaty::san::{San, SanPlus};
+use shakmaty::{CastlingMode, Chess, Move};
+use structopt::StructOpt;
+use tracing::{debug, info, instrument, warn};
+
+use crate::adapters::board::BoardExt;
+use crate::adapters::debug::{DFenExt, LineExt};
+use crate::shutdown::Shutdown;
+use crate::uci;
+use crate::Config;
+
+/// Single test position
+#[derive(Derivative)]
+#[derivative(Debug)]
+struct Test {
+    id: String,
+    #[derivative(Debug(format_with = "DFenExt::fmt"))]
+    position: Chess,
+    /// Best moves (`bm`), any of them solves the test
+    #[derivative(Debug(format_with = "LineExt::fmt"))]
+    best: Vec<Move>,
+    /// Avoided moves (`am`), any other move solves the test
+    #[derivative(Debug(format_with = "LineExt::fmt"))]
+    avoid: Vec<Move>,
+    /// Points for the moves (`c0`), if given
+    #[derivative(Debug = "ignore")]
+    points: Vec<(Move, u32)>,
+}
+
+/// Splits the EPD operations by `;`, respecting the quoted operands
+fn split_operations(operations: &str) -> Vec<&str> {
+    let mut result = vec![];
+    let mut quoted = false;
+    let mut start = 0;
+
+    for (idx, c) in operations.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                result.push(operations[start..idx].trim());
+                start = idx + 1;
+            }
+            _ => (),
+        }
+    }
+
+    result.push(operations[start..].trim());
+    result.retain(|op| !op.is_empty());
+    result
+}
+
+impl Test {
+    fn parse_move(position: &Chess, san: &str) -> Result<Move> {
+        let san: SanPlus = san
+            .parse()
+            .wrap_err_with(|| format!("Invalid move {san}"))?;
+        Ok(san.san.to_move(position)?)
+    }
+
+    /// Parses the `Nc4=10, Nd5=5` points comment
+    fn parse_points(position: &Chess, comment: &str) -> Result<Vec<(Move, u32)>> {
+        comment
+            .split(',')
+            .map(|item| {
+                let (san, points) = item
+                    .trim()
+                    .split_once('=')
+                    .ok_or_eyre("Invalid points comment")?;
+                Ok((
+                    Self::parse_move(position, san)?,
+                    points.parse().wrap_err("Invalid points")?,
+                ))
+            })
+            .collect()
+    }
+
+    /// Parses the EPD line, `no` is used as the id if the `id` operation is missing
+    fn parse(no: usize, line: &str) -> Result<Self> {
+        let fields: Vec<_> = line.splitn(5, char::is_whitespace).collect();
+        ensure!(fields.len() >= 4, "Incomplete EPD");
+        let epd: Epd = fields[..4].join(" ").parse().wrap_err("Invalid EPD")?;
+        let position: Chess = epd.into_position(CastlingMode::Standard)?;
+
+        let mut test = Self {
+            id: no.to_string(),
+            position,
+            best: vec![],
+            avoid: vec![],
+            points: vec![],
+        };
+
+        for operation in split_operations(fields.get(4).copied().unwrap_or_default()) {
+            let (opcode, operands) = operation.split_once(' ').unwrap_or((operation, ""));
+            let operands = operands.trim();
+            match opcode {
+                "id" => test.id = operands.trim_matches('"').to_owned(),
+                "bm" | "am" => {
+                    let moves = operands
+                        .split_whitespace()
+                        .map(|san| Self::parse_move(&test.position, san))
+                        .collect::<Result<Vec<_>>>()?;
+                    match opcode {
+                        "bm" => test.best = moves,
+                        _ => test.avoid = moves,
+                    }
+                }
+                "c0" => {
+                    // Only the points comments are meaningful, other comments are ignored
+                    let comment = operands.trim_matches('"');
+                    if let Ok(points) = Self::parse_points(&test.position, comment) {
+                        test.points = points;
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        if test.best.is_empty() && test.avoid.is_empty() {
+            bail!("Neither `bm` nor `am` given");
+        }
+        Ok(test)
+    }
+
+    /// Checks if the move solves the test
+    fn solves(&self, mov: &Move) -> bool {
+        match self.best.is_empty() {
+            true => !self.avoid.contains(mov),
+            false => self.best.contains(mov),
+        }
+    }
+
+    /// Points scored by the move and the maximum points of the test
+    fn score(&self, mov: &Move) -> (u32, u32) {
+        if self.points.is_empty() {
+            return (self.solves(mov) as u32, 1);
+        }
+
+        let points = self
+            .points
+            .iter()
+            .find(|(candidate, _)| candidate == mov)
+            .map_or(0, |(_, points)| *points);
+        let max = self.points.iter().map(|(_, points)| *points).max();
+        (points, max.unwrap_or(0))
+    }
+
+    /// Expected moves for the report
+    fn expected(&self) -> String {
+        let (prefix, moves) = match self.best.is_empty() {
+            true => ("not ", &self.avoid),
+            false => ("", &self.best),
+        };
+        let moves: Vec<_> = moves
+            .iter()
+            .map(|mov| San::from_move(&self.position, mov).to_string())
+            .collect();
+        format!("{prefix}{}", moves.join(" "))
+    }
+}
+
+/// Result of the single test
+#[derive(Debug)]
+struct Outcome {
+    best: Move,
+    solved: bool,
+    /// Time since the engine settled on the solution
+    solved_in: Option<Duration>,
+    depth: u8,
+    points: u32,
+    max: u32,
+}
+
+/// EPD test suite runner. Every position is searched by the engine within the limit (from the
+/// `epd` config unless given) and the found move is checked against the `bm`/`am` operations.
+#[derive(Debug, StructOpt)]
+pub struct Suite {
+    /// EPD files with the tests
+    #[structopt(required = true)]
+    input: Vec<PathBuf>,
+    /// Engine name, the main `engine` by default
+    #[structopt(short, long)]
+    engine: Option<String>,
+    /// Search depth limit
+    #[structopt(short, long)]
+    depth: Option<u8>,
+    /// Search time limit in seconds
+    #[structopt(short, long)]
+    time: Option<f64>,
+}
+
+impl Suite {
+    /// Loads all the tests, invalid ones are reported and skipped
+    async fn load(&self) -> Result<Vec<Test>> {
+        let mut tests = vec![];
+        for path in &self.input {
+            let suite = tokio::fs::read_to_string(path)
+                .await
+                .wrap_err_with(|| format!("While reading {}", path.display()))?;
+            let lines = suite
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'));
+            for (idx, line) in lines.enumerate() {
+                match Test::parse(idx + 1, line) {
+                    Ok(test) => tests.push(test),
+                    Err(err) => warn!(%err, file = ?path, line, "Invalid test, skipping"),
+                }
+            }
+        }
+
+        ensure!(!tests.is_empty(), "No tests in the suites");
+        Ok(tests)
+    }
+
+    /// Runs the single test. Returns `None` if interrupted.
+    #[instrument(skip_all, fields(test = test.id), err)]
+    async fn run_test(
+        engine: &mut uci::Engine,
+        test: &Test,
+        depth: Option<u8>,
+        time: Option<Duration>,
+        shutdown: &mut Shutdown,
+    ) -> Result<Option<Outcome>> {
+        let started = Instant::now();
+        let mut stream = engine.go(test.position.clone(), &[], depth, time).await?;
+        let mut solved_at = None;
+        let mut last_depth = 0;
+
+        loop {
+            let info = tokio::select! {
+                info = stream.info() => info?,
+                _ = shutdown.wait() => {
+                    stream.stop_wait().await?;
+                    return Ok(None);
+                },
+            };
+
+            let Some(info) = info else {
+                break;
+            };
+            if info.multipv > 1 {
+                continue;
+            }
+
+            last_depth = info.depth;
+            let solves = info
+                .line
+                .first()
+                .and_then(|mov| mov.to_move(&test.position).ok())
+                .is_some_and(|mov| test.solves(&mov));
+            match (solves, solved_at) {
+                (true, None) => solved_at = Some(started.elapsed()),
+                (false, _) => solved_at = None,
+                _ => (),
+            }
+        }
+
+        let best = stream.best().await?;
+        let best = best.to_move(&test.position).map_err(|_| {
+            let board = test.position.d_board().colors(false);
+            eyre!("Engine played illegal move {best} in {board:?}")
+        })?;
+        let solved = test.solves(&best);
+        let (points, max) = test.score(&best);
+
+        Ok(Some(Outcome {
+            best,
+            solved,
+            solved_in: solved_at.filter(|_| solved),
+            depth: last_depth,
+            points,
+            max,
+        }))
+    }
+
+    #[instrument(skip(self, config), err)]
+    pub async fn run(self, config: Config) -> Result<()> {
+        info!(?self, "EPD test suite");
+        let mut shutdown = Shutdown::listen()?;
+
+        let depth = self.depth.or(config.epd.depth);
+        let time = self
+            .time
+            .map(Duration::try_from_secs_f64)
+            .transpose()
+            .wrap_err("Invalid time limit")?
+            .or(config.epd.time);
+        ensure!(
+            depth.is_some() || time.is_some(),
+            "Depth or time limit is required"
+        );
+
+        let engine_config = match &self.engine {
+            Some(name) => config.engine_named(name),
+            None => config.engine.as_ref(),
+        };
+        let engine_config = engine_config.ok_or_eyre("No engine configuration")?;
+        let tests = self.load().await?;
+        info!(tests = tests.len(), "Tests loaded");
+
+        let mut engine = uci::Engine::run(engine_config.clone()).await?;
+        let started = Instant::now();
+        let (mut solved, mut points, mut max) = (0, 0, 0);
+        let mut run = 0;
+
+        for test in &tests {
+            engine.new_game().await?;
+            let Some(outcome) =
+                Self::run_test(&mut engine, test, depth, time, &mut shutdown).await?
+            else {
+                warn!("Test suite interrupted");
+                break;
+            };
+            debug!(?test, ?outcome, "Test finished");
+
+            run += 1;
+            solved += outcome.solved as usize;
+            points += outcome.points;
+            max += outcome.max;
+
+            let best = San::from_move(&test.position, &outcome.best);
+            let status = match (outcome.solved, outcome.solved_in) {
+                (true, Some(time)) => format!("solved in {:.2}s", time.as_secs_f64()),
+                (true, None) => "solved".to_owned(),
+                (false, _) => "unsolved".to_owned(),
+            };
+            println!(
+                "{}: {status}, played {best} (expected {}), depth {}, {}/{} points",
+                test.id,
+                test.expected(),
+                outcome.depth,
+                outcome.points,
+                outcome.max
+            );
+        }
+
+        engine.quit().await?;
+
+        println!(
+            "Solved {solved}/{run}, score {points}/{max}, total time {:.1}s",
+            started.elapsed().as_secs_f64()
+        );
+        Ok(())
+    }
+}
@@ -16,8 +16,9 @@ use tracing::{error, info, instrument, trace, warn};
 
 use self::proto::{InfoStream, Protocol};
 use crate::adapters::debug::{DFenExt, FlatOptExt, LineExt};
+use crate::shutdown::Shutdown;
 
-pub use self::proto::Score;
+pub use self::proto::{Clock, Command, Info, Limits, Msg, Perft, Score};
 
 mod proto;
 
@@ -67,6 +68,9 @@ impl Engine {
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .kill_on_drop(true);
+        // Terminal interrupts are handled by emily, the engine is stopped gracefully
+        #[cfg(unix)]
+        command.process_group(0);
 
         if let Some(pwd) = &config.pwd {
             command.current_dir(pwd);
@@ -107,12 +111,19 @@ impl Engine {
         }
         info!(pid = process.id(), "Engine started");
 
-        let proto = Protocol::new(stdin, stdout);
+        let proto = Protocol::new(stdin, stdout, config.protocol);
 
         let task = spawn(async move {
-            match process.wait().await {
-                Ok(code) => info!(%code, "Engine exited"),
-                Err(err) => error!(?err, "While running engine"),
+            tokio::select! {
+                code = process.wait() => match code {
+                    Ok(code) => info!(%code, "Engine exited"),
+                    Err(err) => error!(?err, "While running engine"),
+                },
+                _ = Shutdown::terminated() => {
+                    if let Err(err) = process.kill().await {
+                        error!(?err, "While killing engine");
+                    }
+                }
             }
         });
 
@@ -129,6 +140,25 @@ impl Engine {
         Ok(engine)
     }
 
+    /// Engine author, if reported by the engine
+    pub fn author(&self) -> Option<&str> {
+        self.proto.author()
+    }
+
+    /// Options reported by the engine, as the `option` arguments
+    pub fn options(&self) -> &[String] {
+        self.proto.options()
+    }
+
+    /// Sets the engine option, waiting until the engine applies it
+    #[instrument(err)]
+    pub async fn set_option(&mut self, option: &str, value: &str) -> Result<()> {
+        self.proto
+            .set_option(option.to_owned(), value.to_owned())
+            .await?;
+        self.proto.wait_ready().await
+    }
+
     #[instrument(err)]
     pub async fn new_game(&mut self) -> Result<()> {
         self.proto.new_game().await?;
@@ -142,13 +172,50 @@ impl Engine {
         moves: &[Move],
         depth: Option<u8>,
         time: Option<Duration>,
-    ) -> Result<InfoStream> {
+    ) -> Result<InfoStream<'_>> {
         let fen = Fen::from_position(fen, EnPassantMode::Always);
         let moves = moves.iter().map(UciMove::from_standard).collect();
         self.proto.position(Some(fen), moves).await?;
         self.proto.go(depth, time).await
     }
 
+    /// Searches the move to play in the game, on the `clock` or limited by `nodes`
+    #[instrument(skip(fen, moves, nodes), fields(fen=?fen.d_fen(), moves=?moves.d_line(), nodes=?nodes.d_opt()), err)]
+    pub async fn play(
+        &mut self,
+        fen: Chess,
+        moves: &[Move],
+        clock: Option<Clock>,
+        nodes: Option<u64>,
+    ) -> Result<InfoStream<'_>> {
+        let fen = Fen::from_position(fen, EnPassantMode::Always);
+        let moves = moves.iter().map(UciMove::from_standard).collect();
+        self.proto.position(Some(fen), moves).await?;
+        self.proto.go_clock(clock, nodes).await
+    }
+
+    /// Searches the position with any limits
+    #[instrument(skip(fen, moves), fields(fen=?fen.d_fen(), moves=?moves.d_line()), err)]
+    pub async fn search(
+        &mut self,
+        fen: Chess,
+        moves: &[Move],
+        limits: Limits,
+    ) -> Result<InfoStream<'_>> {
+        let fen = Fen::from_position(fen, EnPassantMode::Always);
+        let moves = moves.iter().map(UciMove::from_standard).collect();
+        self.proto.position(Some(fen), moves).await?;
+        self.proto.search(limits).await
+    }
+
+    /// Counts the leaf nodes of the position to the `depth`, divided by the root moves
+    #[instrument(skip(fen), fields(fen=?fen.d_fen()), err)]
+    pub async fn perft(&mut self, fen: Chess, depth: u8) -> Result<Perft> {
+        let fen = Fen::from_position(fen, EnPassantMode::Always);
+        self.proto.position(Some(fen), vec![]).await?;
+        self.proto.perft(depth).await
+    }
+
     #[instrument(err)]
     pub async fn quit(mut self) -> Result<()> {
         self.proto.quit().await
@@ -8,11 +8,20 @@ use derivative::Derivative;
 use serde::{Deserialize, Serialize};
 use shakmaty::fen::Fen;
 use shakmaty::uci::UciMove;
+use shakmaty::Color;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
 use tokio::process::{ChildStdin, ChildStdout};
 use tracing::{debug, instrument, trace, warn, Level};
 
+use self::cecp::Cecp;
 use crate::adapters::debug::{DFenExt, FlatOptExt, LineExt};
+use crate::config;
+
+mod cecp;
+
+/// Time the engine has to report the first perft result. Engines not supporting perft usually
+/// ignore the command or start an infinite search.
+const PERFT_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Derivative)]
 #[derivative(Debug)]
@@ -22,34 +31,66 @@ pub struct Protocol {
     #[derivative(Debug = "ignore")]
     stdout: Lines<BufReader<ChildStdout>>,
     engine: String,
+    /// Engine author, if reported
+    author: Option<String>,
+    /// Options reported by the engine, as the `option` arguments
+    options: Vec<String>,
+    /// Translation for the CECP engines
+    cecp: Option<Cecp>,
 }
 
 impl Protocol {
-    pub fn new(stdin: ChildStdin, stdout: ChildStdout) -> Self {
+    pub fn new(stdin: ChildStdin, stdout: ChildStdout, protocol: config::Protocol) -> Self {
         Self {
             stdin,
             stdout: BufReader::new(stdout).lines(),
             engine: String::new(),
+            author: None,
+            options: vec![],
+            cecp: (protocol == config::Protocol::Cecp).then(Cecp::default),
         }
     }
 
-    #[instrument(err)]
-    async fn send(&mut self, command: Command) -> Result<()> {
-        let mut command = command.to_string();
-        command.push('\n');
+    async fn write(&mut self, line: String) -> Result<()> {
+        let mut line = line;
+        line.push('\n');
 
         self.stdin
-            .write_all(command.as_bytes())
+            .write_all(line.as_bytes())
             .await
             .wrap_err("While writting to engine")?;
 
-        trace!("UCI send: {}", command.trim());
+        trace!("Engine send: {}", line.trim());
+        Ok(())
+    }
+
+    /// Sends the lines the CECP translation produced
+    async fn flush(&mut self) -> Result<()> {
+        let lines = self.cecp.as_mut().map(Cecp::outgoing).unwrap_or_default();
+        for line in lines {
+            self.write(line).await?;
+        }
         Ok(())
     }
 
+    #[instrument(err)]
+    async fn send(&mut self, command: Command) -> Result<()> {
+        match &mut self.cecp {
+            Some(cecp) => {
+                cecp.command(command)?;
+                self.flush().await
+            }
+            None => self.write(command.to_string()).await,
+        }
+    }
+
     #[instrument(err, ret(level=Level::TRACE))]
     async fn recv(&mut self) -> Result<Msg> {
         loop {
+            if let Some(msg) = self.cecp.as_mut().and_then(Cecp::incoming) {
+                return Ok(msg);
+            }
+
             let line = self
                 .stdout
                 .next_line()
@@ -58,10 +99,20 @@ impl Protocol {
                 .ok_or_eyre("Engine stdout closed")?;
 
             let line = line.trim();
-            if !line.is_empty() {
-                trace!("UCI recv: {}", line);
-                if let Some(msg) = Msg::parse(line) {
-                    return Ok(msg);
+            if line.is_empty() {
+                continue;
+            }
+
+            trace!("Engine recv: {}", line);
+            match &mut self.cecp {
+                Some(cecp) => {
+                    cecp.line(line);
+                    self.flush().await?;
+                }
+                None => {
+                    if let Some(msg) = Msg::parse(line) {
+                        return Ok(msg);
+                    }
                 }
             }
         }
@@ -84,10 +135,30 @@ impl Protocol {
         loop {
             use Msg::*;
 
-            match self.recv().await? {
-                Id { name: Some(n), .. } => {
-                    self.engine = n;
+            let timeout = self.cecp.as_ref().and_then(Cecp::features_timeout);
+            let msg = match timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, self.recv()).await {
+                    Ok(msg) => msg?,
+                    // Engine not finishing the features (eg. `protover 1` one) is assumed ready
+                    Err(_) => {
+                        if let Some(cecp) = &mut self.cecp {
+                            cecp.features_done();
+                        }
+                        self.flush().await?;
+                        continue;
+                    }
+                },
+                None => self.recv().await?,
+            };
+
+            match msg {
+                Id { name, author } => {
+                    if let Some(name) = name {
+                        self.engine = name;
+                    }
+                    self.author = author.or(self.author.take());
                 }
+                Option(option) => self.options.push(option),
                 UciOk => break,
                 _ => (),
             }
@@ -96,6 +167,16 @@ impl Protocol {
         Ok(())
     }
 
+    /// Engine author, if reported
+    pub fn author(&self) -> Option<&str> {
+        self.author.as_deref()
+    }
+
+    /// Options reported by the engine, as the `option` arguments
+    pub fn options(&self) -> &[String] {
+        &self.options
+    }
+
     #[instrument(err)]
     pub async fn wait_ready(&mut self) -> Result<()> {
         self.send(Command::IsReady).await?;
@@ -118,8 +199,38 @@ impl Protocol {
 
     /// Starts the game analysis
     #[instrument(skip(depth, time), fields(depth=?depth.d_opt(), time=?time.d_opt()), err)]
-    pub async fn go(&mut self, depth: Option<u8>, time: Option<Duration>) -> Result<InfoStream> {
-        self.send(Command::Go { depth, time }).await?;
+    pub async fn go(
+        &mut self,
+        depth: Option<u8>,
+        time: Option<Duration>,
+    ) -> Result<InfoStream<'_>> {
+        self.search(Limits {
+            depth,
+            time,
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Starts searching the move in the game played on the clock (or with the nodes limit)
+    #[instrument(skip(clock, nodes), fields(clock=?clock.d_opt(), nodes=?nodes.d_opt()), err)]
+    pub async fn go_clock(
+        &mut self,
+        clock: Option<Clock>,
+        nodes: Option<u64>,
+    ) -> Result<InfoStream<'_>> {
+        self.search(Limits {
+            clock,
+            nodes,
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Starts the search with any limits, no limits means the infinite search
+    #[instrument(err)]
+    pub async fn search(&mut self, limits: Limits) -> Result<InfoStream<'_>> {
+        self.send(Command::Go(limits)).await?;
 
         Ok(InfoStream {
             proto: self,
@@ -127,12 +238,59 @@ impl Protocol {
         })
     }
 
+    /// Counts the leaf nodes of the legal moves tree (`go perft`, not a standard UCI command, but
+    /// supported by many engines). The engine is reported as not supporting perft if it searches
+    /// instead, or doesn't report any result within the `PERFT_TIMEOUT`.
+    #[instrument(err)]
+    pub async fn perft(&mut self, depth: u8) -> Result<Perft> {
+        self.send(Command::Perft(depth)).await?;
+
+        let mut moves = vec![];
+        loop {
+            // Once the engine started reporting, the perft can take as long as it needs
+            let msg = match moves.is_empty() {
+                true => tokio::time::timeout(PERFT_TIMEOUT, self.recv()).await.ok(),
+                false => Some(self.recv().await),
+            };
+
+            match msg.transpose()? {
+                Some(Msg::PerftMove(mov, nodes)) => moves.push((mov, nodes)),
+                Some(Msg::PerftNodes(nodes)) => return Ok(Perft { moves, nodes }),
+                Some(Msg::BestMove(_)) => bail!("Engine doesn't support perft"),
+                Some(Msg::Info(_)) | None => {
+                    self.abort_perft().await?;
+                    bail!("Engine doesn't support perft");
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Stops the search the engine started instead of the perft, and waits until it is ready for
+    /// the next commands
+    async fn abort_perft(&mut self) -> Result<()> {
+        debug!("Engine doesn't report perft, stopping");
+        self.send(Command::Stop).await?;
+        self.send(Command::IsReady).await?;
+        while !matches!(self.recv().await?, Msg::ReadyOk) {}
+        Ok(())
+    }
+
     #[instrument(err)]
     pub async fn quit(&mut self) -> Result<()> {
         self.send(Command::Quit).await
     }
 }
 
+/// Engine perft result
+#[derive(Debug)]
+pub struct Perft {
+    /// Leaf nodes after every root move
+    pub moves: Vec<(UciMove, u64)>,
+    /// Total leaf nodes
+    pub nodes: u64,
+}
+
 /// Ongoing engine analysis after the `go` command. It allows to retrieve the `info` position
 /// information and waiting for a final best move information.
 ///
@@ -149,7 +307,6 @@ pub struct InfoStream<'a> {
 impl InfoStream<'_> {
     /// Waits for the best move ignoring the info command. After this analysis is fully complete so
     /// it consumes `self`.
-    #[allow(unused)]
     pub async fn best(self) -> Result<UciMove> {
         // `bestmove` command was already met, returning cached move.
         if let Some(best) = self.best {
@@ -167,14 +324,17 @@ impl InfoStream<'_> {
     /// calling this the caller should still wait for `info` function returning `None` or call the
     /// `best` method to ensure the whole analysis is consumed. Alternatievely user can synchronize
     /// with the I/O using the `Protocol::wait_ready`.
-    #[allow(unused)]
     pub async fn stop(&mut self) -> Result<()> {
         self.proto.send(Command::Stop).await
     }
 
+    /// Switches the pondering search to the normal one, the opponent played the expected move
+    pub async fn ponder_hit(&mut self) -> Result<()> {
+        self.proto.send(Command::PonderHit).await
+    }
+
     /// Stops the analysis as soon as possible and wait for it finishes leaving the communication
     /// with engine in-sync. Ignores remaining `info` messages.
-    #[allow(unused)]
     pub async fn stop_wait(mut self) -> Result<UciMove> {
         self.stop().await?;
         self.best().await
@@ -197,10 +357,62 @@ impl InfoStream<'_> {
     }
 }
 
-/// Command send to the engine
+/// Remaining time and increments of both sides in the game played on the clock
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Clock {
+    pub wtime: Duration,
+    pub btime: Duration,
+    pub winc: Duration,
+    pub binc: Duration,
+}
+
+/// Search limits of the `go` command
+#[derive(Derivative, Default, Clone)]
+#[derivative(Debug)]
+pub struct Limits {
+    /// Limit depth search
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub depth: Option<u8>,
+    /// Limit search time
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub time: Option<Duration>,
+    /// Game clock the engine manages its time on
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub clock: Option<Clock>,
+    /// Limit searched nodes
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub nodes: Option<u64>,
+    /// Search for the mate in the moves
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub mate: Option<u8>,
+    /// Moves to the next time control of the `clock`
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub moves_to_go: Option<u32>,
+    /// Root moves the search is restricted to, all the moves if empty
+    #[derivative(Debug(format_with = "LineExt::fmt"))]
+    pub search_moves: Vec<UciMove>,
+    /// Search in the opponent's time, finished by `ponderhit` or `stop`
+    pub ponder: bool,
+    /// Search until stopped, regardless of the other limits
+    pub infinite: bool,
+}
+
+impl Limits {
+    /// Infinite search, until stopped
+    pub fn is_infinite(&self) -> bool {
+        self.infinite
+            || (self.depth.is_none()
+                && self.time.is_none()
+                && self.clock.is_none()
+                && self.nodes.is_none()
+                && self.mate.is_none())
+    }
+}
+
+/// Command send to the engine (or received from the GUI when emily is the engine)
 #[derive(Derivative)]
 #[derivative(Debug)]
-enum Command {
+pub enum Command {
     /// Intialize UCI mode
     Uci,
     /// Set debug mode
@@ -221,21 +433,114 @@ enum Command {
         line: Vec<UciMove>,
     },
     /// Start evaluation
-    Go {
-        /// Limit depth search
-        #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
-        depth: Option<u8>,
-        /// Limit search time
-        #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
-        time: Option<Duration>,
-    },
+    Go(Limits),
+    /// The opponent played the move pondered on
+    PonderHit,
+    /// Count the leaf nodes to the depth
+    Perft(u8),
     /// Stop engine evaluation as soon as possible
-    #[allow(unused)]
     Stop,
     /// Gracefully quit
     Quit,
 }
 
+impl Command {
+    /// Parses the `position` arguments
+    fn parse_position<'a>(mut tokens: impl Iterator<Item = &'a str>) -> Result<Self> {
+        let fen = match tokens.next() {
+            Some("startpos") => {
+                tokens.next().filter(|token| *token == "moves");
+                None
+            }
+            Some("fen") => {
+                let fen: Vec<_> = tokens
+                    .by_ref()
+                    .take_while(|token| *token != "moves")
+                    .collect();
+                Some(fen.join(" ").parse().wrap_err("Invalid FEN")?)
+            }
+            _ => bail!("Invalid position"),
+        };
+
+        let line = tokens
+            .map(|mov| mov.parse().wrap_err_with(|| format!("Invalid move {mov}")))
+            .collect::<Result<_>>()?;
+        Ok(Self::Position { fen, line })
+    }
+
+    /// Parses the `go` arguments. Unknown tokens are ignored.
+    fn parse_go<'a>(tokens: impl Iterator<Item = &'a str>) -> Result<Self> {
+        fn value<'a, T: std::str::FromStr>(
+            token: &str,
+            tokens: &mut impl Iterator<Item = &'a str>,
+        ) -> Result<T> {
+            tokens
+                .next()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| color_eyre::eyre::eyre!("Invalid {token} value"))
+        }
+
+        let mut tokens = tokens.peekable();
+        let mut limits = Limits::default();
+        let mut clock = Clock::default();
+        let mut clocked = false;
+        let ms = |ms: u64| Duration::from_millis(ms);
+
+        while let Some(token) = tokens.next() {
+            match token {
+                "depth" => limits.depth = Some(value(token, &mut tokens)?),
+                "movetime" => limits.time = Some(ms(value(token, &mut tokens)?)),
+                "nodes" => limits.nodes = Some(value(token, &mut tokens)?),
+                "mate" => limits.mate = Some(value(token, &mut tokens)?),
+                "movestogo" => limits.moves_to_go = Some(value(token, &mut tokens)?),
+                "ponder" => limits.ponder = true,
+                "infinite" => limits.infinite = true,
+                // Moves are listed until the next token which isn't a move
+                "searchmoves" => {
+                    while let Some(mov) = tokens.next_if(|token| token.parse::<UciMove>().is_ok()) {
+                        limits.search_moves.push(mov.parse()?);
+                    }
+                }
+                "wtime" => clock.wtime = ms(value(token, &mut tokens)?),
+                "btime" => clock.btime = ms(value(token, &mut tokens)?),
+                "winc" => clock.winc = ms(value(token, &mut tokens)?),
+                "binc" => clock.binc = ms(value(token, &mut tokens)?),
+                "perft" => return Ok(Self::Perft(value(token, &mut tokens)?)),
+                _ => continue,
+            }
+            clocked |= token.ends_with("time") && token != "movetime";
+        }
+
+        limits.clock = clocked.then_some(clock);
+        Ok(Self::Go(limits))
+    }
+
+    /// Parses the command received from the GUI. Unknown commands are `None`.
+    pub fn parse(line: &str) -> Result<Option<Self>> {
+        let mut tokens = line.split_whitespace();
+        let command = match tokens.next() {
+            Some("uci") => Self::Uci,
+            Some("debug") => Self::Debug,
+            Some("isready") => Self::IsReady,
+            Some("ucinewgame") => Self::NewGame,
+            Some("ponderhit") => Self::PonderHit,
+            Some("stop") => Self::Stop,
+            Some("quit") => Self::Quit,
+            Some("setoption") => {
+                let (_, option) = line
+                    .split_once(" name ")
+                    .ok_or_eyre("Missing option name")?;
+                let (name, value) = option.split_once(" value ").unwrap_or((option, ""));
+                Self::SetOption(name.trim().to_owned(), value.trim().to_owned())
+            }
+            Some("position") => Self::parse_position(tokens)?,
+            Some("go") => Self::parse_go(tokens)?,
+            _ => return Ok(None),
+        };
+        Ok(Some(command))
+    }
+}
+
 impl Display for Command {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         use Command::*;
@@ -261,9 +566,24 @@ impl Display for Command {
                 }
                 Ok(())
             }
-            Go { depth, time } => {
+            Go(limits) => {
+                let Limits {
+                    depth,
+                    time,
+                    clock,
+                    nodes,
+                    mate,
+                    moves_to_go,
+                    search_moves,
+                    ponder,
+                    infinite: _,
+                } = limits;
                 write!(f, "go")?;
 
+                if *ponder {
+                    write!(f, " ponder")?;
+                }
+
                 if let Some(depth) = &depth {
                     write!(f, " depth {depth}")?;
                 }
@@ -272,27 +592,64 @@ impl Display for Command {
                     write!(f, " movetime {}", time.as_millis())?;
                 }
 
-                if depth.is_none() && time.is_none() {
+                if let Some(clock) = &clock {
+                    write!(
+                        f,
+                        " wtime {} btime {} winc {} binc {}",
+                        clock.wtime.as_millis(),
+                        clock.btime.as_millis(),
+                        clock.winc.as_millis(),
+                        clock.binc.as_millis()
+                    )?;
+                }
+
+                if let Some(moves_to_go) = &moves_to_go {
+                    write!(f, " movestogo {moves_to_go}")?;
+                }
+
+                if let Some(nodes) = &nodes {
+                    write!(f, " nodes {nodes}")?;
+                }
+
+                if let Some(mate) = &mate {
+                    write!(f, " mate {mate}")?;
+                }
+
+                if limits.is_infinite() {
                     write!(f, " infinite")?;
                 }
 
+                // Moves are listed last, as they end with the first token which isn't a move
+                if !search_moves.is_empty() {
+                    write!(f, " searchmoves")?;
+                    for mov in search_moves {
+                        write!(f, " {mov}")?;
+                    }
+                }
+
                 Ok(())
             }
+            PonderHit => write!(f, "ponderhit"),
+            Perft(depth) => write!(f, "go perft {depth}"),
             Stop => write!(f, "stop"),
             Quit => write!(f, "quit"),
         }
     }
 }
 
-/// Messages received from engine
+/// Messages received from engine (or send to the GUI when emily is the engine)
 #[derive(Derivative)]
 #[derivative(Debug)]
-enum Msg {
+pub enum Msg {
     /// Information about engine
     Id {
         #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
         name: Option<String>,
+        #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+        author: Option<String>,
     },
+    /// Option supported by the engine, with the `option` arguments
+    Option(String),
     /// Initialization complete
     UciOk,
     /// IO sync
@@ -301,12 +658,23 @@ enum Msg {
     BestMove(#[derivative(Debug(format_with = "Display::fmt"))] UciMove),
     /// Analysis step
     Info(Info),
+    /// Perft leaf nodes after the root move
+    PerftMove(
+        #[derivative(Debug(format_with = "Display::fmt"))] UciMove,
+        u64,
+    ),
+    /// Perft total leaf nodes
+    PerftNodes(u64),
 }
 
 impl Msg {
     fn parse_id(args: &str) -> Option<Self> {
-        let name = args.split_once(" name ").map(|(_, name)| name.to_owned());
-        Some(Self::Id { name })
+        let (name, author) = match args.trim().split_once(' ') {
+            Some(("name", name)) => (Some(name.trim().to_owned()), None),
+            Some(("author", author)) => (None, Some(author.trim().to_owned())),
+            _ => (None, None),
+        };
+        Some(Self::Id { name, author })
     }
 
     fn parse_bestmove(args: &str) -> Option<Self> {
@@ -324,6 +692,13 @@ impl Msg {
         }
     }
 
+    /// Parses the perft divide line, `e2e4: 20`
+    fn parse_perft(cmd: &str, args: &str) -> Option<Self> {
+        let mov = cmd.strip_suffix(':')?.parse().ok()?;
+        let nodes = args.trim().parse().ok()?;
+        Some(Self::PerftMove(mov, nodes))
+    }
+
     fn parse(line: &str) -> Option<Self> {
         let idx = line.find(' ').unwrap_or(line.len());
         let cmd = line[..idx].trim();
@@ -331,6 +706,7 @@ impl Msg {
 
         match cmd {
             "id" => Self::parse_id(args),
+            "option" => Some(Self::Option(args.trim().to_owned())),
             "uciok" => Some(Self::UciOk),
             "readyok" => Some(Self::ReadyOk),
             "bestmove" => Self::parse_bestmove(args),
@@ -341,7 +717,43 @@ impl Msg {
                     None
                 }
             },
-            _ => None,
+            "Nodes" => args
+                .trim()
+                .strip_prefix("searched:")
+                .and_then(|nodes| nodes.trim().parse().ok())
+                .map(Self::PerftNodes),
+            _ => Self::parse_perft(cmd, args),
+        }
+    }
+}
+
+impl Display for Msg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            // Every field is sent on its own line
+            Self::Id {
+                name: Some(name),
+                author: Some(author),
+            } => write!(f, "id name {name}\nid author {author}"),
+            Self::Id {
+                name: Some(name),
+                author: None,
+            } => write!(f, "id name {name}"),
+            Self::Id {
+                name: None,
+                author: Some(author),
+            } => write!(f, "id author {author}"),
+            Self::Id {
+                name: None,
+                author: None,
+            } => write!(f, "id"),
+            Self::Option(option) => write!(f, "option {option}"),
+            Self::UciOk => write!(f, "uciok"),
+            Self::ReadyOk => write!(f, "readyok"),
+            Self::BestMove(best) => write!(f, "bestmove {best}"),
+            Self::Info(info) => write!(f, "info {info}"),
+            Self::PerftMove(mov, nodes) => write!(f, "{mov}: {nodes}"),
+            Self::PerftNodes(nodes) => write!(f, "Nodes searched: {nodes}"),
         }
     }
 }
@@ -352,7 +764,6 @@ impl Msg {
 pub struct Info {
     /// Line number (1 - best, 2 - second the best, ...). If not send (single-line mode) it will be
     /// defaulted to 1.
-    #[allow(unused)]
     pub multipv: u8,
     /// Engine evaluation
     pub score: Score,
@@ -360,8 +771,15 @@ pub struct Info {
     #[derivative(Debug(format_with = "LineExt::fmt"))]
     pub line: Vec<UciMove>,
     /// Actuall depth the calculation reached
-    #[allow(unused)]
     pub depth: u8,
+    /// Nodes searched so far
+    pub nodes: u64,
+    /// Search speed in nodes per second, if reported
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub nps: Option<u64>,
+    /// Time searched so far, if reported
+    #[derivative(Debug(format_with = "FlatOptExt::fmt"))]
+    pub time: Option<Duration>,
 }
 
 impl Info {
@@ -385,6 +803,9 @@ impl Info {
 
         let mut multipv = 1;
         let mut depth = 0;
+        let mut nodes = 0;
+        let mut nps = None;
+        let mut time = None;
         let mut score = None;
         let mut line = vec![];
 
@@ -426,6 +847,29 @@ impl Info {
                         .parse()
                         .wrap_err("Invalid depth value")?;
                 }
+                "nodes" => {
+                    nodes = args
+                        .next()
+                        .ok_or_eyre("Missing nodes value")?
+                        .parse()
+                        .wrap_err("Invalid nodes value")?;
+                }
+                "nps" => {
+                    let value = args
+                        .next()
+                        .ok_or_eyre("Missing nps value")?
+                        .parse()
+                        .wrap_err("Invalid nps value")?;
+                    nps = Some(value);
+                }
+                "time" => {
+                    let ms = args
+                        .next()
+                        .ok_or_eyre("Missing time value")?
+                        .parse()
+                        .wrap_err("Invalid time value")?;
+                    time = Some(Duration::from_millis(ms));
+                }
                 "pv" => {
                     line.clear();
                     while let Some(mv) = args.peek().and_then(|m| m.parse().ok()) {
@@ -447,10 +891,38 @@ impl Info {
             score,
             line,
             depth,
+            nodes,
+            nps,
+            time,
         }))
     }
 }
 
+/// Formatted as the `info` arguments
+impl Display for Info {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "depth {} multipv {} score ", self.depth, self.multipv)?;
+        match self.score {
+            Score::Cp(cp) => write!(f, "cp {cp}")?,
+            Score::Mate(mate) => write!(f, "mate {mate}")?,
+        }
+
+        write!(f, " nodes {}", self.nodes)?;
+        if let Some(nps) = self.nps {
+            write!(f, " nps {nps}")?;
+        }
+        if let Some(time) = self.time {
+            write!(f, " time {}", time.as_millis())?;
+        }
+
+        write!(f, " pv")?;
+        for mov in &self.line {
+            write!(f, " {mov}")?;
+        }
+        Ok(())
+    }
+}
+
 /// Engine score evaluation
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 pub enum Score {
@@ -467,6 +939,14 @@ impl Score {
             Score::Mate(m) => Score::Mate(-m),
         }
     }
+
+    /// Converts the score from the `turn` side PoV to the white PoV
+    pub fn pov(self, turn: Color) -> Score {
+        match turn {
+            Color::White => self,
+            Color::Black => self.rev(),
+        }
+    }
 }
 
 /// It's importat to be able to order the score to decide which line is better:
@@ -490,8 +970,8 @@ impl Ord for Score {
             (Cp(_), Mate(_m)) /* if *m < 0 */ => Ordering::Greater,
             // If there are mates by the different players on both sides, better is side where we
             // are mating
-            (Mate(n), Mate(m)) if *n >= 0 && *m < 1 => Ordering::Greater,
-            (Mate(n), Mate(m)) if *n < 1 && *m >= 0 => Ordering::Greater,
+            (Mate(n), Mate(m)) if *n >= 0 && *m < 0 => Ordering::Greater,
+            (Mate(n), Mate(m)) if *n < 0 && *m >= 0 => Ordering::Less,
             // If we are mating on both sides, we prefer the shorter mate (reversing the
             // comparison!)
             (Mate(n), Mate(m)) if *n >= 0 && *m >= 0 => m.cmp(n),
@@ -511,9 +991,9 @@ impl Display for Score {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Cp(cp) => {
-                let h = cp / 100;
-                let l = cp.abs() % 100;
-                write!(f, "{h}.{l}")
+                let sign = if *cp < 0 { "-" } else { "" };
+                let cp = cp.unsigned_abs();
+                write!(f, "{sign}{}.{:02}", cp / 100, cp % 100)
             }
             Self::Mate(m) => {
                 write!(f, "#{m}")
@@ -521,3 +1001,78 @@ impl Display for Score {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_go(line: &str) -> Limits {
+        match Command::parse(line).unwrap() {
+            Some(Command::Go(limits)) => limits,
+            command => panic!("Not a search: {command:?}"),
+        }
+    }
+
+    #[test]
+    fn go_limits_parsed() {
+        let limits = parse_go(
+            "go ponder wtime 1000 btime 2000 movestogo 20 searchmoves e2e4 d2d4 depth 5 mate 3",
+        );
+        assert!(limits.ponder);
+        assert!(!limits.is_infinite());
+        assert_eq!(limits.depth, Some(5));
+        assert_eq!(limits.mate, Some(3));
+        assert_eq!(limits.moves_to_go, Some(20));
+        assert_eq!(
+            limits.search_moves,
+            ["e2e4".parse::<UciMove>().unwrap(), "d2d4".parse().unwrap()]
+        );
+        let clock = limits.clock.unwrap();
+        assert_eq!(clock.wtime, Duration::from_secs(1));
+        assert_eq!(clock.btime, Duration::from_secs(2));
+
+        assert!(parse_go("go infinite").is_infinite());
+        assert!(parse_go("go infinite depth 10").is_infinite());
+        assert!(parse_go("go").is_infinite());
+        assert!(!parse_go("go mate 2").is_infinite());
+    }
+
+    #[test]
+    fn go_forwarded_unchanged() {
+        let line = "go ponder wtime 1000 btime 2000 winc 10 binc 20 movestogo 20 nodes 100 mate 3 \
+                    searchmoves e2e4 d2d4";
+        let forwarded = Command::Go(parse_go(line)).to_string();
+        assert_eq!(forwarded, line);
+        assert_eq!(
+            Command::Go(parse_go("go infinite searchmoves e2e4")).to_string(),
+            "go infinite searchmoves e2e4"
+        );
+    }
+
+    #[test]
+    fn ponderhit_parsed() {
+        assert!(matches!(
+            Command::parse("ponderhit").unwrap(),
+            Some(Command::PonderHit)
+        ));
+    }
+
+    #[test]
+    fn engine_id_and_options_parsed() {
+        let Some(Msg::Id { name, author }) = Msg::parse("id name Fake Engine 1.0") else {
+            panic!("Not an id");
+        };
+        assert_eq!(name.as_deref(), Some("Fake Engine 1.0"));
+        assert_eq!(author, None);
+
+        let Some(Msg::Id { name, author }) = Msg::parse("id author The Authors") else {
+            panic!("Not an id");
+        };
+        assert_eq!(name, None);
+        assert_eq!(author.as_deref(), Some("The Authors"));
+
+        let option = "option name Hash type spin default 16 min 1 max 1024";
+        let msg = Msg::parse(option).unwrap();
+        assert_eq!(msg.to_string(), option);
+    }
+}
@@ -0,0 +1,501 @@
+//! CECP (xboard/WinBoard) engines. UCI commands are translated to the xboard ones, and the engine
+//! output back to the UCI messages, so the rest of the protocol stays the same.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use color_eyre::eyre::{bail, ensure, eyre};
+use color_eyre::Result;
+use shakmaty::fen::Fen;
+use shakmaty::san::{San, SanPlus};
+use shakmaty::uci::UciMove;
+use shakmaty::{CastlingMode, Chess, Color, Move, Position};
+use tracing::{trace, warn};
+
+use crate::adapters::board::BoardExt;
+
+use super::{Clock, Command, Info, Limits, Msg, Score};
+
+/// Time the engine has to send its features after `protover`, unless it asks for more
+const FEATURES_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Mate score of the thinking output, `MATE + N` is the mate in N moves
+const MATE: i32 = 100000;
+
+/// Features negotiated with the engine, defaults as defined by the protocol
+#[derive(Debug)]
+struct Features {
+    setboard: bool,
+    analyze: bool,
+    ping: bool,
+    san: bool,
+    usermove: bool,
+    /// Hash size set with `memory`
+    memory: bool,
+    /// Threads set with `cores`
+    smp: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            setboard: false,
+            analyze: true,
+            ping: false,
+            san: false,
+            usermove: false,
+            memory: false,
+            smp: false,
+        }
+    }
+}
+
+/// Search the engine performs
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    #[default]
+    Idle,
+    /// Searching the move to play (`go`)
+    Thinking,
+    /// Infinite analysis (`analyze`)
+    Analyzing,
+}
+
+/// Thinking output the engine may still send for the analysis already stopped, which is discarded
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Stale {
+    #[default]
+    None,
+    /// Until the `pong` with the number
+    UntilPong(u32),
+    /// Until the next search, without `ping` there is no way to know when the output ends
+    UntilSearch,
+}
+
+/// CECP translation state
+#[derive(Debug, Default)]
+pub struct Cecp {
+    features: Features,
+    /// Engine asked for more time for the features (`done=0`)
+    negotiating: bool,
+    /// Position the engine searches
+    position: Chess,
+    mode: Mode,
+    /// First move of the latest line, the result of the analysis
+    best: Option<UciMove>,
+    pings: u32,
+    stale: Stale,
+    /// Lines to send to the engine
+    outgoing: VecDeque<String>,
+    /// Messages translated from the engine output
+    incoming: VecDeque<Msg>,
+}
+
+impl Cecp {
+    fn send(&mut self, line: impl Into<String>) {
+        self.outgoing.push_back(line.into());
+    }
+
+    /// Lines to send to the engine, pending since the last call
+    pub fn outgoing(&mut self) -> Vec<String> {
+        self.outgoing.drain(..).collect()
+    }
+
+    /// Next translated message
+    pub fn incoming(&mut self) -> Option<Msg> {
+        self.incoming.pop_front()
+    }
+
+    /// Time to wait for the next feature, `None` if the engine asked for more time
+    pub fn features_timeout(&self) -> Option<Duration> {
+        (!self.negotiating).then_some(FEATURES_TIMEOUT)
+    }
+
+    /// Finishes the features negotiation, also when the engine doesn't send `done=1`
+    pub fn features_done(&mut self) {
+        self.negotiating = false;
+        // Thinking output on, pondering off
+        self.send("post");
+        self.send("easy");
+        self.incoming.push_back(Msg::UciOk);
+    }
+
+    /// Translates the command
+    pub fn command(&mut self, command: Command) -> Result<()> {
+        match command {
+            Command::Uci => {
+                self.send("xboard");
+                self.send("protover 2");
+            }
+            Command::Debug => (),
+            Command::SetOption(name, value) => self.set_option(&name, &value),
+            Command::IsReady if self.features.ping => {
+                self.pings += 1;
+                self.send(format!("ping {}", self.pings));
+            }
+            // No way to synchronize with the engine
+            Command::IsReady => self.incoming.push_back(Msg::ReadyOk),
+            Command::NewGame => {
+                self.send("new");
+                self.send("force");
+            }
+            Command::Position { fen, line } => self.position(fen, line)?,
+            Command::Go(limits) => self.go(limits)?,
+            Command::Perft(_) => bail!("Perft is not supported by CECP engines"),
+            Command::PonderHit => bail!("Pondering is not supported by CECP engines"),
+            Command::Stop => self.stop(),
+            Command::Quit => self.send("quit"),
+        }
+
+        Ok(())
+    }
+
+    /// Sets the option, the UCI `Hash` and `Threads` are translated to the `memory` and `cores`
+    /// commands
+    fn set_option(&mut self, name: &str, value: &str) {
+        match name {
+            "Hash" if self.features.memory => self.send(format!("memory {value}")),
+            "Threads" if self.features.smp => self.send(format!("cores {value}")),
+            "Hash" | "Threads" => warn!(name, "Option is not supported by the engine"),
+            _ => self.send(format!("option {name}={value}")),
+        }
+    }
+
+    fn position(&mut self, fen: Option<Fen>, line: Vec<UciMove>) -> Result<()> {
+        // `new` also resets the depth limit and time controls of the previous search
+        self.send("new");
+        self.send("force");
+
+        self.position = Chess::default();
+        if let Some(fen) = fen.filter(|fen| *fen != Fen::default()) {
+            ensure!(self.features.setboard, "Engine doesn't support `setboard`");
+            self.send(format!("setboard {fen}"));
+            self.position = fen.into_position(CastlingMode::Standard)?;
+        }
+
+        for mov in line {
+            let m = mov.to_move(&self.position).map_err(|_| {
+                let board = self.position.d_board().colors(false);
+                eyre!("Illegal move {mov} in {board:?}")
+            })?;
+            let notation = match self.features.san {
+                true => San::from_move(&self.position, &m).to_string(),
+                false => mov.to_string(),
+            };
+            match self.features.usermove {
+                true => self.send(format!("usermove {notation}")),
+                false => self.send(notation),
+            }
+            self.position.play_unchecked(&m);
+        }
+
+        Ok(())
+    }
+
+    fn go(&mut self, limits: Limits) -> Result<()> {
+        self.best = None;
+        ensure!(!limits.ponder, "Pondering is not supported by CECP engines");
+        ensure!(
+            limits.mate.is_none(),
+            "Mate search is not supported by CECP engines"
+        );
+        ensure!(
+            limits.search_moves.is_empty(),
+            "Search moves are not supported by CECP engines"
+        );
+
+        // Output of the new search is not stale, unless the engine may still send the stopped one's
+        if self.stale == Stale::UntilSearch {
+            self.stale = Stale::None;
+        }
+
+        if limits.is_infinite() {
+            ensure!(self.features.analyze, "Engine doesn't support analysis");
+            self.send("analyze");
+            self.mode = Mode::Analyzing;
+            return Ok(());
+        }
+
+        ensure!(
+            limits.nodes.is_none(),
+            "Nodes limit is not supported by CECP engines"
+        );
+
+        if let Some(depth) = limits.depth {
+            self.send(format!("sd {depth}"));
+        }
+        match (limits.time, limits.clock) {
+            (Some(time), _) => self.send(format!("st {}", time.as_secs_f64().ceil().max(1.))),
+            (None, Some(clock)) => self.clock(clock, limits.moves_to_go),
+            // Depth only, practically unlimited time
+            (None, None) => self.send("st 86400"),
+        }
+
+        self.send("go");
+        self.mode = Mode::Thinking;
+        Ok(())
+    }
+
+    /// Sets up the engine clock. The base time is irrelevant as the remaining time is always sent.
+    /// Moves to go are sent as the moves per the time control, which is incremental without them.
+    fn clock(&mut self, clock: Clock, moves_to_go: Option<u32>) {
+        let (own, opponent, inc) = match self.position.turn() {
+            Color::White => (clock.wtime, clock.btime, clock.winc),
+            Color::Black => (clock.btime, clock.wtime, clock.binc),
+        };
+
+        let secs = own.as_secs();
+        self.send(format!(
+            "level {} {}:{:02} {}",
+            moves_to_go.unwrap_or(0),
+            secs / 60,
+            secs % 60,
+            inc.as_secs_f64()
+        ));
+        self.send(format!("time {}", own.as_millis() / 10));
+        self.send(format!("otim {}", opponent.as_millis() / 10));
+    }
+
+    fn stop(&mut self) {
+        match self.mode {
+            Mode::Analyzing => {
+                self.send("exit");
+                self.mode = Mode::Idle;
+                // Thinking output sent before the engine read `exit` is discarded
+                self.stale = match self.features.ping {
+                    true => {
+                        self.pings += 1;
+                        self.send(format!("ping {}", self.pings));
+                        Stale::UntilPong(self.pings)
+                    }
+                    false => Stale::UntilSearch,
+                };
+                // Analysis gives no move, the best line is its result
+                let best = self.best.take().or_else(|| {
+                    let moves = self.position.legal_moves();
+                    moves.first().map(UciMove::from_standard)
+                });
+                self.incoming
+                    .push_back(Msg::BestMove(best.unwrap_or(UciMove::Null)));
+            }
+            Mode::Thinking => self.send("?"),
+            Mode::Idle => (),
+        }
+    }
+
+    /// Parses the engine move, in the coordinate or SAN notation
+    fn parse_move(position: &Chess, mov: &str) -> Option<Move> {
+        if let Ok(mov) = mov.parse::<UciMove>() {
+            return mov.to_move(position).ok();
+        }
+
+        let san: SanPlus = mov.parse().ok()?;
+        san.san.to_move(position).ok()
+    }
+
+    /// Translates the engine output line
+    pub fn line(&mut self, line: &str) {
+        let (keyword, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+
+        match keyword {
+            "feature" => self.features(args),
+            "pong" => match args.trim().parse() {
+                Ok(ping) if self.stale == Stale::UntilPong(ping) => self.stale = Stale::None,
+                _ => self.incoming.push_back(Msg::ReadyOk),
+            },
+            "move" => {
+                self.mode = Mode::Idle;
+                let best = Self::parse_move(&self.position, args.trim());
+                if best.is_none() {
+                    warn!(line, "Invalid engine move");
+                }
+                // Invalid move is reported as the null one, so it is handled as illegal
+                let best = best.map_or(UciMove::Null, |best| UciMove::from_standard(&best));
+                self.incoming.push_back(Msg::BestMove(best));
+            }
+            // Resignation is played as the null move, forfeiting the game
+            "resign" if self.mode == Mode::Thinking => {
+                self.mode = Mode::Idle;
+                self.incoming.push_back(Msg::BestMove(UciMove::Null));
+            }
+            "Illegal" => warn!(line, "Engine rejected the move"),
+            _ if keyword.starts_with("Error") => warn!(line, "Engine error"),
+            _ if self.stale != Stale::None => trace!(line, "Stale engine output ignored"),
+            _ => match self.thinking(line) {
+                Some(info) => self.incoming.push_back(Msg::Info(info)),
+                None => trace!(line, "Engine output ignored"),
+            },
+        }
+    }
+
+    /// Parses the `feature` arguments - `name=value` pairs, values optionally quoted. All the
+    /// features are accepted, the ones emily uses are recorded.
+    fn features(&mut self, args: &str) {
+        let mut rest = args.trim();
+        while let Some((name, value)) = rest.split_once('=') {
+            let (value, tail) = match value.strip_prefix('"') {
+                Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+                None => value.split_once(char::is_whitespace).unwrap_or((value, "")),
+            };
+            let name = name.trim();
+            rest = tail.trim();
+
+            trace!(name, value, "Engine feature");
+            self.send(format!("accepted {name}"));
+
+            let enabled = value == "1";
+            match name {
+                "myname" => self.incoming.push_back(Msg::Id {
+                    name: Some(value.to_owned()),
+                    author: None,
+                }),
+                "setboard" => self.features.setboard = enabled,
+                "analyze" => self.features.analyze = enabled,
+                "ping" => self.features.ping = enabled,
+                "san" => self.features.san = enabled,
+                "usermove" => self.features.usermove = enabled,
+                "memory" => self.features.memory = enabled,
+                "smp" => self.features.smp = enabled,
+                "done" if enabled => self.features_done(),
+                "done" => self.negotiating = true,
+                _ => (),
+            }
+        }
+    }
+
+    /// Parses the thinking output - `ply score time nodes pv`, time in centiseconds
+    fn thinking(&mut self, line: &str) -> Option<Info> {
+        let mut tokens = line.split_whitespace();
+        // Ply may be followed by a marker, eg. `12.` or `12&`
+        let depth = tokens
+            .next()?
+            .trim_end_matches(|c: char| !c.is_ascii_digit())
+            .parse()
+            .ok()?;
+        let score: i32 = tokens.next()?.parse().ok()?;
+        let centis: u64 = tokens.next()?.parse().ok()?;
+        let nodes: u64 = tokens.next()?.parse().ok()?;
+
+        let score = match score {
+            score if score >= MATE => Score::Mate((score - MATE).min(i8::MAX as i32) as i8),
+            score if score <= -MATE => Score::Mate((score + MATE).max(i8::MIN as i32) as i8),
+            score => Score::Cp(score.clamp(i16::MIN as i32, i16::MAX as i32) as i16),
+        };
+
+        let mut position = self.position.clone();
+        let mut pv = vec![];
+        for token in tokens {
+            // Move numbers and annotations
+            let token = token.trim_end_matches(['+', '#', '!', '?']);
+            if token.is_empty() || token.ends_with('.') {
+                continue;
+            }
+
+            let Some(mov) = Self::parse_move(&position, token) else {
+                break;
+            };
+            pv.push(UciMove::from_standard(&mov));
+            position.play_unchecked(&mov);
+        }
+
+        if let Some(best) = pv.first() {
+            self.best = Some(best.clone());
+        }
+
+        Some(Info {
+            multipv: 1,
+            score,
+            line: pv,
+            depth,
+            nodes,
+            nps: (centis > 0).then(|| nodes * 100 / centis),
+            time: Some(Duration::from_millis(centis * 10)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Translation state after the engine sent the `features`
+    fn negotiated(features: &str) -> Cecp {
+        let mut cecp = Cecp::default();
+        cecp.command(Command::Uci).unwrap();
+        cecp.line(&format!("feature {features} done=1"));
+        assert!(matches!(cecp.incoming(), Some(Msg::UciOk)));
+        cecp.outgoing();
+        cecp
+    }
+
+    fn analyse(cecp: &mut Cecp) {
+        cecp.command(Command::Go(Limits::default())).unwrap();
+        cecp.line("3 25 10 1000 e2e4 e7e5");
+        assert!(matches!(cecp.incoming(), Some(Msg::Info(_))));
+    }
+
+    #[test]
+    fn thinking_after_exit_discarded_until_pong() {
+        let mut cecp = negotiated("ping=1");
+        analyse(&mut cecp);
+
+        cecp.command(Command::Stop).unwrap();
+        assert_eq!(cecp.outgoing(), ["analyze", "exit", "ping 1"]);
+        assert!(matches!(cecp.incoming(), Some(Msg::BestMove(_))));
+
+        cecp.line("4 30 20 2000 d2d4 d7d5");
+        assert!(cecp.incoming().is_none());
+        cecp.line("pong 1");
+        assert!(cecp.incoming().is_none());
+
+        analyse(&mut cecp);
+    }
+
+    #[test]
+    fn thinking_after_exit_discarded_until_search() {
+        let mut cecp = negotiated("ping=0");
+        analyse(&mut cecp);
+
+        cecp.command(Command::Stop).unwrap();
+        assert!(matches!(cecp.incoming(), Some(Msg::BestMove(_))));
+        cecp.line("4 30 20 2000 d2d4 d7d5");
+        assert!(cecp.incoming().is_none());
+
+        analyse(&mut cecp);
+    }
+
+    #[test]
+    fn ready_pongs_reported() {
+        let mut cecp = negotiated("ping=1");
+        cecp.command(Command::IsReady).unwrap();
+        analyse(&mut cecp);
+        cecp.command(Command::Stop).unwrap();
+        cecp.incoming();
+
+        // Pong of the `isready` sent before the analysis, then the one ending the stale output
+        cecp.line("pong 1");
+        assert!(matches!(cecp.incoming(), Some(Msg::ReadyOk)));
+        cecp.line("pong 2");
+        assert!(cecp.incoming().is_none());
+    }
+
+    #[test]
+    fn hash_and_threads_translated() {
+        let mut cecp = negotiated("memory=1 smp=1");
+        cecp.command(Command::SetOption("Hash".to_owned(), "64".to_owned()))
+            .unwrap();
+        cecp.command(Command::SetOption("Threads".to_owned(), "4".to_owned()))
+            .unwrap();
+        cecp.command(Command::SetOption("Style".to_owned(), "solid".to_owned()))
+            .unwrap();
+        assert_eq!(
+            cecp.outgoing(),
+            ["memory 64", "cores 4", "option Style=solid"]
+        );
+
+        let mut cecp = negotiated("memory=0");
+        cecp.command(Command::SetOption("Hash".to_owned(), "64".to_owned()))
+            .unwrap();
+        assert!(cecp.outgoing().is_empty());
+    }
+}